@@ -2,10 +2,6 @@
 #![no_main]
 #![feature(type_alias_impl_trait)]
 
-mod policy_engine;
-mod protocol;
-mod protocol_engine;
-
 use core::pin::pin;
 
 use defmt::{panic, *};
@@ -16,14 +12,17 @@ use embassy_stm32::time::mhz;
 use embassy_stm32::ucpd::{CcPhy, CcPull, CcSel, CcVState, Ucpd};
 use embassy_stm32::{bind_interrupts, peripherals, ucpd, Config};
 use embassy_time::{with_timeout, Duration};
-use policy_engine::PolicyEngine;
-use protocol_engine::ProtocolEngine;
+use usb_pd::policy_engine::PolicyEngine;
+use usb_pd::protocol_engine::ProtocolEngine;
+use usb_pd::sink_policy::{PowerRange, Preference, SinkConfig};
 use {defmt_rtt as _, panic_probe as _};
 
 bind_interrupts!(struct Irqs {
     UCPD1 => ucpd::InterruptHandler<peripherals::UCPD1>;
 });
 
+static SINK_POWER_RANGES: [PowerRange; 1] = [PowerRange::fixed(5000, 100)];
+
 #[derive(Debug, Format)]
 enum CableOrientation {
     Normal,
@@ -62,7 +61,7 @@ async fn wait_attached<T: ucpd::Instance>(cc_phy: &mut CcPhy<'_, T>) -> CableOri
 // Using the CC lines to detect cable detach is not spec compliant.
 // The correct approach is be to monitor VBUS using an additional pin
 // Use the CC lines nevertheless to keep the example simple.
-async fn wait_detach<T: ucpd::Instance>(cc_phy: &mut CcPhy<'_, T>) {
+async fn wait_detach<T: ucpd::Instance>(cc_phy: &CcPhy<'_, T>) {
     while !matches!(
         cc_phy.wait_for_vstate_change().await,
         (CcVState::LOWEST, CcVState::LOWEST)
@@ -115,11 +114,19 @@ fn main() -> ! {
                 CableOrientation::DebugAccessoryMode => panic!("No PD communication in DAM"),
             };
 
-            let (mut cc_phy, pd_phy) = ucpd.split_pd_phy(&p.DMA1_CH1, &mut p.DMA1_CH2, cc_sel);
-            let protocol_engine = ProtocolEngine::new(pd_phy);
-            let mut policy_engine = PolicyEngine::new(protocol_engine, 100);
+            let (cc_phy, pd_phy) = ucpd.split_pd_phy(&p.DMA1_CH1, &mut p.DMA1_CH2, cc_sel);
+            // Monitor the Rp level for PD 3.0 collision avoidance.
+            let protocol_engine = ProtocolEngine::new(pd_phy).with_cc_phy(&cc_phy);
+            let sink_config = SinkConfig {
+                ranges: &SINK_POWER_RANGES,
+                preference: Preference::HighestPower,
+                pps: None,
+                commands: None,
+                give_back_current_ma: None,
+            };
+            let mut policy_engine = PolicyEngine::new(protocol_engine, sink_config);
 
-            select(wait_detach(&mut cc_phy), async {
+            select(wait_detach(&cc_phy), async {
                 policy_engine.run_sink().await
             })
             .await;
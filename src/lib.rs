@@ -0,0 +1,16 @@
+//! USB Power Delivery protocol stack built on embassy.
+//!
+//! The [`policy_engine`] and [`protocol_engine`] are independent of the
+//! hardware and talk to the wire through the [`phy::PdPhy`] trait.
+#![no_std]
+
+// This must go first so that the logging macros are visible in all modules.
+mod fmt;
+
+pub mod phy;
+pub mod policy_engine;
+pub mod protocol;
+pub mod protocol_engine;
+pub mod sink_policy;
+pub mod source_policy;
+pub mod vdm_policy;
@@ -0,0 +1,189 @@
+//! Hardware abstraction for the USB PD physical layer.
+//!
+//! The protocol and policy engines only talk to the wire through the [`PdPhy`]
+//! and [`CcPhy`] traits, which allows them to run on any Type-C port controller
+//! or on a host in tests.
+
+#[cfg(feature = "stm32")]
+mod ucpd;
+
+use embassy_time::Duration;
+
+use crate::protocol::PortPowerRole;
+
+/// Start of packet ordered set, selects the recipient of a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Sop {
+    /// Port partner.
+    Sop,
+
+    /// Cable plug closest to the source (SOP').
+    SopPrime,
+
+    /// Cable plug furthest from the source (SOP'').
+    SopDoublePrime,
+}
+
+/// Receive Error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RxError {
+    /// Incorrect CRC or truncated message (a line becoming static before EOP is met).
+    Crc,
+
+    /// Provided buffer was too small for the received message.
+    Overrun,
+
+    /// Hard Reset received before or during reception.
+    HardReset,
+}
+
+/// Transmit Error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TxError {
+    /// Concurrent receive in progress or excessive noise on the line.
+    Discarded,
+
+    /// Hard Reset received before or during transmission.
+    HardReset,
+
+    /// The PHY is not able to transmit the requested SOP* type or the BIST
+    /// carrier.
+    Unsupported,
+}
+
+/// Physical layer able to send and receive raw USB PD messages.
+///
+/// Buffers contain the message header followed by the data objects in little
+/// endian byte order. Preamble, SOP and CRC are handled by the implementation.
+#[allow(async_fn_in_trait)]
+pub trait PdPhy {
+    /// Receives a message into `buf` and returns the number of bytes received.
+    async fn receive(&mut self, buf: &mut [u8]) -> Result<usize, RxError>;
+
+    /// Transmits a message.
+    async fn transmit(&mut self, buf: &[u8]) -> Result<(), TxError>;
+
+    /// Transmits a Hard Reset ordered set.
+    async fn transmit_hard_reset(&mut self) -> Result<(), TxError>;
+
+    /// Receives a message of any SOP* type and returns its type along with
+    /// the number of bytes received.
+    ///
+    /// The default implementation only receives SOP messages.
+    async fn receive_sop(&mut self, buf: &mut [u8]) -> Result<(Sop, usize), RxError> {
+        Ok((Sop::Sop, self.receive(buf).await?))
+    }
+
+    /// Transmits a message to the port partner or to a cable plug.
+    ///
+    /// The default implementation fails with [`TxError::Unsupported`] for all
+    /// messages not sent to the port partner.
+    async fn transmit_sop(&mut self, sop: Sop, buf: &[u8]) -> Result<(), TxError> {
+        match sop {
+            Sop::Sop => self.transmit(buf).await,
+            Sop::SopPrime | Sop::SopDoublePrime => Err(TxError::Unsupported),
+        }
+    }
+
+    /// Transmits the BIST Carrier Mode 2 test pattern for `duration`.
+    ///
+    /// The default implementation does not support the carrier and fails
+    /// with [`TxError::Unsupported`].
+    async fn transmit_bist_carrier(&mut self, _duration: Duration) -> Result<(), TxError> {
+        Err(TxError::Unsupported)
+    }
+}
+
+/// Current advertised by a source with its Rp pull-up.
+///
+/// USB PD 3.0 sources use the 3.0A level to signal SinkTxOk and the 1.5A level
+/// to signal SinkTxNG for collision avoidance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RpLevel {
+    /// Default USB power.
+    Default,
+
+    /// 1.5A, SinkTxNG
+    Current1_5A,
+
+    /// 3.0A, SinkTxOk
+    Current3_0A,
+}
+
+impl RpLevel {
+    /// Returns `true` when a sink is allowed to initiate an atomic message sequence.
+    pub fn sink_tx_ok(self) -> bool {
+        self == Self::Current3_0A
+    }
+}
+
+/// Access to the CC line state as seen by a sink.
+#[allow(async_fn_in_trait)]
+pub trait CcPhy {
+    /// Returns the Rp level currently advertised by the source.
+    fn rp_level(&self) -> RpLevel;
+
+    /// Waits for a change of the CC lines and returns the new Rp level.
+    async fn wait_for_rp_level_change(&self) -> RpLevel;
+}
+
+impl<T: CcPhy> CcPhy for &T {
+    fn rp_level(&self) -> RpLevel {
+        T::rp_level(self)
+    }
+
+    async fn wait_for_rp_level_change(&self) -> RpLevel {
+        T::wait_for_rp_level_change(self).await
+    }
+}
+
+/// Control of the CC line pull resistors of a dual-role port.
+pub trait DualRoleCcPhy: CcPhy {
+    /// Presents Rp on the CC lines as source or Rd as sink.
+    fn set_power_role(&mut self, power_role: PortPowerRole);
+}
+
+/// Placeholder for ports without access to the CC lines.
+///
+/// Always reports SinkTxOk, which disables collision avoidance.
+pub struct NoCcPhy;
+
+impl CcPhy for NoCcPhy {
+    fn rp_level(&self) -> RpLevel {
+        RpLevel::Current3_0A
+    }
+
+    async fn wait_for_rp_level_change(&self) -> RpLevel {
+        core::future::pending().await
+    }
+}
+
+/// Switch for the VCONN supply of e-marked cables.
+///
+/// VCONN is applied to the CC line that is not used for communication, the
+/// implementation is responsible for picking it based on the plug orientation.
+#[allow(async_fn_in_trait)]
+pub trait VconnSwitch {
+    /// Switches VCONN on and returns when it is within its operating range.
+    async fn enable(&mut self);
+
+    /// Switches VCONN off.
+    async fn disable(&mut self);
+}
+
+/// Placeholder for ports that cannot supply VCONN.
+pub enum NoVconn {}
+
+impl VconnSwitch for NoVconn {
+    async fn enable(&mut self) {
+        match *self {}
+    }
+
+    async fn disable(&mut self) {
+        match *self {}
+    }
+}
@@ -0,0 +1,79 @@
+//! [`PdPhy`] and [`CcPhy`] implementations for the STM32 UCPD peripheral.
+//!
+//! The UCPD driver of `embassy-stm32` only sends and receives SOP messages.
+//! Cable plugs cannot be addressed, transmitting SOP' and SOP'' messages
+//! fails with [`TxError::Unsupported`] and e-marked cables are not
+//! discovered.
+//!
+//! The driver does not expose the BIST Carrier Mode 2 transmit mode of the
+//! peripheral either, BIST carrier requests are answered without a carrier.
+
+use embassy_stm32::ucpd::{self, CcPull, CcVState};
+
+use super::{CcPhy, DualRoleCcPhy, PdPhy, RpLevel, RxError, TxError};
+use crate::protocol::PortPowerRole;
+
+impl From<ucpd::RxError> for RxError {
+    fn from(err: ucpd::RxError) -> Self {
+        match err {
+            ucpd::RxError::Crc => Self::Crc,
+            ucpd::RxError::Overrun => Self::Overrun,
+            ucpd::RxError::HardReset => Self::HardReset,
+        }
+    }
+}
+
+impl From<ucpd::TxError> for TxError {
+    fn from(err: ucpd::TxError) -> Self {
+        match err {
+            ucpd::TxError::Discarded => Self::Discarded,
+            ucpd::TxError::HardReset => Self::HardReset,
+        }
+    }
+}
+
+impl<'d, T: ucpd::Instance> PdPhy for ucpd::PdPhy<'d, T> {
+    async fn receive(&mut self, buf: &mut [u8]) -> Result<usize, RxError> {
+        Ok(ucpd::PdPhy::receive(self, buf).await?)
+    }
+
+    async fn transmit(&mut self, buf: &[u8]) -> Result<(), TxError> {
+        Ok(ucpd::PdPhy::transmit(self, buf).await?)
+    }
+
+    async fn transmit_hard_reset(&mut self) -> Result<(), TxError> {
+        Ok(ucpd::PdPhy::transmit_hardreset(self).await?)
+    }
+}
+
+fn rp_level((cc1, cc2): (CcVState, CcVState)) -> RpLevel {
+    // Only the CC line connected to the source is pulled up.
+    let vstate = if cc1 == CcVState::LOWEST { cc2 } else { cc1 };
+    if vstate == CcVState::HIGHEST {
+        RpLevel::Current3_0A
+    } else if vstate == CcVState::HIGH {
+        RpLevel::Current1_5A
+    } else {
+        RpLevel::Default
+    }
+}
+
+impl<'d, T: ucpd::Instance> CcPhy for ucpd::CcPhy<'d, T> {
+    fn rp_level(&self) -> RpLevel {
+        rp_level(self.vstate())
+    }
+
+    async fn wait_for_rp_level_change(&self) -> RpLevel {
+        rp_level(self.wait_for_vstate_change().await)
+    }
+}
+
+impl<'d, T: ucpd::Instance> DualRoleCcPhy for ucpd::CcPhy<'d, T> {
+    fn set_power_role(&mut self, power_role: PortPowerRole) {
+        self.set_pull(match power_role {
+            PortPowerRole::Sink => CcPull::Sink,
+            // Also signals SinkTxOk to PD 3.0 sinks.
+            PortPowerRole::Source => CcPull::Source3_0A,
+        });
+    }
+}
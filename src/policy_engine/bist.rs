@@ -0,0 +1,53 @@
+use embassy_time::Duration;
+
+use super::{Error, PolicyEngine};
+use crate::phy::{CcPhy, PdPhy, VconnSwitch};
+use crate::protocol::*;
+use crate::vdm_policy::{UnstructuredVdmHandler, VdmPolicy};
+
+/// Time the BIST carrier is transmitted, tBISTContMode is 30ms to 60ms.
+const TIMEOUT_BIST_CONT_MODE: Duration = Duration::from_millis(45);
+
+impl<P: PdPhy, S, C: CcPhy, V: VconnSwitch, M: VdmPolicy, U: UnstructuredVdmHandler>
+    PolicyEngine<P, S, C, V, M, U>
+{
+    /// Runs the test requested with a BIST message.
+    ///
+    /// BIST is only allowed at vSafe5V and ignored in contracts with a higher
+    /// voltage. The test data mode is only left with a hard reset.
+    pub(super) async fn respond_bist(&mut self, objects: &[u32]) -> Result<(), Error> {
+        let Some(&bdo) = objects.first() else {
+            warn!("Ignoring BIST without data object");
+            return Ok(());
+        };
+        // The first capability is always vSafe5V.
+        if self.contract.is_some_and(|c| c.object_position != 1) {
+            info!("Ignoring BIST outside of vSafe5V");
+            return Ok(());
+        }
+        match BistDataObject::from(bdo).mode() {
+            BistMode::CarrierMode => {
+                info!("Entering BIST carrier mode");
+                if !self
+                    .protocol_engine
+                    .transmit_bist_carrier(TIMEOUT_BIST_CONT_MODE)
+                    .await?
+                {
+                    warn!("PHY does not support the BIST carrier");
+                }
+                Ok(())
+            }
+            BistMode::TestData => {
+                info!("Entering BIST test data mode");
+                let mut obj_buf = [0; 7];
+                loop {
+                    self.protocol_engine.receive(&mut obj_buf).await?;
+                }
+            }
+            mode => {
+                info!("Ignoring BIST {}", mode);
+                Ok(())
+            }
+        }
+    }
+}
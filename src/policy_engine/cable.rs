@@ -0,0 +1,121 @@
+use bilge::prelude::*;
+
+use super::{Error, PolicyEngine};
+use crate::phy::{CcPhy, PdPhy, Sop, VconnSwitch};
+use crate::protocol::source_capabilities::*;
+use crate::protocol::*;
+use crate::protocol_engine::HardReset;
+use crate::vdm_policy::{UnstructuredVdmHandler, VdmPolicy};
+
+/// Current every Type-C cable is able to carry.
+const DEFAULT_CABLE_CURRENT_MA: u32 = 3000;
+
+/// Current an EPR AVS supplies at most, at any voltage.
+const EPR_AVS_MAX_CURRENT_MA: u32 = 5000;
+
+impl<P: PdPhy, S, C: CcPhy, V: VconnSwitch, M: VdmPolicy, U: UnstructuredVdmHandler>
+    PolicyEngine<P, S, C, V, M, U>
+{
+    /// Reads the capabilities of an e-marked cable with a Discover Identity
+    /// request to the SOP' cable plug.
+    ///
+    /// Only the VCONN source may talk to the cable. Returns `None` when the
+    /// cable does not respond, which is the case for cables without e-marker.
+    pub(super) async fn discover_cable(&mut self) -> Result<Option<CableCapabilities>, HardReset> {
+        if !self.vconn_source {
+            return Ok(None);
+        }
+
+        let request = StructuredVdmHeader::request(
+            PD_SID,
+            VdmCommand::DiscoverIdentity,
+            self.protocol_engine.specification_revision(),
+        );
+        let mut obj_buf = [0; 7];
+        let vdos = match self
+            .structured_vdm(Sop::SopPrime, request, &[], &mut obj_buf)
+            .await
+        {
+            Ok(Some((header, vdos))) if header.command_type() == VdmCommandType::Ack => vdos,
+            Ok(_) => {
+                info!("No e-marker in cable");
+                return Ok(None);
+            }
+            Err(Error::HardReset) => return Err(HardReset),
+            // Communication with the cable plug never causes a soft reset.
+            Err(_) => return Ok(None),
+        };
+
+        let cable = CableCapabilities::from_discover_identity(vdos);
+        info!("Cable capabilities {}", cable);
+        Ok(cable)
+    }
+
+    /// Removes the source capabilities exceeding the voltage rating of the
+    /// cable and limits the current of the remaining ones. Adjustable
+    /// supplies starting below the voltage rating are cut off at it instead.
+    ///
+    /// Cables without e-marker are limited to 3A and 20V. The vSafe5V
+    /// capability is always kept.
+    pub(super) fn limit_source_capabilities(&mut self, cable: Option<CableCapabilities>) {
+        let (max_current_ma, max_voltage_mv) = match cable {
+            Some(cable) => (cable.max_current_ma, cable.max_voltage_mv),
+            None => (DEFAULT_CABLE_CURRENT_MA, 20_000),
+        };
+
+        let current_10ma = u10::new((max_current_ma / 10) as u16);
+        let current_50ma = u7::new((max_current_ma / 50) as u8);
+        let mut n = 0;
+        for i in 0..self.num_source_capabilities {
+            let pdo = match self.source_capabilities[i] {
+                PowerDataObject::FixedSupply(mut pdo)
+                    if i == 0 || pdo.voltage_mv() <= max_voltage_mv =>
+                {
+                    pdo.set_max_current(pdo.max_current().min(current_10ma));
+                    PowerDataObject::FixedSupply(pdo)
+                }
+                PowerDataObject::VariableSupply(mut pdo)
+                    if pdo.max_voltage_mv() <= max_voltage_mv =>
+                {
+                    pdo.set_max_current(pdo.max_current().min(current_10ma));
+                    PowerDataObject::VariableSupply(pdo)
+                }
+                PowerDataObject::Battery(pdo) if pdo.max_voltage_mv() <= max_voltage_mv => {
+                    PowerDataObject::Battery(pdo)
+                }
+                // Adjustable supplies stay usable in the part of their range
+                // covered by the cable.
+                PowerDataObject::Pps(mut pdo) if pdo.min_voltage_mv() <= max_voltage_mv => {
+                    let limited_mv = pdo.max_voltage_mv().min(max_voltage_mv);
+                    pdo.set_max_voltage((limited_mv / 100) as u8);
+                    pdo.set_max_current(pdo.max_current().min(current_50ma));
+                    PowerDataObject::Pps(pdo)
+                }
+                PowerDataObject::SprAvs(mut pdo) => {
+                    pdo.set_max_current_15v(pdo.max_current_15v().min(current_10ma));
+                    pdo.set_max_current_20v(pdo.max_current_20v().min(current_10ma));
+                    PowerDataObject::SprAvs(pdo)
+                }
+                PowerDataObject::EprAvs(mut pdo) if pdo.min_voltage_mv() <= max_voltage_mv => {
+                    let limited_mv = pdo.max_voltage_mv().min(max_voltage_mv);
+                    pdo.set_max_voltage(u9::new((limited_mv / 100) as u16));
+                    // The current follows from the PDP and is highest at the
+                    // minimum voltage, where it is limited to 5A anyway.
+                    if max_current_ma < EPR_AVS_MAX_CURRENT_MA {
+                        let pdp_w = max_current_ma * pdo.min_voltage_mv() / 1_000_000;
+                        pdo.set_pdp(pdo.pdp().min(pdp_w as u8));
+                    }
+                    PowerDataObject::EprAvs(pdo)
+                }
+                pdo @ PowerDataObject::Unknown(_) => pdo,
+                _pdo => {
+                    info!("Cable does not support {}", _pdo);
+                    continue;
+                }
+            };
+            self.source_capabilities[n] = pdo;
+            n += 1;
+        }
+        self.num_source_capabilities = n;
+    }
+}
@@ -0,0 +1,200 @@
+use bilge::prelude::*;
+
+use super::{Error, PolicyEngine};
+use crate::phy::{CcPhy, PdPhy, Sop, VconnSwitch};
+use crate::protocol::displayport::*;
+use crate::protocol::*;
+use crate::vdm_policy::{UnstructuredVdmHandler, VdmPolicy};
+
+/// DP v1.3 signaling in DP Configure.
+const SIGNALING_DP: u8 = 0b0001;
+
+impl<P: PdPhy, S, C: CcPhy, V: VconnSwitch, M: VdmPolicy, U: UnstructuredVdmHandler>
+    PolicyEngine<P, S, C, V, M, U>
+{
+    /// Returns `true` when the port should enter the DisplayPort alternate
+    /// mode, which only the DFP is allowed to do.
+    pub(super) fn wants_displayport(&self) -> bool {
+        self.displayport_mode.is_none()
+            && self.protocol_engine.data_role() == PortDataRole::DownstreamFacingPort
+            && self
+                .vdm
+                .displayport_pin_assignments()
+                .select(false)
+                .is_some()
+    }
+
+    /// Discovers the DisplayPort alternate mode of the port partner, enters
+    /// it and configures the port partner as UFP_D.
+    ///
+    /// The port stays in USB operation when the port partner does not
+    /// support the mode or does not acknowledge one of the commands.
+    pub(super) async fn enter_displayport(&mut self) -> Result<(), Error> {
+        let Some((object_position, capabilities)) = self.discover_displayport().await? else {
+            info!("Port partner does not support DisplayPort");
+            return Ok(());
+        };
+        let pins = capabilities
+            .ufp_d_pins()
+            .intersection(self.vdm.displayport_pin_assignments());
+        if pins.select(false).is_none() {
+            info!("No common DisplayPort pin assignment");
+            return Ok(());
+        }
+
+        if self
+            .displayport_command(VdmCommand::EnterMode, object_position, None)
+            .await?
+            .is_none()
+        {
+            info!("Port partner refused to enter DisplayPort mode");
+            return Ok(());
+        }
+        self.displayport_mode = Some(object_position);
+        info!("Entered DisplayPort mode {=u8}", object_position);
+
+        let mut status = DisplayPortStatus::from(0);
+        status.set_dfp_d_connected(true);
+        let Some(status) = self
+            .displayport_command(
+                VdmCommand::DisplayPortStatusUpdate,
+                object_position,
+                Some(status.into()),
+            )
+            .await?
+        else {
+            warn!("DP Status Update not acknowledged");
+            return Ok(());
+        };
+        let status = DisplayPortStatus::from(status);
+
+        let Some(pin_assignment) = pins.select(status.multi_function_preferred()) else {
+            return Ok(());
+        };
+        let mut configure = DisplayPortConfigure::from(0);
+        configure.set_configuration(DisplayPortConfiguration::UfpD);
+        configure.set_signaling(u4::new(SIGNALING_DP));
+        configure.set_pin_assignment(pin_assignment.into());
+        if self
+            .displayport_command(
+                VdmCommand::DisplayPortConfigure,
+                object_position,
+                Some(configure.into()),
+            )
+            .await?
+            .is_none()
+        {
+            warn!("DP Configure not acknowledged");
+            return Ok(());
+        }
+        info!(
+            "DisplayPort configured for pin assignment {}",
+            pin_assignment
+        );
+        self.vdm.displayport_configured(Some(pin_assignment));
+        self.vdm
+            .displayport_hpd(status.hpd_state(), status.irq_hpd());
+        Ok(())
+    }
+
+    /// Runs Discover Identity, Discover SVIDs and Discover Modes and returns
+    /// the object position and capabilities of the first DisplayPort mode
+    /// with UFP_D support.
+    async fn discover_displayport(
+        &mut self,
+    ) -> Result<Option<(u8, DisplayPortCapabilities)>, Error> {
+        let revision = self.protocol_engine.specification_revision();
+        let mut obj_buf = [0; 7];
+
+        let request = StructuredVdmHeader::request(PD_SID, VdmCommand::DiscoverIdentity, revision);
+        match self
+            .structured_vdm(Sop::Sop, request, &[], &mut obj_buf)
+            .await?
+        {
+            Some((header, &[id_header, ..]))
+                if header.command_type() == VdmCommandType::Ack
+                    && IdHeaderVdo::from(id_header).modal_operation_supported() => {}
+            _ => return Ok(None),
+        }
+
+        let request = StructuredVdmHeader::request(PD_SID, VdmCommand::DiscoverSvids, revision);
+        match self
+            .structured_vdm(Sop::Sop, request, &[], &mut obj_buf)
+            .await?
+        {
+            Some((header, vdos))
+                if header.command_type() == VdmCommandType::Ack
+                    && vdos
+                        .iter()
+                        .flat_map(|&vdo| [(vdo >> 16) as u16, vdo as u16])
+                        .take_while(|&svid| svid != 0)
+                        .any(|svid| svid == DISPLAYPORT_SID) => {}
+            _ => return Ok(None),
+        }
+
+        let request =
+            StructuredVdmHeader::request(DISPLAYPORT_SID, VdmCommand::DiscoverModes, revision);
+        match self
+            .structured_vdm(Sop::Sop, request, &[], &mut obj_buf)
+            .await?
+        {
+            Some((header, vdos)) if header.command_type() == VdmCommandType::Ack => Ok(vdos
+                .iter()
+                .map(|&vdo| DisplayPortCapabilities::from(vdo))
+                .zip(1..)
+                .find(|(capabilities, _)| capabilities.ufp_d_capable())
+                .map(|(capabilities, object_position)| (object_position, capabilities))),
+            _ => Ok(None),
+        }
+    }
+
+    /// Sends a DisplayPort command for the mode at `object_position` and
+    /// returns the first VDO of the ACK, `None` when it was not acknowledged.
+    async fn displayport_command(
+        &mut self,
+        command: VdmCommand,
+        object_position: u8,
+        vdo: Option<u32>,
+    ) -> Result<Option<u32>, Error> {
+        let mut request = StructuredVdmHeader::request(
+            DISPLAYPORT_SID,
+            command,
+            self.protocol_engine.specification_revision(),
+        );
+        request.set_object_position(u3::new(object_position));
+        let mut obj_buf = [0; 7];
+        match self
+            .structured_vdm(Sop::Sop, request, vdo.as_slice(), &mut obj_buf)
+            .await?
+        {
+            Some((header, vdos)) if header.command_type() == VdmCommandType::Ack => {
+                Ok(Some(vdos.first().copied().unwrap_or(0)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Forwards the HPD state of an Attention message for the entered mode.
+    pub(super) fn displayport_attention(&mut self, header: StructuredVdmHeader, vdos: &[u32]) {
+        let (Some(object_position), Some(&status)) = (self.displayport_mode, vdos.first()) else {
+            info!("Ignoring DisplayPort Attention");
+            return;
+        };
+        if header.object_position().value() != object_position {
+            warn!("DisplayPort Attention for another mode");
+            return;
+        }
+        let status = DisplayPortStatus::from(status);
+        debug!("DisplayPort Attention {}", status);
+        self.vdm
+            .displayport_hpd(status.hpd_state(), status.irq_hpd());
+    }
+
+    /// Returns the mux to USB after a hard reset, which exits all modes.
+    pub(super) fn exit_displayport(&mut self) {
+        if self.displayport_mode.take().is_some() {
+            info!("Exited DisplayPort mode");
+            self.vdm.displayport_configured(None);
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use embassy_time::{with_timeout, Duration, Timer};
+
+use super::{PolicyEngine, TIMEOUT_SRC_TRANSITION};
+use crate::phy::{DualRoleCcPhy, PdPhy, VconnSwitch};
+use crate::protocol::*;
+use crate::protocol_engine::{HardReset, Message};
+use crate::sink_policy::SinkPolicy;
+use crate::source_policy::{PowerSupply, SourcePolicy};
+use crate::vdm_policy::{UnstructuredVdmHandler, VdmPolicy};
+
+/// Time the initial source has to switch off VBUS during a power role swap.
+const TIMEOUT_PS_SOURCE_OFF: Duration = Duration::from_millis(900);
+
+/// Time the new source has to switch on VBUS during a power role swap.
+const TIMEOUT_PS_SOURCE_ON: Duration = Duration::from_millis(450);
+
+impl<
+        P: PdPhy,
+        S: SinkPolicy + SourcePolicy,
+        C: DualRoleCcPhy,
+        V: VconnSwitch,
+        M: VdmPolicy,
+        U: UnstructuredVdmHandler,
+    > PolicyEngine<P, S, C, V, M, U>
+{
+    /// Runs the state machine of a dual-role power port.
+    ///
+    /// The port starts in `power_role` and switches roles whenever the port
+    /// partner requests a power role swap which the policy accepts. `supply`
+    /// must be disabled when starting as sink and at vSafe5V when starting as
+    /// source.
+    ///
+    /// Returns after a hard reset or when a power role swap failed. In both
+    /// cases the port must return to its initial role.
+    pub async fn run_dual_role(
+        &mut self,
+        mut power_role: PortPowerRole,
+        supply: &mut impl PowerSupply,
+    ) -> Result<(), HardReset> {
+        self.set_power_role(power_role);
+        self.protocol_engine.set_data_role(match power_role {
+            PortPowerRole::Sink => PortDataRole::UpstreamFacingPort,
+            PortPowerRole::Source => PortDataRole::DownstreamFacingPort,
+        });
+        self.dual_role = true;
+        self.set_vconn(power_role == PortPowerRole::Source).await;
+
+        loop {
+            power_role = match power_role {
+                PortPowerRole::Sink => {
+                    self.sink_loop().await?;
+                    self.swap_to_source(supply).await?;
+                    PortPowerRole::Source
+                }
+                PortPowerRole::Source => {
+                    self.source_loop(supply).await?;
+                    self.swap_to_sink(supply).await?;
+                    PortPowerRole::Sink
+                }
+            };
+        }
+    }
+
+    /// Completes an accepted power role swap from sink to source.
+    async fn swap_to_source(&mut self, supply: &mut impl PowerSupply) -> Result<(), HardReset> {
+        info!("Power role swap, waiting for the source to switch off VBUS");
+        self.contract = None;
+        self.wait_ps_rdy(TIMEOUT_PS_SOURCE_OFF).await?;
+
+        self.set_power_role(PortPowerRole::Source);
+        supply.enable().await;
+        self.transmit_ps_rdy().await?;
+        info!("Power role swap finished, now source");
+        Ok(())
+    }
+
+    /// Completes an accepted power role swap from source to sink.
+    async fn swap_to_sink(&mut self, supply: &mut impl PowerSupply) -> Result<(), HardReset> {
+        info!("Power role swap, switching off VBUS");
+        self.contract = None;
+        Timer::after(TIMEOUT_SRC_TRANSITION).await;
+        supply.disable().await;
+
+        self.set_power_role(PortPowerRole::Sink);
+        self.transmit_ps_rdy().await?;
+        self.wait_ps_rdy(TIMEOUT_PS_SOURCE_ON).await?;
+        info!("Power role swap finished, now sink");
+        Ok(())
+    }
+
+    fn set_power_role(&mut self, power_role: PortPowerRole) {
+        self.protocol_engine.cc_phy_mut().set_power_role(power_role);
+        self.protocol_engine.set_power_role(power_role);
+    }
+
+    async fn transmit_ps_rdy(&mut self) -> Result<(), HardReset> {
+        if self
+            .protocol_engine
+            .transmit(&Message::Control(ControlMessageType::PsRdy))
+            .await?
+        {
+            Ok(())
+        } else {
+            error!("PS_RDY not acknowledged during power role swap");
+            Err(HardReset)
+        }
+    }
+
+    /// Waits for the PS_RDY of the port partner during a power role swap.
+    ///
+    /// A soft reset is not possible during the swap, every error ends it.
+    async fn wait_ps_rdy(&mut self, timeout: Duration) -> Result<(), HardReset> {
+        match with_timeout(timeout, self.protocol_engine.receive(&mut [])).await {
+            Ok(Ok(Message::Control(ControlMessageType::PsRdy))) => Ok(()),
+            Ok(Ok(msg)) => {
+                error!("Expected PS_RDY message, received {} instead", msg);
+                Err(HardReset)
+            }
+            Ok(Err(e)) => Err(e),
+            Err(_) => {
+                error!("PS_RDY timeout during power role swap");
+                Err(HardReset)
+            }
+        }
+    }
+}
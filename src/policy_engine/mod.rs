@@ -0,0 +1,494 @@
+//! Policy engine for the sink and source power roles.
+
+mod bist;
+mod cable;
+mod displayport;
+mod dual_role;
+mod sink;
+mod source;
+mod vdm;
+
+use embassy_time::{with_timeout, Duration, Instant};
+
+use crate::phy::{CcPhy, NoCcPhy, NoVconn, PdPhy, VconnSwitch};
+use crate::protocol::source_capabilities::PowerDataObject;
+use crate::protocol::*;
+use crate::protocol_engine::{HardReset, Message, ProtocolEngine};
+use crate::sink_policy::Selection;
+use crate::vdm_policy::{NoVdm, UnstructuredVdmHandler, VdmPolicy};
+
+/// Time to wait for a response.
+const TIMEOUT_SENDER_RESPONSE: Duration = Duration::from_millis(30);
+
+/// Time between accepting a request and starting the VBUS transition.
+const TIMEOUT_SRC_TRANSITION: Duration = Duration::from_millis(30);
+
+/// Time the new VCONN source has to switch on VCONN during a VCONN swap.
+const TIMEOUT_VCONN_SOURCE_ON: Duration = Duration::from_millis(100);
+
+/// Runs the sink or source state machine.
+///
+/// `S` is the device policy, a [`SinkPolicy`](crate::sink_policy::SinkPolicy)
+/// for [`run_sink`](Self::run_sink), a
+/// [`SourcePolicy`](crate::source_policy::SourcePolicy) for
+/// [`run_source`](Self::run_source) and both for
+/// [`run_dual_role`](Self::run_dual_role).
+///
+/// Ports able to supply VCONN provide their switch with
+/// [`with_vconn`](Self::with_vconn), the answers to Vendor Defined Messages
+/// are configured with [`with_vdm`](Self::with_vdm) and unstructured VDMs
+/// of a vendor are handled by
+/// [`with_unstructured_vdm`](Self::with_unstructured_vdm).
+pub struct PolicyEngine<
+    P: PdPhy,
+    S,
+    C: CcPhy = NoCcPhy,
+    V: VconnSwitch = NoVconn,
+    M: VdmPolicy = NoVdm,
+    U: UnstructuredVdmHandler = NoVdm,
+> {
+    protocol_engine: ProtocolEngine<P, C>,
+    policy: S,
+    /// Capabilities of the source, received as sink or advertised as source.
+    source_capabilities: [PowerDataObject; 7],
+    num_source_capabilities: usize,
+    contract: Option<Selection>,
+    /// Voltage requested with a sink command instead of the policy selection.
+    requested_voltage_mv: Option<u32>,
+    /// GiveBack set with a sink command instead of the policy selection.
+    requested_give_back_ma: Option<Option<u32>>,
+    /// Hard resets sent since the last explicit contract as sink, apart from
+    /// the ones commanded by the application.
+    hard_reset_count: u8,
+    /// Start of the wait for source capabilities as sink.
+    wait_cap_start: Option<Instant>,
+    /// Time at which the sink repeats a request the source answered with
+    /// Wait.
+    sink_request_at: Option<Instant>,
+    /// Time at which the sink repeats the request of its PPS contract.
+    pps_request_at: Option<Instant>,
+    /// Power role swaps are possible.
+    dual_role: bool,
+    vconn: Option<V>,
+    /// The port currently supplies VCONN.
+    vconn_source: bool,
+    vdm: M,
+    /// A structured VDM request of the VDM policy awaits its response.
+    vdm_request_pending: bool,
+    /// Object position of the entered DisplayPort mode.
+    displayport_mode: Option<u8>,
+    /// Vendor ID and handler of unstructured VDMs.
+    unstructured_vdm: Option<(u16, U)>,
+}
+
+enum Error {
+    HardReset,
+    SoftReset,
+    /// A power role swap was accepted.
+    PowerRoleSwap,
+}
+
+impl From<HardReset> for Error {
+    fn from(_: HardReset) -> Self {
+        Self::HardReset
+    }
+}
+
+impl<P: PdPhy, S, C: CcPhy> PolicyEngine<P, S, C> {
+    pub fn new(protocol_engine: ProtocolEngine<P, C>, policy: S) -> Self {
+        Self {
+            protocol_engine,
+            policy,
+            source_capabilities: [PowerDataObject::Unknown(0); 7],
+            num_source_capabilities: 0,
+            contract: None,
+            requested_voltage_mv: None,
+            requested_give_back_ma: None,
+            hard_reset_count: 0,
+            wait_cap_start: None,
+            sink_request_at: None,
+            pps_request_at: None,
+            dual_role: false,
+            vconn: None,
+            vconn_source: false,
+            vdm: NoVdm,
+            vdm_request_pending: false,
+            displayport_mode: None,
+            unstructured_vdm: None,
+        }
+    }
+}
+
+/// Parts of the policy engine replaced by its builder methods.
+struct Extensions<V, M, U> {
+    vconn: Option<V>,
+    vdm: M,
+    unstructured_vdm: Option<(u16, U)>,
+}
+
+impl<P: PdPhy, S, C: CcPhy, V: VconnSwitch, M: VdmPolicy, U: UnstructuredVdmHandler>
+    PolicyEngine<P, S, C, V, M, U>
+{
+    /// Moves the state into a policy engine with the extensions returned by
+    /// `f`.
+    fn map_extensions<V2: VconnSwitch, M2: VdmPolicy, U2: UnstructuredVdmHandler>(
+        self,
+        f: impl FnOnce(Extensions<V, M, U>) -> Extensions<V2, M2, U2>,
+    ) -> PolicyEngine<P, S, C, V2, M2, U2> {
+        let extensions = f(Extensions {
+            vconn: self.vconn,
+            vdm: self.vdm,
+            unstructured_vdm: self.unstructured_vdm,
+        });
+        PolicyEngine {
+            protocol_engine: self.protocol_engine,
+            policy: self.policy,
+            source_capabilities: self.source_capabilities,
+            num_source_capabilities: self.num_source_capabilities,
+            contract: self.contract,
+            requested_voltage_mv: self.requested_voltage_mv,
+            requested_give_back_ma: self.requested_give_back_ma,
+            hard_reset_count: self.hard_reset_count,
+            wait_cap_start: self.wait_cap_start,
+            sink_request_at: self.sink_request_at,
+            pps_request_at: self.pps_request_at,
+            dual_role: self.dual_role,
+            vconn: extensions.vconn,
+            vconn_source: self.vconn_source,
+            vdm: extensions.vdm,
+            vdm_request_pending: self.vdm_request_pending,
+            displayport_mode: self.displayport_mode,
+            unstructured_vdm: extensions.unstructured_vdm,
+        }
+    }
+}
+
+impl<P: PdPhy, S, C: CcPhy, M: VdmPolicy, U: UnstructuredVdmHandler>
+    PolicyEngine<P, S, C, NoVconn, M, U>
+{
+    /// Uses `vconn` to supply VCONN.
+    ///
+    /// The port supplies VCONN from the start when running as source and
+    /// accepts VCONN swaps.
+    pub fn with_vconn<V: VconnSwitch>(self, vconn: V) -> PolicyEngine<P, S, C, V, M, U> {
+        self.map_extensions(|e| Extensions {
+            vconn: Some(vconn),
+            vdm: e.vdm,
+            unstructured_vdm: e.unstructured_vdm,
+        })
+    }
+}
+
+impl<P: PdPhy, S, C: CcPhy, V: VconnSwitch, U: UnstructuredVdmHandler>
+    PolicyEngine<P, S, C, V, NoVdm, U>
+{
+    /// Answers the discovery commands of structured Vendor Defined Messages
+    /// with `vdm` instead of NAK.
+    pub fn with_vdm<M: VdmPolicy>(self, vdm: M) -> PolicyEngine<P, S, C, V, M, U> {
+        self.map_extensions(|e| Extensions {
+            vconn: e.vconn,
+            vdm,
+            unstructured_vdm: e.unstructured_vdm,
+        })
+    }
+}
+
+impl<P: PdPhy, S, C: CcPhy, V: VconnSwitch, M: VdmPolicy> PolicyEngine<P, S, C, V, M, NoVdm> {
+    /// Passes unstructured Vendor Defined Messages with `vendor_id` to
+    /// `handler`, which may also reply to them.
+    ///
+    /// Unstructured VDMs of other vendors are still ignored with USB PD 2.0 and
+    /// answered with Not_Supported with USB PD 3.x.
+    pub fn with_unstructured_vdm<U: UnstructuredVdmHandler>(
+        self,
+        vendor_id: u16,
+        handler: U,
+    ) -> PolicyEngine<P, S, C, V, M, U> {
+        self.map_extensions(|e| Extensions {
+            vconn: e.vconn,
+            vdm: e.vdm,
+            unstructured_vdm: Some((vendor_id, handler)),
+        })
+    }
+}
+
+impl<P: PdPhy, S, C: CcPhy, V: VconnSwitch, M: VdmPolicy, U: UnstructuredVdmHandler>
+    PolicyEngine<P, S, C, V, M, U>
+{
+    /// Waits until a sink initiated atomic message sequence can be started.
+    ///
+    /// Returns messages received in the meantime.
+    async fn start_ams<'m>(
+        &mut self,
+        obj_buf: &'m mut [u32],
+    ) -> Result<Option<Message<'m>>, Error> {
+        match self.protocol_engine.start_ams(obj_buf).await? {
+            Some(msg) => self.handle_soft_reset(msg).await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    async fn receive<'m>(&mut self, obj_buf: &'m mut [u32]) -> Result<Message<'m>, Error> {
+        let msg = self.protocol_engine.receive(obj_buf).await?;
+        self.handle_soft_reset(msg).await
+    }
+
+    async fn handle_soft_reset<'m>(&mut self, msg: Message<'m>) -> Result<Message<'m>, Error> {
+        match msg {
+            Message::Control(ControlMessageType::SoftReset) => {
+                warn!("Received SoftReset, sending Accept");
+                self.transmit(&Message::Control(ControlMessageType::Accept))
+                    .await?;
+                Err(Error::SoftReset)
+            }
+            msg => Ok(msg),
+        }
+    }
+
+    async fn transmit(&mut self, msg: &Message<'_>) -> Result<(), Error> {
+        if self.protocol_engine.transmit(msg).await? {
+            Ok(())
+        } else {
+            self.transmit_soft_reset().await?;
+            Err(Error::SoftReset)
+        }
+    }
+
+    /// Answers a message that is not supported in the current state.
+    async fn not_supported(&mut self, msg: Message<'_>) -> Result<(), Error> {
+        info!("Rejecting unsupported message {}", msg);
+        let response = match self.protocol_engine.specification_revision() {
+            SpecificationRevision::Revision3_0 => ControlMessageType::NotSupported,
+            _ => ControlMessageType::Reject,
+        };
+        self.transmit(&Message::Control(response)).await
+    }
+
+    /// Sends DR_Swap and returns the new data role when the port partner
+    /// accepted.
+    async fn data_role_swap(&mut self) -> Result<Option<PortDataRole>, Error> {
+        info!("Requesting data role swap");
+        self.transmit(&Message::Control(ControlMessageType::DrSwap))
+            .await?;
+        match with_timeout(TIMEOUT_SENDER_RESPONSE, self.receive(&mut [])).await {
+            Ok(Ok(Message::Control(ControlMessageType::Accept))) => Ok(Some(self.swap_data_role())),
+            Ok(Ok(Message::Control(
+                ControlMessageType::Reject
+                | ControlMessageType::Wait
+                | ControlMessageType::NotSupported,
+            ))) => {
+                info!("Data role swap refused");
+                Ok(None)
+            }
+            Ok(Ok(msg)) => {
+                error!(
+                    "Expected Accept or Reject message in response to DR_Swap, received {} instead",
+                    msg
+                );
+                self.transmit_soft_reset().await?;
+                Err(Error::SoftReset)
+            }
+            Ok(Err(e)) => Err(e),
+            // The port stays in its data role.
+            Err(_) => {
+                warn!("DR_Swap timeout");
+                Ok(None)
+            }
+        }
+    }
+
+    /// Sends PR_Swap and returns [`Error::PowerRoleSwap`] when the port
+    /// partner accepted, the swap is then completed by the dual-role loop.
+    async fn power_role_swap(&mut self) -> Result<(), Error> {
+        if !self.dual_role {
+            warn!("Power role swap needs a dual-role port");
+            return Ok(());
+        }
+        info!("Requesting power role swap");
+        self.transmit(&Message::Control(ControlMessageType::PrSwap))
+            .await?;
+        match with_timeout(TIMEOUT_SENDER_RESPONSE, self.receive(&mut [])).await {
+            Ok(Ok(Message::Control(ControlMessageType::Accept))) => Err(Error::PowerRoleSwap),
+            Ok(Ok(Message::Control(ControlMessageType::Wait))) => {
+                info!("Port partner asked to wait, power role unchanged");
+                Ok(())
+            }
+            Ok(Ok(Message::Control(
+                ControlMessageType::Reject | ControlMessageType::NotSupported,
+            ))) => {
+                info!("Power role swap refused");
+                Ok(())
+            }
+            Ok(Ok(msg)) => {
+                error!(
+                    "Expected Accept or Reject message in response to PR_Swap, received {} instead",
+                    msg
+                );
+                self.transmit_soft_reset().await?;
+                Err(Error::SoftReset)
+            }
+            Ok(Err(e)) => Err(e),
+            Err(_) => {
+                warn!("PR_Swap timeout");
+                Ok(())
+            }
+        }
+    }
+
+    /// Answers a DR_Swap of the port partner and returns the new data role
+    /// when `accept` is set.
+    async fn respond_data_role_swap(
+        &mut self,
+        accept: bool,
+    ) -> Result<Option<PortDataRole>, Error> {
+        if !accept {
+            self.transmit(&Message::Control(ControlMessageType::Reject))
+                .await?;
+            return Ok(None);
+        }
+        self.transmit(&Message::Control(ControlMessageType::Accept))
+            .await?;
+        Ok(Some(self.swap_data_role()))
+    }
+
+    /// Returns `true` when the port should take over VCONN, which is the
+    /// case for a DFP able to supply it.
+    fn wants_vconn_swap(&self) -> bool {
+        self.vconn.is_some()
+            && !self.vconn_source
+            && self.protocol_engine.data_role() == PortDataRole::DownstreamFacingPort
+    }
+
+    /// Sends VCONN_Swap and completes the swap when the port partner accepted.
+    async fn vconn_swap(&mut self) -> Result<(), Error> {
+        info!("Requesting VCONN swap");
+        self.transmit(&Message::Control(ControlMessageType::VconnSwap))
+            .await?;
+        match with_timeout(TIMEOUT_SENDER_RESPONSE, self.receive(&mut [])).await {
+            Ok(Ok(Message::Control(ControlMessageType::Accept))) => {
+                self.complete_vconn_swap().await
+            }
+            Ok(Ok(Message::Control(
+                ControlMessageType::Reject
+                | ControlMessageType::Wait
+                | ControlMessageType::NotSupported,
+            ))) => {
+                info!("VCONN swap refused");
+                Ok(())
+            }
+            Ok(Ok(msg)) => {
+                error!(
+                    "Expected Accept or Reject message in response to VCONN_Swap, received {} instead",
+                    msg
+                );
+                self.transmit_soft_reset().await?;
+                Err(Error::SoftReset)
+            }
+            Ok(Err(e)) => Err(e),
+            Err(_) => {
+                warn!("VCONN_Swap timeout");
+                Ok(())
+            }
+        }
+    }
+
+    /// Answers a VCONN_Swap of the port partner, which is only accepted by
+    /// ports able to supply VCONN.
+    async fn respond_vconn_swap(&mut self) -> Result<(), Error> {
+        if self.vconn.is_none() {
+            return self
+                .not_supported(Message::Control(ControlMessageType::VconnSwap))
+                .await;
+        }
+        self.transmit(&Message::Control(ControlMessageType::Accept))
+            .await?;
+        self.complete_vconn_swap().await
+    }
+
+    /// Hands VCONN over to the port partner or takes it over after an
+    /// accepted VCONN swap.
+    async fn complete_vconn_swap(&mut self) -> Result<(), Error> {
+        if !self.vconn_source {
+            self.set_vconn(true).await;
+            info!("VCONN swap finished, now VCONN source");
+            return self
+                .transmit(&Message::Control(ControlMessageType::PsRdy))
+                .await;
+        }
+
+        // VCONN stays on until the port partner supplies it.
+        match with_timeout(TIMEOUT_VCONN_SOURCE_ON, self.receive(&mut [])).await {
+            Ok(Ok(Message::Control(ControlMessageType::PsRdy))) => {
+                self.set_vconn(false).await;
+                info!("VCONN swap finished");
+                Ok(())
+            }
+            Ok(Ok(msg)) => {
+                error!("Expected PS_RDY message, received {} instead", msg);
+                self.transmit_hard_reset().await;
+                Err(Error::HardReset)
+            }
+            Ok(Err(e)) => Err(e),
+            Err(_) => {
+                error!("PS_RDY timeout during VCONN swap");
+                self.transmit_hard_reset().await;
+                Err(Error::HardReset)
+            }
+        }
+    }
+
+    /// Switches VCONN if the port is able to supply it.
+    async fn set_vconn(&mut self, enabled: bool) {
+        let Some(vconn) = &mut self.vconn else {
+            return;
+        };
+        if enabled && !self.vconn_source {
+            vconn.enable().await;
+        } else if !enabled && self.vconn_source {
+            vconn.disable().await;
+        }
+        self.vconn_source = enabled;
+    }
+
+    fn swap_data_role(&mut self) -> PortDataRole {
+        let data_role = match self.protocol_engine.data_role() {
+            PortDataRole::UpstreamFacingPort => PortDataRole::DownstreamFacingPort,
+            PortDataRole::DownstreamFacingPort => PortDataRole::UpstreamFacingPort,
+        };
+        self.protocol_engine.set_data_role(data_role);
+        info!("Data role swapped, now {}", data_role);
+        data_role
+    }
+
+    async fn transmit_soft_reset(&mut self) -> Result<(), HardReset> {
+        if !self
+            .protocol_engine
+            .transmit(&Message::Control(ControlMessageType::SoftReset))
+            .await?
+        {
+            error!("Error during SoftReset transmission");
+            self.transmit_hard_reset().await;
+            return Err(HardReset);
+        }
+        let msg = with_timeout(
+            TIMEOUT_SENDER_RESPONSE,
+            self.protocol_engine.receive(&mut []),
+        )
+        .await
+        .map_err(|_| HardReset)??;
+        if msg != Message::Control(ControlMessageType::Accept) {
+            error!(
+                "Expected Accept message in renspone to SoftReset, received {} instead",
+                msg
+            );
+            self.transmit_hard_reset().await;
+            return Err(HardReset);
+        };
+        Ok(())
+    }
+
+    async fn transmit_hard_reset(&mut self) {
+        self.hard_reset_count = self.hard_reset_count.saturating_add(1);
+        self.protocol_engine.transmit_hard_reset().await;
+    }
+}
@@ -0,0 +1,698 @@
+use core::future::pending;
+
+use bilge::arbitrary_int::*;
+use embassy_futures::select::{select, Either};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+
+use super::{Error, PolicyEngine, TIMEOUT_SENDER_RESPONSE};
+use crate::phy::{CcPhy, PdPhy, VconnSwitch};
+use crate::protocol::source_capabilities::PowerDataObject;
+use crate::protocol::*;
+use crate::protocol_engine::{HardReset, Message};
+use crate::sink_policy::{Selection, SinkCommand, SinkEvent, SinkPolicy};
+use crate::vdm_policy::{UnstructuredVdmHandler, VdmPolicy, VdmRequest};
+
+/// Time to wait for a PS_RDY message.
+const TIMEOUT_PS_TRANSITION: Duration = Duration::from_millis(500);
+
+/// Time after which a sink that has not received source capabilities since
+/// it started asks for them, tFirstSourceCap.
+const TIMEOUT_FIRST_SOURCE_CAP: Duration = Duration::from_millis(250);
+
+/// Time after which a sink without source capabilities sends a hard reset,
+/// tTypeCSinkWaitCap is 310ms to 620ms.
+const TIMEOUT_SINK_WAIT_CAP: Duration = Duration::from_millis(465);
+
+/// Number of hard resets after which a sink gives up on the source and stays
+/// on the Type-C current.
+const N_HARD_RESET_COUNT: u8 = 2;
+
+/// Time after which a sink told to wait by the source repeats its request,
+/// tSinkRequest.
+const TIMEOUT_SINK_REQUEST: Duration = Duration::from_millis(100);
+
+/// Time after which a sink in a PPS contract re-sends its request.
+const TIMEOUT_PPS_REQUEST: Duration = Duration::from_secs(10);
+
+/// Atomic message sequences started by the sink.
+#[derive(Clone, Copy)]
+enum Ams {
+    Renegotiate,
+    DataRoleSwap,
+    VconnSwap,
+    EnterDisplayPort,
+    GetSourceCapabilities,
+    StructuredVdm(VdmRequest),
+    PowerRoleSwap,
+}
+
+/// Response of the source to a request.
+#[derive(Clone, Copy, PartialEq)]
+enum RequestOutcome {
+    /// Accepted and followed by PS_RDY.
+    Accepted,
+    Rejected,
+    Wait,
+}
+
+impl<
+        P: PdPhy,
+        S: SinkPolicy,
+        C: CcPhy,
+        V: VconnSwitch,
+        M: VdmPolicy,
+        U: UnstructuredVdmHandler,
+    > PolicyEngine<P, S, C, V, M, U>
+{
+    pub async fn run_sink(&mut self) -> Result<(), HardReset> {
+        self.protocol_engine.set_power_role(PortPowerRole::Sink);
+        self.protocol_engine
+            .set_data_role(PortDataRole::UpstreamFacingPort);
+        self.dual_role = false;
+        self.set_vconn(false).await;
+        self.policy.notify(SinkEvent::Attached);
+        self.sink_loop().await
+    }
+
+    /// Resets the port after the source was detached.
+    ///
+    /// The application detects the detach, e.g. from the loss of VBUS, and
+    /// calls this after stopping [`run_sink`](Self::run_sink).
+    pub async fn detach(&mut self) {
+        info!("Source detached");
+        self.cancel_vdm_request();
+        self.exit_displayport();
+        self.set_vconn(false).await;
+        self.contract = None;
+        self.hard_reset_count = 0;
+        self.protocol_engine.reset();
+        self.policy.notify(SinkEvent::Detached);
+    }
+
+    /// Runs the sink states until a hard reset or an accepted power role
+    /// swap, in which case `Ok(())` is returned.
+    pub(super) async fn sink_loop(&mut self) -> Result<(), HardReset> {
+        self.contract = None;
+        self.num_source_capabilities = 0;
+        let mut ready = false;
+        let mut pending_ams = None;
+        self.wait_cap_start = Some(Instant::now());
+        self.sink_request_at = None;
+        self.pps_request_at = None;
+        loop {
+            let mut obj_buf = [0; 7];
+            // tTypeCSinkWaitCap also runs while other messages are handled
+            // and while atomic message sequences are started.
+            let msg = if !ready && self.sink_wait_cap_expired() {
+                Err(self.sink_wait_cap_timeout().await)
+            } else if pending_ams.is_some() {
+                // The source only signals SinkTxOk in an explicit contract,
+                // Get_Source_Cap before it is sent right away.
+                if ready {
+                    self.start_ams(&mut obj_buf).await
+                } else {
+                    Ok(None)
+                }
+            } else if let (false, Some(start)) = (ready, self.wait_cap_start) {
+                match self.wait_for_capabilities(&mut obj_buf, start).await {
+                    Ok(Either::First(msg)) => Ok(Some(msg)),
+                    Ok(Either::Second(ams)) => {
+                        pending_ams = Some(ams);
+                        continue;
+                    }
+                    Err(e) => Err(e),
+                }
+            } else {
+                match self.receive_ready(&mut obj_buf, ready).await {
+                    Ok(Either::First(msg)) => Ok(Some(msg)),
+                    Ok(Either::Second(ams)) => {
+                        pending_ams = Some(ams);
+                        continue;
+                    }
+                    Err(e) => Err(e),
+                }
+            };
+            let result = match msg {
+                Ok(Some(msg)) => self.handle_sink_message(msg, ready).await,
+                Ok(None) => match pending_ams.take() {
+                    Some(Ams::Renegotiate) => {
+                        info!("Renegotiating power");
+                        // The previous contract stays valid when the request fails.
+                        self.power_negotiation().await.map(|_| ready)
+                    }
+                    Some(Ams::DataRoleSwap) => {
+                        let result = self.sink_data_role_swap().await.map(|_| ready);
+                        pending_ams = self.sink_initial_ams_after(Some(Ams::DataRoleSwap));
+                        result
+                    }
+                    Some(Ams::VconnSwap) => {
+                        let result = self.vconn_swap().await.map(|_| ready);
+                        pending_ams = self.sink_initial_ams_after(Some(Ams::VconnSwap));
+                        result
+                    }
+                    Some(Ams::EnterDisplayPort) => self.enter_displayport().await.map(|_| ready),
+                    Some(Ams::GetSourceCapabilities) => self.get_source_capabilities(ready).await,
+                    Some(Ams::StructuredVdm(request)) => {
+                        self.application_vdm(request).await.map(|_| ready)
+                    }
+                    Some(Ams::PowerRoleSwap) => self.power_role_swap().await.map(|_| ready),
+                    None => Ok(ready),
+                },
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(r) => {
+                    if r && !ready {
+                        pending_ams = self.sink_initial_ams_after(None);
+                    }
+                    ready = r;
+                }
+                Err(Error::HardReset) => {
+                    self.cancel_vdm_request();
+                    self.exit_displayport();
+                    self.set_vconn(false).await;
+                    self.contract = None;
+                    self.pps_request_at = None;
+                    self.policy.notify(SinkEvent::HardReset);
+                    return Err(HardReset);
+                }
+                Err(Error::PowerRoleSwap) => {
+                    self.cancel_vdm_request();
+                    return Ok(());
+                }
+                Err(Error::SoftReset) => {
+                    ready = false;
+                    pending_ams = None;
+                    self.cancel_vdm_request();
+                    self.contract = None;
+                    self.pps_request_at = None;
+                    self.num_source_capabilities = 0;
+                    self.wait_cap_start = Some(Instant::now());
+                    self.policy.notify(SinkEvent::SoftReset);
+                }
+            }
+        }
+    }
+
+    async fn handle_sink_message(
+        &mut self,
+        msg: Message<'_>,
+        was_ready: bool,
+    ) -> Result<bool, Error> {
+        let mut ready = was_ready;
+        match msg {
+            Message::Control(ControlMessageType::Ping) => info!("Ignoring {}", msg),
+            Message::Control(ControlMessageType::GetSinkCap) => {
+                info!("Sending sink capabilites");
+                self.sink_capabilities().await?;
+            }
+            Message::Data(DataMessageType::SourceCapabilites, objects) => {
+                info!("Source capablities received, starting power negotiation");
+                for (i, (pdo, &obj)) in self.source_capabilities.iter_mut().zip(objects).enumerate()
+                {
+                    *pdo = PowerDataObject::from(obj);
+                    debug!("PDO {=usize}: {}", i + 1, pdo);
+                }
+                self.num_source_capabilities = objects.len();
+                self.wait_cap_start = None;
+                self.policy.notify(SinkEvent::SourceCapabilitiesReceived(
+                    &self.source_capabilities[..self.num_source_capabilities],
+                ));
+                if self.power_negotiation().await? == RequestOutcome::Accepted {
+                    info!("Power negotiation finished");
+                    ready = true;
+                } else {
+                    info!("Power negotiation unsuccessful");
+                }
+            }
+            Message::Control(ControlMessageType::GotoMin) if ready => match self.contract {
+                Some(
+                    contract @ Selection {
+                        min_operating_current_ma: Some(min_operating_current_ma),
+                        ..
+                    },
+                ) => self.goto_min(contract, min_operating_current_ma).await?,
+                _ => self.not_supported(msg).await?,
+            },
+            Message::Control(ControlMessageType::PrSwap) if self.dual_role && ready => {
+                if self.policy.accept_swap_to_source() {
+                    self.transmit(&Message::Control(ControlMessageType::Accept))
+                        .await?;
+                    return Err(Error::PowerRoleSwap);
+                }
+                self.transmit(&Message::Control(ControlMessageType::Reject))
+                    .await?;
+            }
+            Message::Control(ControlMessageType::VconnSwap) if ready => {
+                self.respond_vconn_swap().await?;
+            }
+            Message::Control(ControlMessageType::DrSwap) if ready => {
+                let accept = self.policy.accept_data_role_swap();
+                if let Some(data_role) = self.respond_data_role_swap(accept).await? {
+                    self.policy.data_role_swapped(data_role);
+                }
+            }
+            Message::Data(DataMessageType::VendorDefined, objects) => {
+                self.respond_vdm(objects).await?
+            }
+            Message::Data(DataMessageType::Bist, objects) if ready => {
+                self.respond_bist(objects).await?
+            }
+            msg => self.not_supported(msg).await?,
+        }
+        Ok(ready)
+    }
+
+    /// Returns the sequence following `previous` once the first explicit
+    /// contract is in place: swapping to the preferred data role, taking
+    /// over VCONN and entering the DisplayPort mode as DFP.
+    fn sink_initial_ams_after(&self, previous: Option<Ams>) -> Option<Ams> {
+        match previous {
+            None if self.sink_prefers_data_role_swap() => Some(Ams::DataRoleSwap),
+            None | Some(Ams::DataRoleSwap) if self.wants_vconn_swap() => Some(Ams::VconnSwap),
+            None | Some(Ams::DataRoleSwap | Ams::VconnSwap) if self.wants_displayport() => {
+                Some(Ams::EnterDisplayPort)
+            }
+            _ => None,
+        }
+    }
+
+    fn sink_prefers_data_role_swap(&self) -> bool {
+        self.policy
+            .preferred_data_role()
+            .is_some_and(|data_role| data_role != self.protocol_engine.data_role())
+    }
+
+    async fn sink_data_role_swap(&mut self) -> Result<(), Error> {
+        if let Some(data_role) = self.data_role_swap().await? {
+            self.policy.data_role_swapped(data_role);
+        }
+        Ok(())
+    }
+
+    /// Waits for source capabilities after the start, a soft reset or a
+    /// request that failed without an explicit contract.
+    ///
+    /// The capabilities are requested when the source has not sent any yet.
+    /// Without capabilities after tTypeCSinkWaitCap the sink sends a hard
+    /// reset, unless it already did nHardResetCount times, in which case it
+    /// keeps listening while operating from the Type-C current.
+    async fn wait_for_capabilities<'m>(
+        &mut self,
+        obj_buf: &'m mut [u32],
+        start: Instant,
+    ) -> Result<Either<Message<'m>, Ams>, Error> {
+        let elapsed = start.elapsed();
+        if self.num_source_capabilities == 0 && elapsed < TIMEOUT_FIRST_SOURCE_CAP {
+            let timeout = TIMEOUT_FIRST_SOURCE_CAP - elapsed;
+            return match with_timeout(timeout, self.receive(obj_buf)).await {
+                Ok(msg) => msg.map(Either::First),
+                Err(_) => Ok(Either::Second(Ams::GetSourceCapabilities)),
+            };
+        }
+        if self.hard_reset_count > N_HARD_RESET_COUNT {
+            return self.receive(obj_buf).await.map(Either::First);
+        }
+        let timeout = (start + TIMEOUT_SINK_WAIT_CAP).saturating_duration_since(Instant::now());
+        match with_timeout(timeout, self.receive(obj_buf)).await {
+            Ok(msg) => msg.map(Either::First),
+            Err(_) => Err(self.sink_wait_cap_timeout().await),
+        }
+    }
+
+    /// Returns `true` when tTypeCSinkWaitCap expired without source
+    /// capabilities and the sink has not given up on them yet.
+    fn sink_wait_cap_expired(&self) -> bool {
+        self.hard_reset_count <= N_HARD_RESET_COUNT
+            && self
+                .wait_cap_start
+                .is_some_and(|start| start.elapsed() >= TIMEOUT_SINK_WAIT_CAP)
+    }
+
+    /// Sends a hard reset after tTypeCSinkWaitCap expired.
+    async fn sink_wait_cap_timeout(&mut self) -> Error {
+        if self.hard_reset_count == N_HARD_RESET_COUNT {
+            warn!("No source capabilities, giving up after this hard reset");
+        } else {
+            error!("No source capabilities, sending hard reset");
+        }
+        self.transmit_hard_reset().await;
+        Error::HardReset
+    }
+
+    /// Receives the next message.
+    ///
+    /// In the ready state the atomic message sequence to start is returned
+    /// instead when the sink must send a new request, either because the
+    /// sink policy asks for it or because the PPS contract needs to be
+    /// refreshed, or when a command asks for it.
+    async fn receive_ready<'m>(
+        &mut self,
+        obj_buf: &'m mut [u32],
+        ready: bool,
+    ) -> Result<Either<Message<'m>, Ams>, Error> {
+        if !ready {
+            return self.receive(obj_buf).await.map(Either::First);
+        }
+
+        let request_at = self.sink_request_at.or(self.pps_request_at);
+        let request = async {
+            match request_at {
+                Some(at) => Timer::at(at).await,
+                None => pending().await,
+            }
+        };
+        let command = select(request, self.policy.command());
+        // Commands only start while no message is being received.
+        let receive = select(self.protocol_engine.wait_for_message(), self.vdm.request());
+        let command = match select(receive, command).await {
+            Either::First(Either::First(rx)) => {
+                rx?;
+                return self.receive(obj_buf).await.map(Either::First);
+            }
+            Either::First(Either::Second(request)) => {
+                self.vdm_request_pending = true;
+                return Ok(Either::Second(Ams::StructuredVdm(request)));
+            }
+            Either::Second(Either::First(())) => return Ok(Either::Second(Ams::Renegotiate)),
+            Either::Second(Either::Second(command)) => command,
+        };
+        info!("Received command {}", command);
+        match command {
+            SinkCommand::RequestVoltage(voltage_mv) => {
+                self.requested_voltage_mv = Some(voltage_mv);
+                Ok(Either::Second(Ams::Renegotiate))
+            }
+            SinkCommand::Renegotiate => {
+                self.requested_voltage_mv = None;
+                Ok(Either::Second(Ams::Renegotiate))
+            }
+            SinkCommand::GetSourceCapabilities => Ok(Either::Second(Ams::GetSourceCapabilities)),
+            SinkCommand::GiveBack(give_back_ma) => {
+                self.requested_give_back_ma = Some(give_back_ma);
+                Ok(Either::Second(Ams::Renegotiate))
+            }
+            SinkCommand::SoftReset => {
+                self.transmit_soft_reset().await?;
+                Err(Error::SoftReset)
+            }
+            // Commanded hard resets do not count toward nHardResetCount.
+            SinkCommand::HardReset => {
+                self.protocol_engine.transmit_hard_reset().await;
+                Err(Error::HardReset)
+            }
+            SinkCommand::PowerRoleSwap => Ok(Either::Second(Ams::PowerRoleSwap)),
+        }
+    }
+
+    /// Asks the source for its capabilities and negotiates power again when
+    /// they arrive. Returns whether the sink is in an explicit contract.
+    ///
+    /// Before the first contract the source may not support USB PD at all, a
+    /// missing GoodCRC then does not cause a soft reset.
+    async fn get_source_capabilities(&mut self, ready: bool) -> Result<bool, Error> {
+        info!("Requesting source capabilities");
+        let msg = Message::Control(ControlMessageType::GetSourceCap);
+        if ready {
+            self.transmit(&msg).await?;
+        } else if !self.protocol_engine.transmit(&msg).await? {
+            warn!("Source did not acknowledge Get_Source_Cap");
+            return Ok(ready);
+        }
+        let mut obj_buf = [0; 7];
+        match with_timeout(TIMEOUT_SENDER_RESPONSE, self.receive(&mut obj_buf)).await {
+            Ok(Ok(msg @ Message::Data(DataMessageType::SourceCapabilites, _))) => {
+                self.handle_sink_message(msg, ready).await
+            }
+            Ok(Ok(msg)) => {
+                error!("Expected Source_Capabilities, received {} instead", msg);
+                self.transmit_soft_reset().await?;
+                Err(Error::SoftReset)
+            }
+            Ok(Err(e)) => Err(e),
+            Err(_) => {
+                warn!("Source did not send its capabilities");
+                Ok(ready)
+            }
+        }
+    }
+
+    /// Requests power selected from the source capabilities.
+    ///
+    /// A rejected request without an explicit contract is repeated for
+    /// vSafe5V. When the source asks to wait, the request is repeated after
+    /// tSinkRequest in an explicit contract. Without a contract the sink
+    /// waits for new source capabilities in both cases.
+    async fn power_negotiation(&mut self) -> Result<RequestOutcome, Error> {
+        self.sink_request_at = None;
+        let source_capabilities = &self.source_capabilities[..self.num_source_capabilities];
+        let requested = self
+            .requested_voltage_mv
+            .and_then(|voltage_mv| self.policy.select_voltage(source_capabilities, voltage_mv));
+        if requested.is_none() && self.requested_voltage_mv.is_some() {
+            warn!("Requested voltage not available, using policy selection");
+        }
+        let mut selection = requested.unwrap_or_else(|| self.policy.select(source_capabilities));
+        if !(1..=self.num_source_capabilities).contains(&usize::from(selection.object_position)) {
+            error!(
+                "Policy selected invalid object position {=u8}, requesting vSafe5V",
+                selection.object_position
+            );
+            selection = self.vsafe5v_selection(selection);
+        } else if !fits_request(&selection) {
+            error!(
+                "Policy selection {} out of range, requesting vSafe5V",
+                selection
+            );
+            selection = self.vsafe5v_selection(selection);
+        } else if selection.capability_mismatch {
+            warn!("No suitable source capability, requesting vSafe5V");
+        }
+        // Programmable power supplies do not support GiveBack.
+        if let (Some(give_back_ma), None) = (self.requested_give_back_ma, selection.pps_voltage_mv)
+        {
+            selection.min_operating_current_ma =
+                give_back_ma.map(|ma| ma.min(selection.operating_current_ma));
+        }
+
+        let mut outcome = self.request(selection).await?;
+        if outcome == RequestOutcome::Rejected
+            && self.contract.is_none()
+            && selection.object_position != 1
+        {
+            warn!("Request rejected, falling back to vSafe5V");
+            outcome = self.request(self.vsafe5v_selection(selection)).await?;
+        }
+
+        match outcome {
+            RequestOutcome::Accepted => {}
+            RequestOutcome::Rejected => {
+                warn!("Request rejected");
+                // The PPS contract stays, and so does its periodic request.
+                if self.pps_request_at.is_some() {
+                    self.pps_request_at = Some(Instant::now() + TIMEOUT_PPS_REQUEST);
+                }
+                self.policy.notify(SinkEvent::ContractRejected);
+            }
+            RequestOutcome::Wait => {
+                info!("Source asked to wait");
+                self.policy.notify(SinkEvent::Wait);
+                if self.contract.is_some() {
+                    self.sink_request_at = Some(Instant::now() + TIMEOUT_SINK_REQUEST);
+                }
+            }
+        }
+        if outcome != RequestOutcome::Accepted && self.contract.is_none() {
+            self.wait_cap_start = Some(Instant::now());
+        }
+        Ok(outcome)
+    }
+
+    /// Sends the request for `selection` and waits for PS_RDY when the
+    /// source accepts it.
+    async fn request(&mut self, selection: Selection) -> Result<RequestOutcome, Error> {
+        info!("Requesting {}", selection);
+
+        // With GiveBack the minimum takes the place of the maximum current.
+        let max_or_min_current_ma = selection
+            .min_operating_current_ma
+            .unwrap_or(selection.max_operating_current_ma);
+        let obj = match selection.pps_voltage_mv {
+            Some(voltage_mv) => PpsRequest::new(
+                // Round down to stay within the current limit
+                u7::new((selection.operating_current_ma / 50) as u8),
+                u2::new(0),
+                u12::new((voltage_mv / 20) as u16),
+                false,
+                false,
+                false,
+                false,
+                false,
+                selection.capability_mismatch,
+                false,
+                u3::new(selection.object_position),
+                false,
+            )
+            .into(),
+            None => Request::new(
+                // Round up to next 10mA step
+                u10::new(((max_or_min_current_ma + 9) / 10) as u16),
+                u10::new(((selection.operating_current_ma + 9) / 10) as u16),
+                u4::new(0),
+                false,
+                false,
+                selection.capability_mismatch,
+                selection.min_operating_current_ma.is_some(),
+                u3::new(selection.object_position),
+                false,
+            )
+            .into(),
+        };
+        self.transmit(&Message::Data(DataMessageType::Request, &[obj]))
+            .await?;
+
+        match self.receive_timeout(TIMEOUT_SENDER_RESPONSE).await? {
+            Message::Control(ControlMessageType::Accept) => {}
+            Message::Control(ControlMessageType::Reject) => return Ok(RequestOutcome::Rejected),
+            Message::Control(ControlMessageType::Wait) => return Ok(RequestOutcome::Wait),
+            msg => {
+                error!(
+                    "Expected Reject or Wait message in renspone to Request, received {} instead",
+                    msg
+                );
+                self.transmit_soft_reset().await?;
+                return Err(Error::SoftReset);
+            }
+        };
+
+        match self.receive_timeout(TIMEOUT_PS_TRANSITION).await? {
+            Message::Control(ControlMessageType::PsRdy) => {
+                self.contract = Some(selection);
+                self.hard_reset_count = 0;
+                self.pps_request_at = selection
+                    .pps_voltage_mv
+                    .map(|_| Instant::now() + TIMEOUT_PPS_REQUEST);
+                self.policy.notify(self.contract_established(selection));
+                Ok(RequestOutcome::Accepted)
+            }
+            msg => {
+                error!("Expected PS_RDY message, received {} instead", msg);
+                self.transmit_soft_reset().await?;
+                Err(Error::SoftReset)
+            }
+        }
+    }
+
+    /// Receives the response to a request, a missing response causes a hard
+    /// reset.
+    ///
+    /// After nHardResetCount hard resets without an explicit contract the
+    /// sink stops without sending another one, so that a source that never
+    /// completes the transition is not reset forever.
+    async fn receive_timeout<'m>(&mut self, timeout: Duration) -> Result<Message<'m>, Error> {
+        match with_timeout(timeout, self.receive(&mut [])).await {
+            Ok(msg) => msg,
+            Err(_) if self.hard_reset_count > N_HARD_RESET_COUNT => {
+                error!("Receive timeout, giving up on the source");
+                Err(Error::HardReset)
+            }
+            Err(_) => {
+                error!("Receive timeout, sending hard reset");
+                self.transmit_hard_reset().await;
+                Err(Error::HardReset)
+            }
+        }
+    }
+
+    /// Reduces the load to the minimum operating current of `contract` and
+    /// waits for the source to signal PS_RDY.
+    async fn goto_min(
+        &mut self,
+        contract: Selection,
+        min_operating_current_ma: u32,
+    ) -> Result<(), Error> {
+        info!("Reducing load to {=u32}mA", min_operating_current_ma);
+        self.policy.goto_min(min_operating_current_ma);
+
+        match self.receive_timeout(TIMEOUT_PS_TRANSITION).await? {
+            Message::Control(ControlMessageType::PsRdy) => {
+                let selection = Selection {
+                    operating_current_ma: min_operating_current_ma,
+                    ..contract
+                };
+                self.contract = Some(selection);
+                self.policy.notify(self.contract_established(selection));
+                Ok(())
+            }
+            msg => {
+                error!("Expected PS_RDY message, received {} instead", msg);
+                self.transmit_soft_reset().await?;
+                Err(Error::SoftReset)
+            }
+        }
+    }
+
+    /// Returns the request for vSafe5V, the first capability, with at most
+    /// the currents of `selection`.
+    fn vsafe5v_selection(&self, selection: Selection) -> Selection {
+        let available_ma = match self.source_capabilities[0] {
+            PowerDataObject::FixedSupply(pdo) => pdo.max_current_ma(),
+            _ => 0,
+        };
+        Selection {
+            object_position: 1,
+            operating_current_ma: selection.operating_current_ma.min(available_ma),
+            max_operating_current_ma: selection.max_operating_current_ma.min(available_ma),
+            capability_mismatch: true,
+            pps_voltage_mv: None,
+            min_operating_current_ma: selection
+                .min_operating_current_ma
+                .map(|ma| ma.min(available_ma)),
+        }
+    }
+
+    fn contract_established(&self, selection: Selection) -> SinkEvent<'static> {
+        let pdo = self.source_capabilities[usize::from(selection.object_position) - 1];
+        let voltage_mv = selection.pps_voltage_mv.unwrap_or(match pdo {
+            PowerDataObject::FixedSupply(pdo) => pdo.voltage_mv(),
+            PowerDataObject::VariableSupply(pdo) => pdo.max_voltage_mv(),
+            PowerDataObject::Battery(pdo) => pdo.max_voltage_mv(),
+            _ => 0,
+        });
+        SinkEvent::ContractEstablished {
+            voltage_mv,
+            operating_current_ma: selection.operating_current_ma,
+            pdo,
+        }
+    }
+
+    async fn sink_capabilities(&mut self) -> Result<(), Error> {
+        let mut pdos = [0; 7];
+        let n = self.policy.sink_capabilities(&mut pdos);
+        self.transmit(&Message::Data(
+            DataMessageType::SinkCapabilities,
+            &pdos[..n],
+        ))
+        .await
+    }
+}
+
+/// Returns `true` when the currents and the voltage of `selection` fit into
+/// the fields of the request data object.
+fn fits_request(selection: &Selection) -> bool {
+    match selection.pps_voltage_mv {
+        // 50mA and 20mV units.
+        Some(voltage_mv) => {
+            selection.operating_current_ma / 50 <= u32::from(u7::MAX.value())
+                && voltage_mv / 20 <= u32::from(u12::MAX.value())
+        }
+        // 10mA units, rounded up.
+        None => [
+            selection.operating_current_ma,
+            selection.max_operating_current_ma,
+            selection.min_operating_current_ma.unwrap_or(0),
+        ]
+        .iter()
+        .all(|&ma| ma.div_ceil(10) <= u32::from(u10::MAX.value())),
+    }
+}
@@ -0,0 +1,361 @@
+use core::future::pending;
+
+use embassy_futures::select::{select, Either};
+use embassy_time::{with_timeout, Duration, Timer};
+
+use super::{Error, PolicyEngine, TIMEOUT_SENDER_RESPONSE, TIMEOUT_SRC_TRANSITION};
+use crate::phy::{CcPhy, PdPhy, VconnSwitch};
+use crate::protocol::source_capabilities::PowerDataObject;
+use crate::protocol::*;
+use crate::protocol_engine::{HardReset, Message};
+use crate::sink_policy::Selection;
+use crate::source_policy::{Evaluation, PowerSupply, SourceCommand, SourcePolicy};
+use crate::vdm_policy::{UnstructuredVdmHandler, VdmPolicy, VdmRequest};
+
+/// Time between Source_Capabilities messages while the sink does not respond.
+const TIMEOUT_SOURCE_CAPABILITY: Duration = Duration::from_millis(150);
+
+/// Number of unanswered Source_Capabilities messages after which the sink is
+/// considered to not support USB PD.
+const CAPS_COUNT: usize = 50;
+
+/// Time between a hard reset and switching off VBUS.
+const TIMEOUT_PS_HARD_RESET: Duration = Duration::from_millis(30);
+
+/// Time VBUS stays off after a hard reset.
+const TIMEOUT_SRC_RECOVER: Duration = Duration::from_millis(800);
+
+/// Atomic message sequences started by the source on behalf of the
+/// application.
+enum Ams {
+    StructuredVdm(VdmRequest),
+    PowerRoleSwap,
+}
+
+impl<
+        P: PdPhy,
+        S: SourcePolicy,
+        C: CcPhy,
+        V: VconnSwitch,
+        M: VdmPolicy,
+        U: UnstructuredVdmHandler,
+    > PolicyEngine<P, S, C, V, M, U>
+{
+    /// Runs the source state machine.
+    ///
+    /// VBUS must be at vSafe5V when called. Returns after a hard reset, when
+    /// `supply` went through the hard reset sequence and is back at vSafe5V.
+    /// VCONN is switched on when the port is able to supply it.
+    pub async fn run_source(&mut self, supply: &mut impl PowerSupply) -> Result<(), HardReset> {
+        self.protocol_engine.set_power_role(PortPowerRole::Source);
+        self.protocol_engine
+            .set_data_role(PortDataRole::DownstreamFacingPort);
+        self.dual_role = false;
+        self.set_vconn(true).await;
+        self.source_loop(supply).await
+    }
+
+    /// Runs the source states until a hard reset or an accepted power role
+    /// swap, in which case `Ok(())` is returned.
+    ///
+    /// As VCONN source the capabilities of an e-marked cable are read first
+    /// and the advertised capabilities are limited to them.
+    pub(super) async fn source_loop(
+        &mut self,
+        supply: &mut impl PowerSupply,
+    ) -> Result<(), HardReset> {
+        self.num_source_capabilities = self
+            .policy
+            .source_capabilities(&mut self.source_capabilities);
+        self.contract = None;
+        match self.discover_cable().await {
+            Ok(cable) => self.limit_source_capabilities(cable),
+            Err(HardReset) => return self.source_hard_reset(supply).await,
+        }
+
+        let mut send_capabilities = true;
+        loop {
+            let had_contract = self.contract.is_some();
+            let result = if send_capabilities {
+                self.send_source_capabilities(supply).await
+            } else {
+                let mut obj_buf = [0; 7];
+                match self.source_receive_ready(&mut obj_buf).await {
+                    Ok(Either::First(msg)) => self.handle_source_message(msg, supply).await,
+                    Ok(Either::Second(Ams::StructuredVdm(request))) => {
+                        self.application_vdm(request).await.map(|_| false)
+                    }
+                    Ok(Either::Second(Ams::PowerRoleSwap)) => {
+                        self.power_role_swap().await.map(|_| false)
+                    }
+                    Err(e) => Err(e),
+                }
+            };
+            let result = match result {
+                Ok(s) if !had_contract && self.contract.is_some() => {
+                    self.source_initial_swaps().await.map(|_| s)
+                }
+                result => result,
+            };
+            match result {
+                Ok(s) => send_capabilities = s,
+                Err(Error::PowerRoleSwap) => return Ok(()),
+                Err(Error::HardReset) => return self.source_hard_reset(supply).await,
+                // The explicit contract stays in place, only the capabilities
+                // are exchanged again.
+                Err(Error::SoftReset) => send_capabilities = true,
+            }
+        }
+    }
+
+    /// Receives the next message or, in an explicit contract, the next
+    /// command or structured VDM request of the application.
+    async fn source_receive_ready<'m>(
+        &mut self,
+        obj_buf: &'m mut [u32],
+    ) -> Result<Either<Message<'m>, Ams>, Error> {
+        let ready = self.contract.is_some();
+        let (policy, vdm) = (&mut self.policy, &mut self.vdm);
+        let application = async move {
+            if !ready {
+                pending::<()>().await;
+            }
+            match select(policy.command(), vdm.request()).await {
+                Either::First(SourceCommand::PowerRoleSwap) => Ams::PowerRoleSwap,
+                Either::Second(request) => Ams::StructuredVdm(request),
+            }
+        };
+        // The application only starts a sequence while no message is being
+        // received.
+        match select(self.protocol_engine.wait_for_message(), application).await {
+            Either::First(rx) => {
+                rx?;
+                self.receive(obj_buf).await.map(Either::First)
+            }
+            Either::Second(ams) => {
+                if let Ams::StructuredVdm(_) = ams {
+                    self.vdm_request_pending = true;
+                }
+                Ok(Either::Second(ams))
+            }
+        }
+    }
+
+    /// Cycles VBUS and VCONN after a hard reset.
+    async fn source_hard_reset(&mut self, supply: &mut impl PowerSupply) -> Result<(), HardReset> {
+        warn!("Hard reset, cycling VBUS");
+        self.exit_displayport();
+        Timer::after(TIMEOUT_PS_HARD_RESET).await;
+        supply.disable().await;
+        self.set_vconn(false).await;
+        Timer::after(TIMEOUT_SRC_RECOVER).await;
+        supply.enable().await;
+        self.set_vconn(true).await;
+        self.contract = None;
+        Err(HardReset)
+    }
+
+    /// Handles a message in the ready state and returns `true` when the
+    /// source capabilities must be sent again.
+    async fn handle_source_message(
+        &mut self,
+        msg: Message<'_>,
+        supply: &mut impl PowerSupply,
+    ) -> Result<bool, Error> {
+        match msg {
+            Message::Data(DataMessageType::Request, &[request]) => {
+                self.evaluate_request(request, supply).await?;
+            }
+            Message::Control(ControlMessageType::GetSourceCap) => return Ok(true),
+            Message::Control(ControlMessageType::PrSwap) if self.dual_role => {
+                if self.policy.accept_swap_to_sink() {
+                    self.transmit(&Message::Control(ControlMessageType::Accept))
+                        .await?;
+                    return Err(Error::PowerRoleSwap);
+                }
+                self.transmit(&Message::Control(ControlMessageType::Reject))
+                    .await?;
+            }
+            Message::Control(ControlMessageType::VconnSwap) => self.respond_vconn_swap().await?,
+            Message::Control(ControlMessageType::DrSwap) => {
+                let accept = self.policy.accept_data_role_swap();
+                if let Some(data_role) = self.respond_data_role_swap(accept).await? {
+                    self.policy.data_role_swapped(data_role);
+                }
+            }
+            Message::Control(ControlMessageType::Ping) => info!("Ignoring {}", msg),
+            Message::Data(DataMessageType::VendorDefined, objects) => {
+                self.respond_vdm(objects).await?
+            }
+            Message::Data(DataMessageType::Bist, objects) => self.respond_bist(objects).await?,
+            msg => self.not_supported(msg).await?,
+        }
+        Ok(false)
+    }
+
+    /// Swaps to the preferred data role, takes over VCONN and enters the
+    /// DisplayPort mode as DFP once the first explicit contract is in place.
+    async fn source_initial_swaps(&mut self) -> Result<(), Error> {
+        if self.source_prefers_data_role_swap() {
+            self.source_data_role_swap().await?;
+        }
+        if self.wants_vconn_swap() {
+            self.vconn_swap().await?;
+        }
+        if self.wants_displayport() {
+            self.enter_displayport().await?;
+        }
+        Ok(())
+    }
+
+    fn source_prefers_data_role_swap(&self) -> bool {
+        self.policy
+            .preferred_data_role()
+            .is_some_and(|data_role| data_role != self.protocol_engine.data_role())
+    }
+
+    async fn source_data_role_swap(&mut self) -> Result<(), Error> {
+        if let Some(data_role) = self.data_role_swap().await? {
+            self.policy.data_role_swapped(data_role);
+        }
+        Ok(())
+    }
+
+    async fn send_source_capabilities(
+        &mut self,
+        supply: &mut impl PowerSupply,
+    ) -> Result<bool, Error> {
+        let mut pdos = [0; 7];
+        let n = self.num_source_capabilities;
+        for (obj, &pdo) in pdos.iter_mut().zip(&self.source_capabilities[..n]) {
+            *obj = pdo.into();
+        }
+        let msg = Message::Data(DataMessageType::SourceCapabilites, &pdos[..n]);
+
+        if self.contract.is_some() {
+            self.transmit(&msg).await?;
+        } else {
+            // Without a contract the sink might not be ready or not support
+            // USB PD at all, keep trying for a while.
+            let mut caps_count = 0;
+            while !self.protocol_engine.transmit(&msg).await? {
+                caps_count += 1;
+                if caps_count > CAPS_COUNT {
+                    warn!("Sink does not respond to Source_Capabilities, giving up");
+                    return self.source_disabled().await;
+                }
+                Timer::after(TIMEOUT_SOURCE_CAPABILITY).await;
+            }
+        }
+
+        let mut obj_buf = [0; 1];
+        let request = match with_timeout(TIMEOUT_SENDER_RESPONSE, self.receive(&mut obj_buf)).await
+        {
+            Ok(Ok(Message::Data(DataMessageType::Request, &[request]))) => request,
+            Ok(Ok(msg)) => {
+                error!("Expected Request message, received {} instead", msg);
+                self.transmit_soft_reset().await?;
+                return Err(Error::SoftReset);
+            }
+            Ok(Err(e)) => return Err(e),
+            Err(_) => {
+                error!("Request timeout");
+                self.transmit_hard_reset().await;
+                return Err(Error::HardReset);
+            }
+        };
+        self.evaluate_request(request, supply).await?;
+        Ok(false)
+    }
+
+    /// Evaluates a request of the sink and transitions the power supply when
+    /// it was accepted.
+    async fn evaluate_request(
+        &mut self,
+        request: u32,
+        supply: &mut impl PowerSupply,
+    ) -> Result<(), Error> {
+        let object_position = Request::from(request).object_position().value();
+        let pdo = self.source_capabilities[..self.num_source_capabilities]
+            .get(usize::from(object_position).wrapping_sub(1))
+            .copied();
+        let (evaluation, pdo, selection) = match pdo {
+            Some(pdo) => {
+                let selection = decode_request(&pdo, request);
+                (self.policy.evaluate(&pdo, &selection), pdo, selection)
+            }
+            None => {
+                warn!("Request for invalid object position {=u8}", object_position);
+                self.transmit(&Message::Control(ControlMessageType::Reject))
+                    .await?;
+                return Ok(());
+            }
+        };
+
+        info!("Request {}: {}", selection, evaluation);
+        match evaluation {
+            Evaluation::Accept => {
+                self.transmit(&Message::Control(ControlMessageType::Accept))
+                    .await?;
+                Timer::after(TIMEOUT_SRC_TRANSITION).await;
+                supply.transition(&pdo, &selection).await;
+                self.transmit(&Message::Control(ControlMessageType::PsRdy))
+                    .await?;
+                self.contract = Some(selection);
+            }
+            Evaluation::Reject => {
+                self.transmit(&Message::Control(ControlMessageType::Reject))
+                    .await?
+            }
+            Evaluation::Wait => {
+                self.transmit(&Message::Control(ControlMessageType::Wait))
+                    .await?
+            }
+        }
+        Ok(())
+    }
+
+    /// Stops USB PD communication until a hard reset is received.
+    async fn source_disabled(&mut self) -> Result<bool, Error> {
+        loop {
+            self.protocol_engine.receive(&mut []).await?;
+        }
+    }
+}
+
+/// Decodes a request object for the capability `pdo`.
+fn decode_request(pdo: &PowerDataObject, request: u32) -> Selection {
+    match pdo {
+        PowerDataObject::Pps(_) => {
+            let request = PpsRequest::from(request);
+            let current_ma = u32::from(request.operating_current().value()) * 50;
+            Selection {
+                object_position: request.object_position().value(),
+                operating_current_ma: current_ma,
+                max_operating_current_ma: current_ma,
+                capability_mismatch: request.capability_mismatch(),
+                pps_voltage_mv: Some(u32::from(request.output_voltage().value()) * 20),
+                min_operating_current_ma: None,
+            }
+        }
+        _ => {
+            let request = Request::from(request);
+            let operating_current_ma = u32::from(request.operating_curent().value()) * 10;
+            // With GiveBack the second current is the minimum instead of the maximum.
+            let other_current_ma = u32::from(request.min_operating_current().value()) * 10;
+            let give_back = request.give_back_flag();
+            Selection {
+                object_position: request.object_position().value(),
+                operating_current_ma,
+                max_operating_current_ma: if give_back {
+                    operating_current_ma
+                } else {
+                    other_current_ma
+                },
+                capability_mismatch: request.capability_mismatch(),
+                pps_voltage_mv: None,
+                min_operating_current_ma: give_back.then_some(other_current_ma),
+            }
+        }
+    }
+}
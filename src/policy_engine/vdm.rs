@@ -0,0 +1,234 @@
+use embassy_time::{with_timeout, Duration};
+
+use super::{Error, PolicyEngine};
+use crate::phy::{CcPhy, PdPhy, Sop, VconnSwitch};
+use crate::protocol::displayport::DISPLAYPORT_SID;
+use crate::protocol::*;
+use crate::protocol_engine::Message;
+use crate::vdm_policy::{UnstructuredVdmHandler, VdmError, VdmPolicy, VdmRequest, VdmResponse};
+
+/// Time to wait for the response to a structured VDM request.
+const TIMEOUT_VDM_SENDER_RESPONSE: Duration = Duration::from_millis(30);
+
+/// Time to wait for the response to Enter Mode and Exit Mode.
+const TIMEOUT_VDM_WAIT_MODE_ENTRY: Duration = Duration::from_millis(50);
+
+/// Number of SVIDs fitting into a Discover SVIDs response, two per VDO.
+const MAX_SVIDS: usize = 12;
+
+impl<P: PdPhy, S, C: CcPhy, V: VconnSwitch, M: VdmPolicy, U: UnstructuredVdmHandler>
+    PolicyEngine<P, S, C, V, M, U>
+{
+    /// Sends a structured VDM request to `sop` and waits for the response.
+    ///
+    /// Returns the header and the VDOs of the response, which may also be a
+    /// NAK or BUSY, or `None` when the recipient did not respond in time.
+    /// Messages to the cable plugs never cause a soft reset.
+    pub(super) async fn structured_vdm<'m>(
+        &mut self,
+        sop: Sop,
+        request: StructuredVdmHeader,
+        vdos: &[u32],
+        obj_buf: &'m mut [u32; 7],
+    ) -> Result<Option<(StructuredVdmHeader, &'m [u32])>, Error> {
+        let mut objects = [0; 7];
+        objects[0] = request.into();
+        let n = vdos.len().min(6);
+        objects[1..1 + n].copy_from_slice(&vdos[..n]);
+        let msg = Message::Data(DataMessageType::VendorDefined, &objects[..1 + n]);
+        debug!("Sending {} to {}", request, sop);
+        if sop == Sop::Sop {
+            self.transmit(&msg).await?;
+        } else if !self.protocol_engine.transmit_sop(sop, &msg).await? {
+            info!("{} did not acknowledge VDM", sop);
+            return Ok(None);
+        }
+
+        let timeout = match request.command() {
+            VdmCommand::EnterMode | VdmCommand::ExitMode => TIMEOUT_VDM_WAIT_MODE_ENTRY,
+            _ => TIMEOUT_VDM_SENDER_RESPONSE,
+        };
+        let rx = with_timeout(timeout, async {
+            match sop {
+                Sop::Sop => self.receive(obj_buf).await,
+                _ => Ok(self.protocol_engine.receive_sop(sop, obj_buf).await?),
+            }
+        })
+        .await;
+        match rx {
+            Ok(Ok(Message::Data(DataMessageType::VendorDefined, &[header, ref vdos @ ..])))
+                if is_response(request, StructuredVdmHeader::from(header)) =>
+            {
+                Ok(Some((StructuredVdmHeader::from(header), vdos)))
+            }
+            Ok(Ok(msg)) if sop == Sop::Sop => {
+                error!("Expected VDM response, received {} instead", msg);
+                self.transmit_soft_reset().await?;
+                Err(Error::SoftReset)
+            }
+            Ok(Ok(msg)) => {
+                warn!("Expected VDM response, received {} instead", msg);
+                Ok(None)
+            }
+            Ok(Err(e)) => Err(e),
+            Err(_) => {
+                warn!("VDM response timeout");
+                Ok(None)
+            }
+        }
+    }
+
+    /// Sends a structured VDM requested by the VDM policy and passes the
+    /// response back to it.
+    pub(super) async fn application_vdm(&mut self, request: VdmRequest) -> Result<(), Error> {
+        let mut obj_buf = [0; 7];
+        let result = self
+            .structured_vdm(request.sop, request.header, request.vdos(), &mut obj_buf)
+            .await;
+        let response = match result {
+            Ok(Some((header, vdos))) => match VdmError::from_command_type(header.command_type()) {
+                None => Ok(VdmResponse::new(header, vdos)),
+                Some(e) => Err(e),
+            },
+            _ => Err(VdmError::Timeout),
+        };
+        self.vdm_request_pending = false;
+        self.vdm.response(response);
+        result.map(|_| ())
+    }
+
+    /// Answers a structured VDM request of the VDM policy that was not sent
+    /// because the port was reset or detached meanwhile.
+    pub(super) fn cancel_vdm_request(&mut self) {
+        if self.vdm_request_pending {
+            warn!("Structured VDM request not sent");
+            self.vdm_request_pending = false;
+            self.vdm.response(Err(VdmError::Timeout));
+        }
+    }
+
+    /// Answers a Vendor Defined Message of the port partner.
+    ///
+    /// The discovery commands are answered by the VDM policy, every other
+    /// structured request is answered with NAK. Unstructured VDMs go to the
+    /// handler registered for their vendor ID.
+    pub(super) async fn respond_vdm(&mut self, vdos: &[u32]) -> Result<(), Error> {
+        let Some(&header) = vdos.first() else {
+            warn!("Ignoring VDM without header");
+            return Ok(());
+        };
+        let header = StructuredVdmHeader::from(header);
+        if !header.structured() {
+            return self.respond_unstructured_vdm(vdos).await;
+        }
+        if header.command() == VdmCommand::Attention && header.svid() == DISPLAYPORT_SID {
+            self.displayport_attention(header, &vdos[1..]);
+            return Ok(());
+        }
+        // Attention is never answered.
+        if header.command_type() != VdmCommandType::Request
+            || header.command() == VdmCommand::Attention
+        {
+            info!("Ignoring {}", header);
+            return Ok(());
+        }
+
+        let mut response = [0; 7];
+        let n = match header.command() {
+            VdmCommand::DiscoverIdentity if header.svid() == PD_SID => {
+                self.vdm.discover_identity(&mut response[1..])
+            }
+            VdmCommand::DiscoverSvids if header.svid() == PD_SID => {
+                let mut svids = [0; MAX_SVIDS];
+                // Keep room for the terminating zero SVID.
+                let n = self
+                    .vdm
+                    .discover_svids(&mut svids[..MAX_SVIDS - 1])
+                    .min(MAX_SVIDS - 1);
+                if n > 0 {
+                    pack_svids(&svids[..n + 1], &mut response[1..])
+                } else {
+                    0
+                }
+            }
+            VdmCommand::DiscoverModes => self.vdm.discover_modes(header.svid(), &mut response[1..]),
+            _ => 0,
+        };
+        // Ignore counts of the policy beyond the written VDOs.
+        let n = n.min(response.len() - 1);
+
+        let mut response_header = header;
+        response_header.set_command_type(if n > 0 {
+            VdmCommandType::Ack
+        } else {
+            VdmCommandType::Nak
+        });
+        info!(
+            "Answering {} with {}",
+            header.command(),
+            response_header.command_type()
+        );
+        response[0] = response_header.into();
+        self.transmit(&Message::Data(
+            DataMessageType::VendorDefined,
+            &response[..1 + n],
+        ))
+        .await
+    }
+
+    /// Passes an unstructured VDM to the handler of its vendor and sends the
+    /// reply of the handler, if any.
+    async fn respond_unstructured_vdm(&mut self, vdos: &[u32]) -> Result<(), Error> {
+        let header = UnstructuredVdmHeader::from(vdos[0]);
+        let mut reply = [0; 7];
+        let n = match &mut self.unstructured_vdm {
+            Some((vendor_id, handler)) if *vendor_id == header.vendor_id() => handler
+                .handle(header, &vdos[1..], &mut reply)
+                .min(reply.len()),
+            _ => {
+                return match self.protocol_engine.specification_revision() {
+                    SpecificationRevision::Revision3_0 => {
+                        self.not_supported(Message::Data(DataMessageType::VendorDefined, vdos))
+                            .await
+                    }
+                    _ => {
+                        info!(
+                            "Ignoring unstructured VDM of vendor {:x}",
+                            header.vendor_id()
+                        );
+                        Ok(())
+                    }
+                };
+            }
+        };
+        if n == 0 {
+            return Ok(());
+        }
+        debug!(
+            "Replying to unstructured VDM of vendor {:x}",
+            header.vendor_id()
+        );
+        self.transmit(&Message::Data(DataMessageType::VendorDefined, &reply[..n]))
+            .await
+    }
+}
+
+/// Returns `true` when `response` answers the structured VDM `request`.
+fn is_response(request: StructuredVdmHeader, response: StructuredVdmHeader) -> bool {
+    response.structured()
+        && response.svid() == request.svid()
+        && response.command() == request.command()
+        && response.command_type() != VdmCommandType::Request
+}
+
+/// Packs two SVIDs per VDO, the first one in the upper half, and returns the
+/// number of VDOs.
+fn pack_svids(svids: &[u16], vdos: &mut [u32]) -> usize {
+    let mut n = 0;
+    for (vdo, pair) in vdos.iter_mut().zip(svids.chunks(2)) {
+        let lower = pair.get(1).copied().unwrap_or(0);
+        *vdo = (u32::from(pair[0]) << 16) | u32::from(lower);
+        n += 1;
+    }
+    n
+}
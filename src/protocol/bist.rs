@@ -0,0 +1,27 @@
+//! Built-In Self-Test messages used for compliance testing.
+
+use bilge::prelude::*;
+
+/// Test requested with a BIST message.
+#[bitsize(4)]
+#[derive(FromBits, Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum BistMode {
+    /// Transmit BIST Carrier Mode 2 for tBISTContMode.
+    CarrierMode = 0b0101,
+    /// Ignore all messages except Hard Reset.
+    TestData = 0b1000,
+    SharedTestModeEntry = 0b1001,
+    SharedTestModeExit = 0b1010,
+    #[fallback]
+    Reserved,
+}
+
+/// BIST Data Object, the first object of a BIST message.
+#[bitsize(32)]
+#[derive(FromBits, DebugBits, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct BistDataObject {
+    _reserved: u28,
+    pub mode: BistMode,
+}
@@ -0,0 +1,138 @@
+//! Data objects of the DisplayPort alternate mode.
+
+use bilge::prelude::*;
+
+/// Standard ID of the DisplayPort alternate mode.
+pub const DISPLAYPORT_SID: u16 = 0xFF01;
+
+/// Pin assignments of the Type-C connector, one bit each.
+#[bitsize(8)]
+#[derive(FromBits, DebugBits, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PinAssignments {
+    pub a: bool,
+    pub b: bool,
+    /// Four DisplayPort lanes.
+    pub c: bool,
+    /// Two DisplayPort lanes and USB 3.x.
+    pub d: bool,
+    /// Four DisplayPort lanes to a DisplayPort receptacle or plug.
+    pub e: bool,
+    pub f: bool,
+    _reserved: u2,
+}
+
+impl PinAssignments {
+    /// Returns the pin assignments contained in both `self` and `other`.
+    pub fn intersection(self, other: Self) -> Self {
+        Self::from(u8::from(self) & u8::from(other))
+    }
+
+    /// Picks one of the pin assignments C, D and E, which are the only ones
+    /// still defined by the DisplayPort alternate mode.
+    ///
+    /// D keeps USB 3.x available and is taken first when `multi_function` is
+    /// preferred, otherwise the four lane assignments come first.
+    pub fn select(self, multi_function: bool) -> Option<PinAssignment> {
+        let order = if multi_function {
+            [PinAssignment::D, PinAssignment::C, PinAssignment::E]
+        } else {
+            [PinAssignment::C, PinAssignment::E, PinAssignment::D]
+        };
+        order.into_iter().find(|&pin| match pin {
+            PinAssignment::C => self.c(),
+            PinAssignment::D => self.d(),
+            PinAssignment::E => self.e(),
+        })
+    }
+}
+
+/// Pin assignment configured with DP Configure.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PinAssignment {
+    C,
+    D,
+    E,
+}
+
+impl From<PinAssignment> for PinAssignments {
+    fn from(pin: PinAssignment) -> Self {
+        Self::from(match pin {
+            PinAssignment::C => 1 << 2,
+            PinAssignment::D => 1 << 3,
+            PinAssignment::E => 1 << 4,
+        })
+    }
+}
+
+/// DisplayPort Capabilities, the mode VDO of the DisplayPort SVID.
+#[bitsize(32)]
+#[derive(FromBits, DebugBits, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DisplayPortCapabilities {
+    pub ufp_d_capable: bool,
+    pub dfp_d_capable: bool,
+    /// Bit 0: DP v1.3 signaling, bit 1: USB Gen 2 signaling.
+    pub signaling: u4,
+    /// The port is a receptacle, otherwise a captive plug.
+    pub receptacle: bool,
+    pub usb2_not_used: bool,
+    pub dfp_d_pin_assignments: PinAssignments,
+    pub ufp_d_pin_assignments: PinAssignments,
+    _reserved: u8,
+}
+
+impl DisplayPortCapabilities {
+    /// Pin assignments usable with the port as UFP_D.
+    ///
+    /// Captive plugs report them in the DFP_D field for historical reasons.
+    pub fn ufp_d_pins(&self) -> PinAssignments {
+        if self.receptacle() {
+            self.ufp_d_pin_assignments()
+        } else {
+            self.dfp_d_pin_assignments()
+        }
+    }
+}
+
+/// DisplayPort Status, exchanged with DP Status Update and Attention.
+#[bitsize(32)]
+#[derive(FromBits, DebugBits, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DisplayPortStatus {
+    pub dfp_d_connected: bool,
+    pub ufp_d_connected: bool,
+    pub power_low: bool,
+    pub enabled: bool,
+    pub multi_function_preferred: bool,
+    pub usb_configuration_request: bool,
+    pub exit_request: bool,
+    pub hpd_state: bool,
+    pub irq_hpd: bool,
+    _reserved: u23,
+}
+
+/// Configuration selected with DP Configure.
+#[bitsize(2)]
+#[derive(FromBits, Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DisplayPortConfiguration {
+    Usb,
+    DfpD,
+    UfpD,
+    Reserved,
+}
+
+/// DisplayPort Configurations, the VDO of DP Configure.
+#[bitsize(32)]
+#[derive(FromBits, DebugBits, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DisplayPortConfigure {
+    /// Role of the UFP_U.
+    pub configuration: DisplayPortConfiguration,
+    pub signaling: u4,
+    _reserved1: u2,
+    pub pin_assignment: PinAssignments,
+    _reserved2: u16,
+}
@@ -1,8 +1,8 @@
 use bilge::prelude::*;
-use defmt::Format;
 
-#[bitsize(4)]
-#[derive(FromBits, Debug, Format, Clone, Copy, PartialEq)]
+#[bitsize(5)]
+#[derive(FromBits, Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum ControlMessageType {
     GoodCRC = 0x1,
     GotoMin = 0x2,
@@ -17,54 +17,136 @@ pub enum ControlMessageType {
     VconnSwap = 0xB,
     Wait = 0xC,
     SoftReset = 0xD,
+    // PD 3.x
+    NotSupported = 0x10,
+    GetSourceCapExtended = 0x11,
+    GetStatus = 0x12,
+    FrSwap = 0x13,
+    GetPpsStatus = 0x14,
+    GetCountryCodes = 0x15,
+    GetSinkCapExtended = 0x16,
+    GetSourceInfo = 0x17,
+    GetRevision = 0x18,
     #[fallback]
     Reserved,
 }
 
-#[bitsize(4)]
-#[derive(FromBits, Debug, Format, Clone, Copy, PartialEq)]
+#[bitsize(5)]
+#[derive(FromBits, Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum DataMessageType {
     SourceCapabilites = 0x1,
     Request = 0x2,
     Bist = 0x3,
     SinkCapabilities = 0x4,
+    // PD 3.x
+    BatteryStatus = 0x5,
+    Alert = 0x6,
+    GetCountryInfo = 0x7,
+    EnterUsb = 0x8,
+    EprRequest = 0x9,
+    EprMode = 0xA,
+    SourceInfo = 0xB,
+    Revision = 0xC,
     VendorDefined = 0xF,
     #[fallback]
     Reserved,
 }
 
+#[bitsize(5)]
+#[derive(FromBits, Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ExtendedMessageType {
+    SourceCapabilitiesExtended = 0x1,
+    Status = 0x2,
+    GetBatteryCap = 0x3,
+    GetBatteryStatus = 0x4,
+    BatteryCapabilities = 0x5,
+    GetManufacturerInfo = 0x6,
+    ManufacturerInfo = 0x7,
+    SecurityRequest = 0x8,
+    SecurityResponse = 0x9,
+    FirmwareUpdateRequest = 0xA,
+    FirmwareUpdateResponse = 0xB,
+    PpsStatus = 0xC,
+    CountryInfo = 0xD,
+    CountryCodes = 0xE,
+    SinkCapabilitiesExtended = 0xF,
+    ExtendedControl = 0x10,
+    EprSourceCapabilities = 0x11,
+    EprSinkCapabilities = 0x12,
+    VendorDefinedExtended = 0x1E,
+    #[fallback]
+    Reserved,
+}
+
 #[bitsize(1)]
-#[derive(FromBits, Debug, Format, Clone, Copy, PartialEq)]
+#[derive(FromBits, Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum PortDataRole {
     UpstreamFacingPort,
     DownstreamFacingPort,
 }
 
 #[bitsize(2)]
-#[derive(FromBits, Debug, Format, Clone, Copy, PartialEq)]
+#[derive(FromBits, Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum SpecificationRevision {
     Revision1_0,
     Revision2_0,
-    #[fallback]
+    /// PD 3.0 and later
+    Revision3_0,
     Reserved,
 }
 
 #[bitsize(1)]
-#[derive(FromBits, Debug, Format, Clone, Copy, PartialEq)]
+#[derive(FromBits, Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum PortPowerRole {
     Sink,
     Source,
 }
 
 #[bitsize(16)]
-#[derive(FromBits, DebugBits, Format, Clone, Copy)]
+#[derive(FromBits, DebugBits, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct Header {
-    pub message_type: u4,
-    _reserved1: bool,
-    port_data_role: PortDataRole,
-    specification_revision: SpecificationRevision,
-    port_power_role: PortPowerRole,
+    pub message_type: u5,
+    pub port_data_role: PortDataRole,
+    pub specification_revision: SpecificationRevision,
+    pub port_power_role: PortPowerRole,
     pub message_id: u3,
     pub number_of_data_objects: u3,
-    _reserved2: bool,
+    pub extended: bool,
+}
+
+/// Header following the message header of extended messages.
+#[bitsize(16)]
+#[derive(FromBits, DebugBits, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ExtendedHeader {
+    /// Size of the complete payload in bytes.
+    pub data_size: u9,
+    _reserved: bool,
+    pub request_chunk: bool,
+    pub chunk_number: u4,
+    pub chunked: bool,
+}
+
+impl Header {
+    /// Returns `true` for SOP' and SOP'' messages sent by a cable plug.
+    ///
+    /// The Cable Plug bit takes the place of the port power role in these messages.
+    pub fn cable_plug(&self) -> bool {
+        self.port_power_role() == PortPowerRole::Source
+    }
+
+    /// Sets the Cable Plug bit of a SOP' or SOP'' message.
+    pub fn set_cable_plug(&mut self, cable_plug: bool) {
+        self.set_port_power_role(if cable_plug {
+            PortPowerRole::Source
+        } else {
+            PortPowerRole::Sink
+        });
+    }
 }
@@ -1,6 +1,12 @@
+mod bist;
+pub mod displayport;
 mod header;
 mod request;
 pub mod sink_capabilities;
+pub mod source_capabilities;
+mod vdm;
 
+pub use bist::*;
 pub use header::*;
 pub use request::*;
+pub use vdm::*;
@@ -1,8 +1,8 @@
 use bilge::prelude::*;
-use defmt::Format;
 
 #[bitsize(32)]
-#[derive(FromBits, DebugBits, Format, Clone, Copy)]
+#[derive(FromBits, DebugBits, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct Request {
     pub min_operating_current: u10, // 10mA units
     pub operating_curent: u10,      // 10mA units
@@ -14,3 +14,22 @@ pub struct Request {
     pub object_position: u3,
     _reserved2: bool,
 }
+
+/// Request Data Object for a Programmable Power Supply.
+#[bitsize(32)]
+#[derive(FromBits, DebugBits, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PpsRequest {
+    pub operating_current: u7, // 50mA units
+    _reserved1: u2,
+    pub output_voltage: u12, // 20mV units
+    _reserved2: bool,
+    pub epr_mode_capable: bool,
+    pub unchunked_extended_messages_supported: bool,
+    pub no_usb_suspend: bool,
+    pub usb_communications_capable: bool,
+    pub capability_mismatch: bool,
+    _reserved3: bool,
+    pub object_position: u3,
+    _reserved4: bool,
+}
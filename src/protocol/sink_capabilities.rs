@@ -1,8 +1,8 @@
 use bilge::prelude::*;
-use defmt::Format;
 
 #[bitsize(32)]
-#[derive(FromBits, DebugBits, Format, Clone, Copy)]
+#[derive(FromBits, DebugBits, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct FixedSupply {
     pub operating_current: u10, // 10mA units
     pub voltage: u10,           // 150mV units
@@ -14,3 +14,33 @@ pub struct FixedSupply {
     pub dual_power_role: bool,
     fixed_supply: u2,
 }
+
+impl FixedSupply {
+    /// Fixed supply PDO with all flags cleared.
+    pub fn from_mv_ma(voltage_mv: u32, operating_current_ma: u32) -> Self {
+        let mut pdo = Self::from(0);
+        pdo.set_voltage(u10::new((voltage_mv / 50) as u16));
+        pdo.set_operating_current(u10::new((operating_current_ma / 10) as u16));
+        pdo
+    }
+}
+
+#[bitsize(32)]
+#[derive(FromBits, DebugBits, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct VariableSupply {
+    pub operating_current: u10, // 10mA units
+    pub min_voltage: u10,       // 50mV units
+    pub max_voltage: u10,       // 50mV units
+    variable_supply: u2,
+}
+
+impl VariableSupply {
+    pub fn from_mv_ma(min_voltage_mv: u32, max_voltage_mv: u32, operating_current_ma: u32) -> Self {
+        let mut pdo = Self::from(0b10 << 30);
+        pdo.set_min_voltage(u10::new((min_voltage_mv / 50) as u16));
+        pdo.set_max_voltage(u10::new((max_voltage_mv / 50) as u16));
+        pdo.set_operating_current(u10::new((operating_current_ma / 10) as u16));
+        pdo
+    }
+}
@@ -0,0 +1,272 @@
+use bilge::prelude::*;
+
+/// Power Data Object as advertised in a Source_Capabilities message.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PowerDataObject {
+    FixedSupply(FixedSupply),
+    Battery(Battery),
+    VariableSupply(VariableSupply),
+    /// SPR Programmable Power Supply
+    Pps(SprPps),
+    /// SPR Adjustable Voltage Supply
+    SprAvs(SprAvs),
+    /// EPR Adjustable Voltage Supply
+    EprAvs(EprAvs),
+    Unknown(u32),
+}
+
+impl From<u32> for PowerDataObject {
+    fn from(raw: u32) -> Self {
+        match (raw >> 30, (raw >> 28) & 0b11) {
+            (0b00, _) => Self::FixedSupply(raw.into()),
+            (0b01, _) => Self::Battery(raw.into()),
+            (0b10, _) => Self::VariableSupply(raw.into()),
+            (0b11, 0b00) => Self::Pps(raw.into()),
+            (0b11, 0b01) => Self::EprAvs(raw.into()),
+            (0b11, 0b10) => Self::SprAvs(raw.into()),
+            _ => Self::Unknown(raw),
+        }
+    }
+}
+
+impl From<PowerDataObject> for u32 {
+    fn from(pdo: PowerDataObject) -> Self {
+        match pdo {
+            PowerDataObject::FixedSupply(pdo) => pdo.into(),
+            PowerDataObject::Battery(pdo) => pdo.into(),
+            PowerDataObject::VariableSupply(pdo) => pdo.into(),
+            PowerDataObject::Pps(pdo) => pdo.into(),
+            PowerDataObject::SprAvs(pdo) => pdo.into(),
+            PowerDataObject::EprAvs(pdo) => pdo.into(),
+            PowerDataObject::Unknown(raw) => raw,
+        }
+    }
+}
+
+/// Returns `value` in `unit`s, at most `max`.
+fn clamped(value: u32, unit: u32, max: u16) -> u16 {
+    (value / unit).min(u32::from(max)) as u16
+}
+
+/// Fixed supply PDO.
+///
+/// The flags are only valid in the first PDO (vSafe5V) of a capability list
+/// and must be zero in all other Fixed Supply PDOs.
+#[bitsize(32)]
+#[derive(FromBits, DebugBits, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FixedSupply {
+    pub max_current: u10, // 10mA units
+    pub voltage: u10,     // 50mV units
+    pub peak_current: u2,
+    _reserved: bool,
+    pub epr_mode_capable: bool,
+    pub unchunked_extended_messages_supported: bool,
+    pub dual_role_data: bool,
+    pub usb_communications_capable: bool,
+    pub unconstrained_power: bool,
+    pub usb_suspend_supported: bool,
+    pub dual_role_power: bool,
+    supply_type: u2,
+}
+
+impl FixedSupply {
+    /// Fixed supply PDO with all flags cleared. Voltage and current beyond
+    /// the range of the PDO are clamped.
+    pub fn from_mv_ma(voltage_mv: u32, max_current_ma: u32) -> Self {
+        let mut pdo = Self::from(0);
+        pdo.set_voltage(u10::new(clamped(voltage_mv, 50, u10::MAX.value())));
+        pdo.set_max_current(u10::new(clamped(max_current_ma, 10, u10::MAX.value())));
+        pdo
+    }
+
+    pub fn voltage_mv(&self) -> u32 {
+        u32::from(self.voltage().value()) * 50
+    }
+
+    pub fn max_current_ma(&self) -> u32 {
+        u32::from(self.max_current().value()) * 10
+    }
+}
+
+/// Battery supply PDO.
+#[bitsize(32)]
+#[derive(FromBits, DebugBits, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Battery {
+    pub max_power: u10,   // 250mW units
+    pub min_voltage: u10, // 50mV units
+    pub max_voltage: u10, // 50mV units
+    supply_type: u2,
+}
+
+impl Battery {
+    /// Battery supply PDO, voltages and power beyond its range are clamped.
+    pub fn from_mv_mw(min_voltage_mv: u32, max_voltage_mv: u32, max_power_mw: u32) -> Self {
+        let mut pdo = Self::from(0b01 << 30);
+        pdo.set_min_voltage(u10::new(clamped(min_voltage_mv, 50, u10::MAX.value())));
+        pdo.set_max_voltage(u10::new(clamped(max_voltage_mv, 50, u10::MAX.value())));
+        pdo.set_max_power(u10::new(clamped(max_power_mw, 250, u10::MAX.value())));
+        pdo
+    }
+
+    pub fn min_voltage_mv(&self) -> u32 {
+        u32::from(self.min_voltage().value()) * 50
+    }
+
+    pub fn max_voltage_mv(&self) -> u32 {
+        u32::from(self.max_voltage().value()) * 50
+    }
+
+    pub fn max_power_mw(&self) -> u32 {
+        u32::from(self.max_power().value()) * 250
+    }
+}
+
+/// Variable supply (non-battery) PDO.
+#[bitsize(32)]
+#[derive(FromBits, DebugBits, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct VariableSupply {
+    pub max_current: u10, // 10mA units
+    pub min_voltage: u10, // 50mV units
+    pub max_voltage: u10, // 50mV units
+    supply_type: u2,
+}
+
+impl VariableSupply {
+    /// Variable supply PDO, voltages and current beyond its range are
+    /// clamped.
+    pub fn from_mv_ma(min_voltage_mv: u32, max_voltage_mv: u32, max_current_ma: u32) -> Self {
+        let mut pdo = Self::from(0b10 << 30);
+        pdo.set_min_voltage(u10::new(clamped(min_voltage_mv, 50, u10::MAX.value())));
+        pdo.set_max_voltage(u10::new(clamped(max_voltage_mv, 50, u10::MAX.value())));
+        pdo.set_max_current(u10::new(clamped(max_current_ma, 10, u10::MAX.value())));
+        pdo
+    }
+
+    pub fn min_voltage_mv(&self) -> u32 {
+        u32::from(self.min_voltage().value()) * 50
+    }
+
+    pub fn max_voltage_mv(&self) -> u32 {
+        u32::from(self.max_voltage().value()) * 50
+    }
+
+    pub fn max_current_ma(&self) -> u32 {
+        u32::from(self.max_current().value()) * 10
+    }
+}
+
+/// SPR Programmable Power Supply augmented PDO.
+#[bitsize(32)]
+#[derive(FromBits, DebugBits, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SprPps {
+    pub max_current: u7, // 50mA units
+    _reserved1: bool,
+    pub min_voltage: u8, // 100mV units
+    _reserved2: bool,
+    pub max_voltage: u8, // 100mV units
+    _reserved3: u2,
+    pub pps_power_limited: bool,
+    apdo_type: u2,
+    supply_type: u2,
+}
+
+impl SprPps {
+    /// PPS APDO, voltages and current beyond its range are clamped.
+    pub fn from_mv_ma(min_voltage_mv: u32, max_voltage_mv: u32, max_current_ma: u32) -> Self {
+        let mut pdo = Self::from(0b11 << 30);
+        pdo.set_min_voltage(clamped(min_voltage_mv, 100, u8::MAX.into()) as u8);
+        pdo.set_max_voltage(clamped(max_voltage_mv, 100, u8::MAX.into()) as u8);
+        let max_current = clamped(max_current_ma, 50, u7::MAX.value().into());
+        pdo.set_max_current(u7::new(max_current as u8));
+        pdo
+    }
+
+    pub fn min_voltage_mv(&self) -> u32 {
+        u32::from(self.min_voltage()) * 100
+    }
+
+    pub fn max_voltage_mv(&self) -> u32 {
+        u32::from(self.max_voltage()) * 100
+    }
+
+    pub fn max_current_ma(&self) -> u32 {
+        u32::from(self.max_current().value()) * 50
+    }
+}
+
+/// SPR Adjustable Voltage Supply augmented PDO.
+///
+/// Covers 9V up to 20V with the maximum current specified for the 9-15V and
+/// 15-20V ranges.
+#[bitsize(32)]
+#[derive(FromBits, DebugBits, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SprAvs {
+    pub max_current_20v: u10, // 10mA units
+    pub max_current_15v: u10, // 10mA units
+    _reserved: u6,
+    pub peak_current: u2,
+    apdo_type: u2,
+    supply_type: u2,
+}
+
+impl SprAvs {
+    /// SPR AVS APDO with the maximum currents of both voltage ranges,
+    /// clamped to the range of the APDO.
+    pub fn from_ma(max_current_15v_ma: u32, max_current_20v_ma: u32) -> Self {
+        let mut pdo = Self::from((0b11 << 30) | (0b10 << 28));
+        pdo.set_max_current_15v(u10::new(clamped(max_current_15v_ma, 10, u10::MAX.value())));
+        pdo.set_max_current_20v(u10::new(clamped(max_current_20v_ma, 10, u10::MAX.value())));
+        pdo
+    }
+
+    pub fn max_current_15v_ma(&self) -> u32 {
+        u32::from(self.max_current_15v().value()) * 10
+    }
+
+    pub fn max_current_20v_ma(&self) -> u32 {
+        u32::from(self.max_current_20v().value()) * 10
+    }
+}
+
+/// EPR Adjustable Voltage Supply augmented PDO.
+#[bitsize(32)]
+#[derive(FromBits, DebugBits, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct EprAvs {
+    pub pdp: u8,         // 1W units
+    pub min_voltage: u8, // 100mV units
+    _reserved: bool,
+    pub max_voltage: u9, // 100mV units
+    pub peak_current: u2,
+    apdo_type: u2,
+    supply_type: u2,
+}
+
+impl EprAvs {
+    /// EPR AVS APDO, voltages beyond its range are clamped.
+    pub fn from_mv_w(min_voltage_mv: u32, max_voltage_mv: u32, pdp_w: u8) -> Self {
+        let mut pdo = Self::from((0b11 << 30) | (0b01 << 28));
+        pdo.set_min_voltage(clamped(min_voltage_mv, 100, u8::MAX.into()) as u8);
+        pdo.set_max_voltage(u9::new(clamped(max_voltage_mv, 100, u9::MAX.value())));
+        pdo.set_pdp(pdp_w);
+        pdo
+    }
+
+    pub fn min_voltage_mv(&self) -> u32 {
+        u32::from(self.min_voltage()) * 100
+    }
+
+    pub fn max_voltage_mv(&self) -> u32 {
+        u32::from(self.max_voltage().value()) * 100
+    }
+
+    pub fn pdp_mw(&self) -> u32 {
+        u32::from(self.pdp()) * 1000
+    }
+}
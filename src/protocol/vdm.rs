@@ -0,0 +1,205 @@
+//! Vendor Defined Messages and the data objects of the Discover Identity
+//! response.
+
+use bilge::prelude::*;
+
+use super::SpecificationRevision;
+
+/// Standard ID of USB PD used for the discovery commands.
+pub const PD_SID: u16 = 0xFF00;
+
+/// Command of a structured VDM.
+#[bitsize(5)]
+#[derive(FromBits, Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum VdmCommand {
+    DiscoverIdentity = 0x1,
+    DiscoverSvids = 0x2,
+    DiscoverModes = 0x3,
+    EnterMode = 0x4,
+    ExitMode = 0x5,
+    Attention = 0x6,
+    // DisplayPort SVID
+    DisplayPortStatusUpdate = 0x10,
+    DisplayPortConfigure = 0x11,
+    #[fallback]
+    Reserved,
+}
+
+/// Command type of a structured VDM.
+#[bitsize(2)]
+#[derive(FromBits, Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum VdmCommandType {
+    Request,
+    Ack,
+    Nak,
+    Busy,
+}
+
+/// Header of a structured Vendor Defined Message.
+#[bitsize(32)]
+#[derive(FromBits, DebugBits, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct StructuredVdmHeader {
+    pub command: VdmCommand,
+    _reserved: bool,
+    pub command_type: VdmCommandType,
+    pub object_position: u3,
+    pub version_minor: u2,
+    pub version_major: u2,
+    /// Always set for structured VDMs.
+    pub structured: bool,
+    pub svid: u16,
+}
+
+impl StructuredVdmHeader {
+    /// Creates the header of a request, using VDM version 2.0 with USB PD 3.x
+    /// and version 1.0 otherwise.
+    pub fn request(svid: u16, command: VdmCommand, revision: SpecificationRevision) -> Self {
+        let version_major = match revision {
+            SpecificationRevision::Revision3_0 => u2::new(1),
+            _ => u2::new(0),
+        };
+        Self::new(
+            command,
+            VdmCommandType::Request,
+            u3::new(0),
+            u2::new(0),
+            version_major,
+            true,
+            svid,
+        )
+    }
+}
+
+/// Header of an unstructured Vendor Defined Message, whose content is
+/// defined by the vendor.
+#[bitsize(32)]
+#[derive(FromBits, DebugBits, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct UnstructuredVdmHeader {
+    pub vendor_use: u15,
+    /// Always cleared for unstructured VDMs.
+    pub structured: bool,
+    pub vendor_id: u16,
+}
+
+/// ID Header VDO, the first object of a Discover Identity response.
+#[bitsize(32)]
+#[derive(FromBits, DebugBits, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct IdHeaderVdo {
+    pub usb_vendor_id: u16,
+    _reserved: u5,
+    pub connector_type: u2,
+    pub product_type_dfp: u3,
+    pub modal_operation_supported: bool,
+    /// Product type of an UFP or of a cable plug.
+    pub product_type: u3,
+    pub usb_communications_capable_device: bool,
+    pub usb_communications_capable_host: bool,
+}
+
+impl IdHeaderVdo {
+    /// Product type of a passive cable plug.
+    pub const PRODUCT_TYPE_PASSIVE_CABLE: u8 = 0b011;
+
+    /// Product type of an active cable plug.
+    pub const PRODUCT_TYPE_ACTIVE_CABLE: u8 = 0b100;
+}
+
+/// Highest USB data speed supported by a cable.
+#[bitsize(3)]
+#[derive(FromBits, Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum UsbSpeed {
+    Usb2,
+    Usb32Gen1,
+    /// USB 3.2 Gen 2 and USB4 Gen 2
+    Usb32Gen2,
+    Usb4Gen3,
+    Usb4Gen4,
+    #[fallback]
+    Reserved,
+}
+
+/// Cable VDO of a passive cable, or the first Cable VDO of an active cable.
+///
+/// Only the fields shared by passive and active cables are decoded.
+#[bitsize(32)]
+#[derive(FromBits, DebugBits, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CableVdo {
+    pub usb_highest_speed: UsbSpeed,
+    _reserved1: u2,
+    /// 01b = 3A, 10b = 5A
+    pub vbus_current: u2,
+    _reserved2: u2,
+    /// 00b = 20V, 01b = 30V, 10b = 40V, 11b = 50V
+    pub max_vbus_voltage: u2,
+    pub cable_termination: u2,
+    pub cable_latency: u4,
+    pub epr_mode_capable: bool,
+    pub plug_type: u2,
+    _reserved3: bool,
+    pub vdo_version: u3,
+    pub firmware_version: u4,
+    pub hardware_version: u4,
+}
+
+impl CableVdo {
+    /// Maximum current the cable can carry.
+    pub fn max_current_ma(&self) -> u32 {
+        match self.vbus_current().value() {
+            0b10 => 5000,
+            // Every Type-C cable carries 3A, reserved values are treated the same.
+            _ => 3000,
+        }
+    }
+
+    /// Maximum VBUS voltage the cable is rated for.
+    pub fn max_voltage_mv(&self) -> u32 {
+        match self.max_vbus_voltage().value() {
+            0b00 => 20_000,
+            0b01 => 30_000,
+            0b10 => 40_000,
+            _ => 50_000,
+        }
+    }
+}
+
+/// Capabilities of an e-marked cable read with Discover Identity.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CableCapabilities {
+    pub max_current_ma: u32,
+    pub max_voltage_mv: u32,
+    pub speed: UsbSpeed,
+    /// The cable contains active components.
+    pub active: bool,
+}
+
+impl CableCapabilities {
+    /// Decodes the VDOs following the VDM header of an acknowledged Discover
+    /// Identity response of a cable plug.
+    ///
+    /// Returns `None` when the response does not describe a cable.
+    pub fn from_discover_identity(vdos: &[u32]) -> Option<Self> {
+        let &[id_header, _cert_stat, _product, cable, ..] = vdos else {
+            return None;
+        };
+        let active = match IdHeaderVdo::from(id_header).product_type().value() {
+            IdHeaderVdo::PRODUCT_TYPE_PASSIVE_CABLE => false,
+            IdHeaderVdo::PRODUCT_TYPE_ACTIVE_CABLE => true,
+            _ => return None,
+        };
+        let cable = CableVdo::from(cable);
+        Some(Self {
+            max_current_ma: cable.max_current_ma(),
+            max_voltage_mv: cable.max_voltage_mv(),
+            speed: cable.usb_highest_speed(),
+            active,
+        })
+    }
+}
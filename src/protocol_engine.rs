@@ -1,63 +1,374 @@
 use bilge::prelude::*;
-use defmt::{debug, trace, warn, Format};
-use embassy_stm32::ucpd::{Instance, PdPhy, RxError, TxError};
+use embassy_futures::select::{select, Either};
 use embassy_time::{with_timeout, Duration, TimeoutError};
-use safe_transmute::transmute_to_bytes_mut;
+use safe_transmute::{transmute_to_bytes, transmute_to_bytes_mut};
 
+use crate::phy::{CcPhy, NoCcPhy, PdPhy, RxError, Sop, TxError};
 use crate::protocol::*;
 
-const RETRY_COUNT: usize = 3;
+/// Number of retries for PD 2.0 (nRetryCount).
+const RETRY_COUNT_REV20: usize = 3;
+
+/// Number of retries for PD 3.0 and later (nRetryCount).
+const RETRY_COUNT_REV30: usize = 2;
 
 /// Time to wait for a GoodCRC messages
 const TIMEOUT_RECEIVE: Duration = Duration::from_millis(3);
 
-#[derive(Debug, Format, PartialEq)]
+/// Time to wait for the next chunk after requesting it.
+const TIMEOUT_CHUNK_SENDER_RESPONSE: Duration = Duration::from_millis(30);
+
+/// Time to wait for the request of the next chunk.
+const TIMEOUT_CHUNK_SENDER_REQUEST: Duration = Duration::from_millis(30);
+
+/// Maximum payload size of an extended message.
+pub const MAX_EXTENDED_MESSAGE_LEN: usize = 260;
+
+/// Maximum payload size of a single chunk.
+const MAX_EXTENDED_MESSAGE_CHUNK_LEN: usize = 26;
+
+#[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Message<'o> {
     Control(ControlMessageType),
     Data(DataMessageType, &'o [u32]),
+    /// Extended message with its reassembled payload.
+    Extended(ExtendedMessageType, &'o [u8]),
+}
+
+impl Message<'_> {
+    /// Returns `true` for messages introduced with USB PD 3.0.
+    fn requires_revision_3_0(&self) -> bool {
+        match *self {
+            Message::Control(message_type) => matches!(
+                message_type,
+                ControlMessageType::NotSupported
+                    | ControlMessageType::GetSourceCapExtended
+                    | ControlMessageType::GetStatus
+                    | ControlMessageType::FrSwap
+                    | ControlMessageType::GetPpsStatus
+                    | ControlMessageType::GetCountryCodes
+                    | ControlMessageType::GetSinkCapExtended
+                    | ControlMessageType::GetSourceInfo
+                    | ControlMessageType::GetRevision
+            ),
+            Message::Data(message_type, _) => matches!(
+                message_type,
+                DataMessageType::BatteryStatus
+                    | DataMessageType::Alert
+                    | DataMessageType::GetCountryInfo
+                    | DataMessageType::EnterUsb
+                    | DataMessageType::EprRequest
+                    | DataMessageType::EprMode
+                    | DataMessageType::SourceInfo
+                    | DataMessageType::Revision
+            ),
+            Message::Extended(..) => true,
+        }
+    }
+}
+
+/// Result of the chunked reception of an extended message.
+enum ChunkedRx {
+    /// Complete payload received, contains the payload length.
+    Complete(usize),
+    /// Another message was received instead of the next chunk.
+    Interrupted(Header),
+    /// Chunked transfer aborted.
+    Aborted,
 }
 
-#[derive(Debug, Format, Clone, Copy)]
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct HardReset;
 
-pub struct ProtocolEngine<'d, T: Instance> {
-    phy: PdPhy<'d, T>,
-    rx_message_id: Option<u3>,
-    tx_message_id: u3,
+/// Message received by the PHY but not yet answered with GoodCRC.
+struct RxFrame {
+    sop: Sop,
+    len: usize,
+    /// Received bytes, starting at byte 2 like in `receive_raw`.
+    raw_buf: [u32; 8],
+}
+
+/// Message ID state of one SOP* communication channel.
+#[derive(Clone, Copy)]
+struct MessageIds {
+    /// MessageIDCounter, ID of the next transmitted message.
+    tx: u3,
+    /// StoredMessageID, ID of the last received message.
+    rx: Option<u3>,
+}
+
+impl MessageIds {
+    const fn new() -> Self {
+        Self {
+            tx: u3::new(0),
+            rx: None,
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Advances the counter after a transmission, successful or not.
+    fn increment_tx(&mut self) {
+        self.tx = self.tx.wrapping_add(u3::new(1));
+    }
+
+    /// Stores the ID of a received message.
+    ///
+    /// Returns `false` when the message is a retransmission of the previously
+    /// received message.
+    fn store_rx(&mut self, message_id: u3) -> bool {
+        if self.rx == Some(message_id) {
+            return false;
+        }
+        self.rx = Some(message_id);
+        true
+    }
+}
+
+pub struct ProtocolEngine<P: PdPhy, C: CcPhy = NoCcPhy> {
+    phy: P,
+    cc_phy: C,
+    /// Message IDs for SOP, SOP' and SOP''.
+    message_ids: [MessageIds; 3],
+    /// Highest supported specification revision.
+    max_revision: SpecificationRevision,
     header_template: Header,
+    /// Message received by [`ProtocolEngine::wait_for_message`].
+    rx_frame: Option<RxFrame>,
 }
 
-impl<'d, T: Instance> ProtocolEngine<'d, T> {
-    pub fn new(phy: PdPhy<'d, T>) -> Self {
+impl<P: PdPhy> ProtocolEngine<P> {
+    /// Creates a protocol engine supporting up to USB PD 3.x.
+    pub fn new(phy: P) -> Self {
+        Self::with_max_revision(phy, SpecificationRevision::Revision3_0)
+    }
+
+    /// Creates a protocol engine that never uses a revision higher than `max_revision`.
+    pub fn with_max_revision(phy: P, max_revision: SpecificationRevision) -> Self {
         Self {
             phy,
-            rx_message_id: None,
-            tx_message_id: u3::new(0),
-            // TODO: make configurable
+            cc_phy: NoCcPhy,
+            message_ids: [MessageIds::new(); 3],
+            max_revision,
             header_template: Header::new(
-                u4::new(0),
-                false,
+                u5::new(0),
                 PortDataRole::UpstreamFacingPort,
-                SpecificationRevision::Revision2_0,
+                max_revision,
                 PortPowerRole::Sink,
                 u3::new(0),
                 u3::new(0),
                 false,
             ),
+            rx_frame: None,
+        }
+    }
+}
+
+impl<P: PdPhy, C: CcPhy> ProtocolEngine<P, C> {
+    /// Uses `cc_phy` to monitor the Rp level for collision avoidance.
+    pub fn with_cc_phy<C2: CcPhy>(self, cc_phy: C2) -> ProtocolEngine<P, C2> {
+        ProtocolEngine {
+            phy: self.phy,
+            cc_phy,
+            message_ids: self.message_ids,
+            max_revision: self.max_revision,
+            header_template: self.header_template,
+            rx_frame: self.rx_frame,
         }
     }
 
+    /// Sets the power role used in the header of transmitted messages.
+    pub fn set_power_role(&mut self, power_role: PortPowerRole) {
+        self.header_template.set_port_power_role(power_role);
+    }
+
+    /// Data role currently used in the header of transmitted messages.
+    pub fn data_role(&self) -> PortDataRole {
+        self.header_template.port_data_role()
+    }
+
+    /// Sets the data role used in the header of transmitted messages.
+    pub fn set_data_role(&mut self, data_role: PortDataRole) {
+        self.header_template.set_port_data_role(data_role);
+    }
+
+    /// Returns the CC line PHY, e.g. to switch the pull resistors.
+    pub fn cc_phy_mut(&mut self) -> &mut C {
+        &mut self.cc_phy
+    }
+
+    /// Specification revision currently used for communication.
+    pub fn specification_revision(&self) -> SpecificationRevision {
+        self.header_template.specification_revision()
+    }
+
+    /// Receives the next message.
+    ///
+    /// Not cancel safe: once the PHY received a message, it is answered with
+    /// GoodCRC and the remaining chunks of an extended message are requested.
+    /// Dropping the future meanwhile loses the message. Use
+    /// [`wait_for_message`](Self::wait_for_message) to race the reception
+    /// against other events.
     pub async fn receive<'o>(&mut self, obj_buf: &'o mut [u32]) -> Result<Message<'o>, HardReset> {
+        self.receive_sop(Sop::Sop, obj_buf).await
+    }
+
+    /// Waits until the PHY received a message, which is then handled by the
+    /// next call to [`receive`](Self::receive).
+    ///
+    /// Cancel safe as long as the [`PdPhy::receive_sop`] of the PHY is. The
+    /// message must be received right after this returns for the GoodCRC to
+    /// be sent in time.
+    pub async fn wait_for_message(&mut self) -> Result<(), HardReset> {
+        if self.rx_frame.is_some() {
+            return Ok(());
+        }
+        let mut raw_buf = [0_u32; 8];
+        loop {
+            let buf = &mut transmute_to_bytes_mut(&mut raw_buf)[2..];
+            match self.phy.receive_sop(buf).await {
+                Ok((sop, len)) => {
+                    self.rx_frame = Some(RxFrame { sop, len, raw_buf });
+                    return Ok(());
+                }
+                Err(RxError::Crc | RxError::Overrun) => continue,
+                Err(RxError::HardReset) => {
+                    self.handle_hard_reset()?;
+                    unreachable!()
+                }
+            }
+        }
+    }
+
+    /// Receives the next message sent with `sop`, messages of other SOP*
+    /// types are ignored without GoodCRC.
+    pub async fn receive_sop<'o>(
+        &mut self,
+        sop: Sop,
+        obj_buf: &'o mut [u32],
+    ) -> Result<Message<'o>, HardReset> {
+        match self.receive_until(sop, obj_buf, false).await? {
+            Some(msg) => Ok(msg),
+            None => unreachable!(),
+        }
+    }
+
+    /// Waits until the sink is allowed to initiate an atomic message sequence.
+    ///
+    /// With USB PD 3.0 the sink must wait for the source to signal SinkTxOk.
+    /// Messages received in the meantime are returned, in which case the
+    /// sequence must be postponed until the message was handled.
+    pub async fn start_ams<'o>(
+        &mut self,
+        obj_buf: &'o mut [u32],
+    ) -> Result<Option<Message<'o>>, HardReset> {
+        if self.header_template.port_power_role() != PortPowerRole::Sink
+            || self.specification_revision() != SpecificationRevision::Revision3_0
+        {
+            return Ok(None);
+        }
+        self.receive_until(Sop::Sop, obj_buf, true).await
+    }
+
+    /// Receives the next message or returns `None` as soon as the source
+    /// signals SinkTxOk when `until_sink_tx_ok` is set.
+    async fn receive_until<'o>(
+        &mut self,
+        sop: Sop,
+        obj_buf: &'o mut [u32],
+        until_sink_tx_ok: bool,
+    ) -> Result<Option<Message<'o>>, HardReset> {
+        let mut raw_buf = [0_u32; 8];
+        let Some(mut rx_header) = self
+            .receive_raw(sop, &mut raw_buf, until_sink_tx_ok)
+            .await?
+        else {
+            return Ok(None);
+        };
         loop {
+            let num_objects = usize::from(rx_header.number_of_data_objects().value());
+            let msg = if rx_header.extended() {
+                let payload = transmute_to_bytes_mut(obj_buf);
+                match self
+                    .receive_chunks(sop, rx_header, &mut raw_buf, payload)
+                    .await?
+                {
+                    ChunkedRx::Complete(len) => Message::Extended(
+                        ExtendedMessageType::from(rx_header.message_type()),
+                        &transmute_to_bytes(obj_buf)[..len],
+                    ),
+                    ChunkedRx::Interrupted(header) => {
+                        rx_header = header;
+                        continue;
+                    }
+                    ChunkedRx::Aborted => {
+                        match self
+                            .receive_raw(sop, &mut raw_buf, until_sink_tx_ok)
+                            .await?
+                        {
+                            Some(header) => rx_header = header,
+                            None => return Ok(None),
+                        }
+                        continue;
+                    }
+                }
+            } else if num_objects == 0 {
+                Message::Control(ControlMessageType::from(rx_header.message_type()))
+            } else {
+                let truncated_obj_len = obj_buf.len().min(num_objects);
+                for i in 0..obj_buf.len().min(num_objects) {
+                    obj_buf[i] = raw_buf[i + 1].to_le();
+                }
+                Message::Data(
+                    DataMessageType::from(rx_header.message_type()),
+                    &obj_buf[..truncated_obj_len],
+                )
+            };
+            debug!("Received {}", msg);
+            return Ok(Some(msg));
+        }
+    }
+
+    /// Receives a single message into `raw_buf`, answers it with GoodCRC and
+    /// filters out retransmissions.
+    async fn receive_raw(
+        &mut self,
+        sop: Sop,
+        raw_buf: &mut [u32; 8],
+        until_sink_tx_ok: bool,
+    ) -> Result<Option<Header>, HardReset> {
+        loop {
+            let frame = self.rx_frame.take();
+            if let Some(frame) = &frame {
+                *raw_buf = frame.raw_buf;
+            }
             // Skip the first to bytes so that the header goes into byte 3 and 4
             // and the data starts at a 4 byte alignment which allows it to be
             // transmuted to &[u32].
-            let mut raw_buf = [0_u32; 8];
-            let buf = &mut transmute_to_bytes_mut(&mut raw_buf)[2..];
+            let buf = &mut transmute_to_bytes_mut(raw_buf)[2..];
 
-            let n = match self.phy.receive(buf).await {
+            let rx = if let Some(frame) = frame {
+                Ok((frame.sop, frame.len))
+            } else if until_sink_tx_ok {
+                match select(self.phy.receive_sop(buf), wait_sink_tx_ok(&self.cc_phy)).await {
+                    Either::First(rx) => rx,
+                    Either::Second(()) => return Ok(None),
+                }
+            } else {
+                self.phy.receive_sop(buf).await
+            };
+            let n = match rx {
                 // Good reception, save received size.
-                Ok(n) => n,
+                Ok((rx_sop, n)) if rx_sop == sop => n,
+                // Messages for another SOP* type are not acknowledged.
+                Ok((_rx_sop, _)) => {
+                    debug!("RX ignoring {} message", _rx_sop);
+                    continue;
+                }
                 // Ignore incomplete messages and messages with invalid CRC.
                 Err(RxError::Crc | RxError::Overrun) => continue,
                 // Forward hard reset errors to caller.
@@ -86,15 +397,23 @@ impl<'d, T: Instance> ProtocolEngine<'d, T> {
 
             trace!("RX {=[u8]:x}", buf[..n]);
 
+            // SOP' and SOP'' messages are only accepted from a cable plug.
+            if sop != Sop::Sop && !rx_header.cable_plug() {
+                debug!("RX {} message not sent by a cable plug", sop);
+                continue;
+            }
+
             // Construct and transmit a GoodCRC response with a matching message id.
-            let mut goodcrc_header = self.header_template;
+            let mut goodcrc_header = self.header(sop);
             goodcrc_header.set_message_type(ControlMessageType::GoodCRC.into());
             goodcrc_header.set_message_id(rx_header.message_id());
 
             let tx_buf = u16::from(goodcrc_header).to_le_bytes();
-            match self.phy.transmit(&tx_buf).await {
+            match self.phy.transmit_sop(sop, &tx_buf).await {
                 // Cannot send GoodCRC, ignore received data and wait for retransmission.
-                Err(TxError::Discarded) => warn!("TX {=[u8]:x} GoodCRC Discarded", tx_buf),
+                Err(TxError::Discarded | TxError::Unsupported) => {
+                    warn!("TX {=[u8]:x} GoodCRC Discarded", tx_buf)
+                }
                 // Forward hard reset errors to caller.
                 Err(TxError::HardReset) => self.handle_hard_reset()?,
                 // Good transmission
@@ -104,64 +423,251 @@ impl<'d, T: Instance> ProtocolEngine<'d, T> {
             // Handle soft reset.
             if num_objects == 0 && rx_header.message_type() == ControlMessageType::SoftReset.into()
             {
-                self.rx_message_id = None;
-                self.tx_message_id = u3::new(0);
+                self.message_ids(sop).reset();
             }
 
             // Perform message deduplicated based on message id.
-            if self.rx_message_id == Some(rx_header.message_id()) {
+            if !self.message_ids(sop).store_rx(rx_header.message_id()) {
                 debug!("RX duplicate message");
                 continue;
             }
-            self.rx_message_id = Some(rx_header.message_id());
 
-            let msg = if num_objects == 0 {
-                Message::Control(ControlMessageType::from(rx_header.message_type()))
-            } else {
-                let truncated_obj_len = obj_buf.len().min(num_objects);
-                for i in 0..obj_buf.len().min(num_objects) {
-                    obj_buf[i] = raw_buf[i + 1].to_le();
+            // The Source_Capabilities message and the Request answering it
+            // determine the revision used by both ports.
+            if sop == Sop::Sop
+                && num_objects > 0
+                && !rx_header.extended()
+                && (rx_header.message_type() == DataMessageType::SourceCapabilites.into()
+                    || rx_header.message_type() == DataMessageType::Request.into())
+            {
+                self.negotiate_revision(rx_header.specification_revision());
+            }
+
+            return Ok(Some(rx_header));
+        }
+    }
+
+    /// Like [`Self::receive_raw`] but returns `None` after `timeout`.
+    async fn receive_raw_timeout(
+        &mut self,
+        sop: Sop,
+        raw_buf: &mut [u32; 8],
+        timeout: Duration,
+    ) -> Result<Option<Header>, HardReset> {
+        match with_timeout(timeout, self.receive_raw(sop, raw_buf, false)).await {
+            Ok(rx) => rx,
+            Err(TimeoutError) => Ok(None),
+        }
+    }
+
+    /// Reassembles an extended message starting with the chunk in `raw_buf`.
+    ///
+    /// The payload is truncated when it does not fit into `payload`.
+    async fn receive_chunks(
+        &mut self,
+        sop: Sop,
+        mut rx_header: Header,
+        raw_buf: &mut [u32; 8],
+        payload: &mut [u8],
+    ) -> Result<ChunkedRx, HardReset> {
+        let message_type = rx_header.message_type();
+        let mut chunk_number = 0;
+        let mut received = 0;
+        loop {
+            let num_objects = usize::from(rx_header.number_of_data_objects().value());
+            if num_objects == 0 {
+                warn!("RX extended message without extended header");
+                return Ok(ChunkedRx::Aborted);
+            }
+            // Extended header and data follow the message header.
+            let buf = &transmute_to_bytes(raw_buf)[4..4 + 4 * num_objects];
+            let ext_header = ExtendedHeader::from(u16::from_le_bytes([buf[0], buf[1]]));
+            let data = &buf[2..];
+            let data_size = usize::from(ext_header.data_size().value());
+
+            if !ext_header.chunked() {
+                // Unchunked extended messages are only accepted when they fit
+                // into a single message.
+                let len = data_size.min(data.len()).min(payload.len());
+                payload[..len].copy_from_slice(&data[..len]);
+                return Ok(ChunkedRx::Complete(len));
+            }
+            if ext_header.request_chunk() {
+                warn!("RX unexpected chunk request");
+                return Ok(ChunkedRx::Aborted);
+            }
+            if data_size > MAX_EXTENDED_MESSAGE_LEN {
+                warn!("RX extended message of {=usize} bytes too long", data_size);
+                return Ok(ChunkedRx::Aborted);
+            }
+            if ext_header.chunk_number().value() != chunk_number {
+                warn!(
+                    "RX chunk {=u8}, expected chunk {=u8}",
+                    ext_header.chunk_number().value(),
+                    chunk_number
+                );
+                return Ok(ChunkedRx::Aborted);
+            }
+
+            let chunk_len = data_size
+                .saturating_sub(received)
+                .min(MAX_EXTENDED_MESSAGE_CHUNK_LEN)
+                .min(data.len());
+            if received < payload.len() {
+                let len = chunk_len.min(payload.len() - received);
+                payload[received..received + len].copy_from_slice(&data[..len]);
+            }
+            received += chunk_len;
+            if received >= data_size || chunk_len == 0 {
+                return Ok(ChunkedRx::Complete(received.min(payload.len())));
+            }
+
+            chunk_number += 1;
+            let request = ExtendedHeader::new(u9::new(0), true, u4::new(chunk_number), true);
+            let request = u32::from(u16::from(request));
+            if !self
+                .transmit_raw(sop, message_type, true, &[request])
+                .await?
+            {
+                return Ok(ChunkedRx::Aborted);
+            }
+            match self
+                .receive_raw_timeout(sop, raw_buf, TIMEOUT_CHUNK_SENDER_RESPONSE)
+                .await?
+            {
+                Some(header) if header.extended() && header.message_type() == message_type => {
+                    rx_header = header
                 }
-                Message::Data(
-                    DataMessageType::from(rx_header.message_type()),
-                    &obj_buf[..truncated_obj_len],
-                )
-            };
-            debug!("Received {}", msg);
-            return Ok(msg);
+                Some(header) => return Ok(ChunkedRx::Interrupted(header)),
+                None => {
+                    warn!("RX chunk {=u8} timeout", chunk_number);
+                    return Ok(ChunkedRx::Aborted);
+                }
+            }
         }
     }
 
     pub async fn transmit(&mut self, msg: &Message<'_>) -> Result<bool, HardReset> {
-        debug!("Transmitting {}", msg);
+        self.transmit_sop(Sop::Sop, msg).await
+    }
+
+    /// Transmits a message to the port partner or to a cable plug and returns
+    /// `true` when it was acknowledged with a GoodCRC.
+    ///
+    /// Messages introduced with USB PD 3.0 are not sent while communicating
+    /// with an earlier revision, in which case `false` is returned.
+    pub async fn transmit_sop(&mut self, sop: Sop, msg: &Message<'_>) -> Result<bool, HardReset> {
+        debug!("Transmitting {} to {}", msg, sop);
+
+        if msg.requires_revision_3_0()
+            && self.specification_revision() != SpecificationRevision::Revision3_0
+        {
+            warn!("TX {} requires USB PD 3.0", msg);
+            return Ok(false);
+        }
 
         if let Message::Control(ControlMessageType::SoftReset) = msg {
-            self.rx_message_id = None;
+            self.message_ids(sop).reset();
         }
 
-        let mut raw_buf = [0_u32; 8];
-        let (msg_type, num_objects): (u4, usize) = match *msg {
-            Message::Control(hdr) => (hdr.into(), 0),
-            Message::Data(hdr, data) => {
-                raw_buf[1..1 + data.len()].copy_from_slice(data);
-                (hdr.into(), data.len())
+        match *msg {
+            Message::Control(hdr) => self.transmit_raw(sop, hdr.into(), false, &[]).await,
+            Message::Data(hdr, data) => self.transmit_raw(sop, hdr.into(), false, data).await,
+            Message::Extended(hdr, payload) => self.transmit_chunks(sop, hdr, payload).await,
+        }
+    }
+
+    /// Transmits an extended message in chunks and waits for the chunk
+    /// requests of the receiver.
+    async fn transmit_chunks(
+        &mut self,
+        sop: Sop,
+        message_type: ExtendedMessageType,
+        payload: &[u8],
+    ) -> Result<bool, HardReset> {
+        let payload = &payload[..payload.len().min(MAX_EXTENDED_MESSAGE_LEN)];
+        let mut chunk_number = 0;
+        loop {
+            let start = usize::from(chunk_number) * MAX_EXTENDED_MESSAGE_CHUNK_LEN;
+            let end = payload.len().min(start + MAX_EXTENDED_MESSAGE_CHUNK_LEN);
+            let chunk = &payload[start..end];
+
+            let ext_header = ExtendedHeader::new(
+                u9::new(payload.len() as u16),
+                false,
+                u4::new(chunk_number),
+                true,
+            );
+            let mut objects = [0_u32; 7];
+            let buf = transmute_to_bytes_mut(&mut objects);
+            [buf[0], buf[1]] = u16::from(ext_header).to_le_bytes();
+            buf[2..2 + chunk.len()].copy_from_slice(chunk);
+            let num_objects = (2 + chunk.len()).div_ceil(4);
+            if !self
+                .transmit_raw(sop, message_type.into(), true, &objects[..num_objects])
+                .await?
+            {
+                return Ok(false);
             }
-        };
+            if end == payload.len() {
+                return Ok(true);
+            }
+
+            chunk_number += 1;
+            let mut raw_buf = [0_u32; 8];
+            let Some(rx_header) = self
+                .receive_raw_timeout(sop, &mut raw_buf, TIMEOUT_CHUNK_SENDER_REQUEST)
+                .await?
+            else {
+                warn!("TX chunk request {=u8} timeout", chunk_number);
+                return Ok(false);
+            };
+            let buf = &transmute_to_bytes(&raw_buf)[4..];
+            let request = ExtendedHeader::from(u16::from_le_bytes([buf[0], buf[1]]));
+            if !rx_header.extended()
+                || rx_header.message_type() != message_type.into()
+                || rx_header.number_of_data_objects() == u3::new(0)
+                || !request.request_chunk()
+                || request.chunk_number().value() != chunk_number
+            {
+                warn!("TX expected chunk request {=u8}", chunk_number);
+                return Ok(false);
+            }
+        }
+    }
 
-        let mut tx_header = self.header_template;
-        tx_header.set_message_id(self.tx_message_id);
+    /// Transmits a single message with retries.
+    async fn transmit_raw(
+        &mut self,
+        sop: Sop,
+        msg_type: u5,
+        extended: bool,
+        objects: &[u32],
+    ) -> Result<bool, HardReset> {
+        let mut raw_buf = [0_u32; 8];
+        let num_objects = objects.len();
+        raw_buf[1..1 + num_objects].copy_from_slice(objects);
+
+        let mut tx_header = self.header(sop);
+        let message_id = self.message_ids(sop).tx;
+        tx_header.set_message_id(message_id);
         tx_header.set_message_type(msg_type);
         tx_header.set_number_of_data_objects(u3::new(num_objects as _));
+        tx_header.set_extended(extended);
 
         let mut ok = false;
-        for _retry in 0..=RETRY_COUNT {
+        let retry_count = match self.specification_revision() {
+            SpecificationRevision::Revision3_0 => RETRY_COUNT_REV30,
+            _ => RETRY_COUNT_REV20,
+        };
+        for _retry in 0..=retry_count {
             // Skip the first to bytes to put the header right before the data objects.
             // Transmuting must be done inside the loop to please the borrow checker.
             let buf = &mut transmute_to_bytes_mut(&mut raw_buf)[2..2 + 2 + 4 * num_objects];
             [buf[0], buf[1]] = u16::from(tx_header).to_le_bytes();
 
             trace!("TX {=[u8]:x} retry={=usize}", buf, _retry);
-            match self.phy.transmit(buf).await {
+            match self.phy.transmit_sop(sop, buf).await {
                 Ok(()) => {}
                 // Retry when line not idle.
                 Err(TxError::Discarded) => {
@@ -170,16 +676,21 @@ impl<'d, T: Instance> ProtocolEngine<'d, T> {
                 }
                 // Forward hard reset to caller.
                 Err(TxError::HardReset) => self.handle_hard_reset()?,
+                // Retries cannot help, the message was never sent.
+                Err(TxError::Unsupported) => {
+                    warn!("TX {} not supported by the PHY", sop);
+                    return Ok(false);
+                }
             }
 
             let mut goodcrc_buf = [0_u8; 2];
-            match with_timeout(TIMEOUT_RECEIVE, self.phy.receive(&mut goodcrc_buf)).await {
-                Ok(Ok(2)) => {
+            match with_timeout(TIMEOUT_RECEIVE, self.phy.receive_sop(&mut goodcrc_buf)).await {
+                Ok(Ok((rx_sop, 2))) if rx_sop == sop => {
                     let goodcrc =
                         Header::from(u16::from_le_bytes([goodcrc_buf[0], goodcrc_buf[1]]));
                     if goodcrc.number_of_data_objects() != u3::new(0)
                         || goodcrc.message_type() != ControlMessageType::GoodCRC.into()
-                        || goodcrc.message_id() != self.tx_message_id
+                        || goodcrc.message_id() != message_id
                     {
                         warn!(
                             "TX retry={=usize} Received invalid GoodCRC message {=[u8]:x}",
@@ -206,19 +717,82 @@ impl<'d, T: Instance> ProtocolEngine<'d, T> {
             }
         }
 
-        self.tx_message_id.wrapping_add(u3::new(1));
+        self.message_ids(sop).increment_tx();
         Ok(ok)
     }
 
     pub async fn transmit_hard_reset(&mut self) {
         debug!("Transmitting HardReset");
-        let _ = self.phy.transmit_hardreset().await;
+        let _ = self.phy.transmit_hard_reset().await;
+        self.reset();
+    }
+
+    /// Transmits BIST Carrier Mode 2 for `duration` and returns `false` when
+    /// the PHY does not support it or discarded it.
+    pub async fn transmit_bist_carrier(&mut self, duration: Duration) -> Result<bool, HardReset> {
+        debug!("Transmitting BIST carrier");
+        match self.phy.transmit_bist_carrier(duration).await {
+            Ok(()) => Ok(true),
+            Err(TxError::Discarded | TxError::Unsupported) => Ok(false),
+            Err(TxError::HardReset) => self.handle_hard_reset().map(|_| false),
+        }
     }
 
     fn handle_hard_reset(&mut self) -> Result<(), HardReset> {
         debug!("Received HardReset");
-        self.rx_message_id = None;
-        self.tx_message_id = u3::new(0);
+        self.reset();
         Err(HardReset)
     }
+
+    /// Resets the protocol layer after a hard reset or a detach.
+    pub(crate) fn reset(&mut self) {
+        self.rx_frame = None;
+        for message_ids in &mut self.message_ids {
+            message_ids.reset();
+        }
+        self.header_template
+            .set_specification_revision(self.max_revision);
+    }
+
+    /// Header template for messages sent with `sop`.
+    fn header(&self, sop: Sop) -> Header {
+        let mut header = self.header_template;
+        if sop != Sop::Sop {
+            // Messages to a cable plug have the Cable Plug bit cleared and
+            // no data role.
+            header.set_cable_plug(false);
+            header.set_port_data_role(PortDataRole::UpstreamFacingPort);
+        }
+        header
+    }
+
+    fn message_ids(&mut self, sop: Sop) -> &mut MessageIds {
+        &mut self.message_ids[sop as usize]
+    }
+
+    /// Uses the lower of the own and the partners specification revision.
+    fn negotiate_revision(&mut self, revision: SpecificationRevision) {
+        let revision = match revision {
+            // Future revisions are backwards compatible to the highest known one.
+            SpecificationRevision::Reserved => SpecificationRevision::Revision3_0,
+            revision => revision,
+        };
+        let revision = if u2::from(revision) < u2::from(self.max_revision) {
+            revision
+        } else {
+            self.max_revision
+        };
+        if revision != self.specification_revision() {
+            debug!("Using specification revision {}", revision);
+            self.header_template.set_specification_revision(revision);
+        }
+    }
+}
+
+async fn wait_sink_tx_ok(cc_phy: &impl CcPhy) {
+    let mut rp_level = cc_phy.rp_level();
+    while !rp_level.sink_tx_ok() {
+        debug!("Waiting for SinkTxOk");
+        rp_level = cc_phy.wait_for_rp_level_change().await;
+    }
 }
@@ -0,0 +1,508 @@
+//! Selection of the power to request from a source.
+
+use core::cell::Cell;
+use core::future::pending;
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+
+use crate::protocol::source_capabilities::PowerDataObject;
+use crate::protocol::{sink_capabilities, PortDataRole};
+
+/// vSafe5V, the voltage of the first PDO in every capability list.
+const VSAFE5V_MV: u32 = 5000;
+
+/// Power to request from the source.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Selection {
+    /// Position of the selected PDO in the Source_Capabilities, starting at 1.
+    pub object_position: u8,
+    pub operating_current_ma: u32,
+    pub max_operating_current_ma: u32,
+    /// None of the offered capabilities satisfies the sink.
+    pub capability_mismatch: bool,
+    /// Output voltage when the selected PDO is a programmable power supply.
+    pub pps_voltage_mv: Option<u32>,
+    /// Current the sink is able to reduce its load to when the source sends
+    /// GotoMin. Sets the GiveBack flag of the request, which then carries it
+    /// in place of the maximum operating current.
+    pub min_operating_current_ma: Option<u32>,
+}
+
+/// Decides which of the capabilities offered by a source the sink requests.
+#[allow(async_fn_in_trait)]
+pub trait SinkPolicy {
+    /// Selects the power to request from `source_capabilities`.
+    fn select(&mut self, source_capabilities: &[PowerDataObject]) -> Selection;
+
+    /// Writes the PDOs of the Sink_Capabilities message into `pdos` and
+    /// returns how many were written.
+    fn sink_capabilities(&self, pdos: &mut [u32]) -> usize;
+
+    /// Resolves when the sink wants to request power again from the last
+    /// received source capabilities. Never resolves by default.
+    async fn renegotiate(&mut self) {
+        pending().await
+    }
+
+    /// Resolves with the next command for the sink in an explicit contract.
+    ///
+    /// By default this is [`SinkCommand::Renegotiate`] once
+    /// [`renegotiate`](Self::renegotiate) resolves.
+    async fn command(&mut self) -> SinkCommand {
+        self.renegotiate().await;
+        SinkCommand::Renegotiate
+    }
+
+    /// Selects a capability of `source_capabilities` with exactly
+    /// `voltage_mv` for [`SinkCommand::RequestVoltage`], `None` when the sink
+    /// cannot operate from any of them. Rejects all voltages by default.
+    fn select_voltage(
+        &mut self,
+        _source_capabilities: &[PowerDataObject],
+        _voltage_mv: u32,
+    ) -> Option<Selection> {
+        None
+    }
+
+    /// Returns `true` when a dual-role port accepts to become source.
+    fn accept_swap_to_source(&mut self) -> bool {
+        false
+    }
+
+    /// Data role the port swaps to with DR_Swap once in an explicit contract,
+    /// `None` to keep the initial data role.
+    fn preferred_data_role(&self) -> Option<PortDataRole> {
+        None
+    }
+
+    /// Returns `true` when the port accepts a data role swap requested by
+    /// the port partner.
+    fn accept_data_role_swap(&mut self) -> bool {
+        false
+    }
+
+    /// Called with the new data role after a data role swap, e.g. to switch
+    /// the USB controller between device and host mode.
+    fn data_role_swapped(&mut self, _data_role: PortDataRole) {}
+
+    /// Called when the source sends GotoMin in a contract requested with
+    /// GiveBack. The load must be reduced to `min_operating_current_ma`
+    /// before the source signals PS_RDY.
+    fn goto_min(&mut self, _min_operating_current_ma: u32) {}
+
+    /// Called when the state of the sink changes, e.g. to enable loads once
+    /// a contract is established or to forward the event to a channel.
+    fn notify(&mut self, _event: SinkEvent<'_>) {}
+}
+
+/// State change of a sink reported to [`SinkPolicy::notify`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SinkEvent<'a> {
+    /// The sink started running with a source attached.
+    Attached,
+    SourceCapabilitiesReceived(&'a [PowerDataObject]),
+    /// The source signaled with PS_RDY that it supplies the requested power.
+    ContractEstablished {
+        /// Output voltage, the upper end of the range for variable and
+        /// battery supplies.
+        voltage_mv: u32,
+        operating_current_ma: u32,
+        pdo: PowerDataObject,
+    },
+    /// The source rejected the request. The previous contract stays in
+    /// place, without one the sink falls back to vSafe5V.
+    ContractRejected,
+    /// The source asked the sink to wait. In an explicit contract the request
+    /// is repeated after tSinkRequest.
+    Wait,
+    /// The protocol was reset with a soft reset, the sink waits for new
+    /// source capabilities.
+    SoftReset,
+    /// A hard reset was sent or received, VBUS returns to vSafe5V.
+    HardReset,
+    /// The source was detached, see
+    /// [`PolicyEngine::detach`](crate::policy_engine::PolicyEngine::detach).
+    Detached,
+}
+
+/// Voltage range and current a sink is able to operate with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PowerRange {
+    pub min_voltage_mv: u32,
+    pub max_voltage_mv: u32,
+    /// A supply offering less current is not acceptable.
+    pub min_current_ma: u32,
+    /// The sink requests up to this current when the supply offers it.
+    pub max_current_ma: u32,
+}
+
+impl PowerRange {
+    pub const fn new(
+        min_voltage_mv: u32,
+        max_voltage_mv: u32,
+        min_current_ma: u32,
+        max_current_ma: u32,
+    ) -> Self {
+        Self {
+            min_voltage_mv,
+            max_voltage_mv,
+            min_current_ma,
+            max_current_ma,
+        }
+    }
+
+    /// Exact voltage with a fixed operating current.
+    pub const fn fixed(voltage_mv: u32, current_ma: u32) -> Self {
+        Self::new(voltage_mv, voltage_mv, current_ma, current_ma)
+    }
+
+    fn contains(&self, voltage_mv: u32) -> bool {
+        self.min_voltage_mv <= voltage_mv && voltage_mv <= self.max_voltage_mv
+    }
+}
+
+/// Order in which acceptable capabilities are preferred.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Preference {
+    /// Highest product of voltage and requested current.
+    HighestPower,
+    /// Highest voltage, ties are resolved by the current.
+    HighestVoltage,
+    /// Only supplies able to provide exactly this voltage in mV, ties are
+    /// resolved by the current.
+    ExactVoltage(u32),
+}
+
+/// Output voltage and current requested from a programmable power supply.
+///
+/// Shared with the application to change the setpoint at runtime. When the
+/// source rejects a new setpoint, [`SinkConfig`] restores the one of the
+/// current PPS contract, so it is not requested again.
+pub struct PpsSetpoint {
+    setpoint: Mutex<CriticalSectionRawMutex, Cell<(u32, u32)>>,
+    /// Setpoint of the current PPS contract.
+    accepted: Mutex<CriticalSectionRawMutex, Cell<Option<(u32, u32)>>>,
+    changed: Signal<CriticalSectionRawMutex, ()>,
+}
+
+impl PpsSetpoint {
+    pub const fn new(voltage_mv: u32, current_ma: u32) -> Self {
+        Self {
+            setpoint: Mutex::new(Cell::new((voltage_mv, current_ma))),
+            accepted: Mutex::new(Cell::new(None)),
+            changed: Signal::new(),
+        }
+    }
+
+    /// Changes the setpoint, which makes the sink send a new request.
+    pub fn set(&self, voltage_mv: u32, current_ma: u32) {
+        self.setpoint.lock(|s| s.set((voltage_mv, current_ma)));
+        self.changed.signal(());
+    }
+
+    /// Returns the output voltage in mV and the operating current in mA.
+    pub fn get(&self) -> (u32, u32) {
+        self.setpoint.lock(|s| s.get())
+    }
+
+    fn set_accepted(&self, accepted: Option<(u32, u32)>) {
+        self.accepted.lock(|a| a.set(accepted));
+    }
+
+    /// Goes back to the setpoint of the current PPS contract, if any.
+    fn restore(&self) {
+        if let Some(accepted) = self.accepted.lock(|a| a.get()) {
+            self.setpoint.lock(|s| s.set(accepted));
+        }
+    }
+}
+
+/// Command from the application to a sink in an explicit contract.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SinkCommand {
+    /// Request the voltage in mV from the current source capabilities, also
+    /// when the source sends new ones, see [`SinkPolicy::select_voltage`].
+    RequestVoltage(u32),
+    /// Request the power selected by the policy again, e.g. after its
+    /// configuration changed. Also ends a previous `RequestVoltage`.
+    Renegotiate,
+    /// Ask the source for its current capabilities with Get_Source_Cap.
+    GetSourceCapabilities,
+    /// Request with GiveBack and the current in mA the sink reduces its load
+    /// to on GotoMin, or without GiveBack for `None`. Overrides
+    /// [`SinkConfig::give_back_current_ma`] and the policy selection for all
+    /// following requests.
+    GiveBack(Option<u32>),
+    SoftReset,
+    HardReset,
+    /// Ask a dual-role port partner to become sink with PR_Swap. The roles
+    /// stay when it answers with Reject or Wait.
+    PowerRoleSwap,
+}
+
+/// Queue of commands shared with the application to control a running sink.
+pub struct SinkCommands {
+    channel: Channel<CriticalSectionRawMutex, SinkCommand, 4>,
+}
+
+impl SinkCommands {
+    pub const fn new() -> Self {
+        Self {
+            channel: Channel::new(),
+        }
+    }
+
+    /// Queues `command`, waits while the queue is full.
+    pub async fn send(&self, command: SinkCommand) {
+        self.channel.send(command).await
+    }
+
+    /// Queues `command` and returns `false` when the queue is full.
+    pub fn try_send(&self, command: SinkCommand) -> bool {
+        self.channel.try_send(command).is_ok()
+    }
+
+    /// Waits for the next queued command.
+    pub async fn receive(&self) -> SinkCommand {
+        self.channel.receive().await
+    }
+}
+
+impl Default for SinkCommands {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// [`SinkPolicy`] selecting a Fixed or Variable supply that fits in one of
+/// the configured power ranges.
+///
+/// When a PPS setpoint is configured and one of the programmable power
+/// supplies offered by the source is able to provide it, the programmable
+/// supply is preferred.
+#[derive(Clone, Copy)]
+pub struct SinkConfig<'a> {
+    /// Acceptable power ranges, ordered by increasing voltage.
+    pub ranges: &'a [PowerRange],
+    pub preference: Preference,
+    pub pps: Option<&'a PpsSetpoint>,
+    /// Commands of the application, e.g. to request another voltage.
+    pub commands: Option<&'a SinkCommands>,
+    /// Current the sink reduces its load to on GotoMin. Requests carry the
+    /// GiveBack flag when set.
+    pub give_back_current_ma: Option<u32>,
+}
+
+impl SinkConfig<'_> {
+    /// Returns how desirable a supply is according to the preference, higher
+    /// is better. `None` when the preference rules the supply out.
+    fn score(&self, min_voltage_mv: u32, max_voltage_mv: u32, current_ma: u32) -> Option<u64> {
+        let (voltage_mv, current_ma) = (u64::from(max_voltage_mv), u64::from(current_ma));
+        match self.preference {
+            Preference::HighestPower => Some(voltage_mv * current_ma),
+            Preference::HighestVoltage => Some((voltage_mv << 32) | current_ma),
+            Preference::ExactVoltage(mv) if min_voltage_mv <= mv && mv <= max_voltage_mv => {
+                Some(current_ma)
+            }
+            Preference::ExactVoltage(_) => None,
+        }
+    }
+
+    fn select_pps(&self, source_capabilities: &[PowerDataObject]) -> Option<Selection> {
+        let (voltage_mv, current_ma) = self.pps?.get();
+        source_capabilities
+            .iter()
+            .position(|pdo| match pdo {
+                PowerDataObject::Pps(pdo) => {
+                    pdo.min_voltage_mv() <= voltage_mv
+                        && voltage_mv <= pdo.max_voltage_mv()
+                        && current_ma <= pdo.max_current_ma()
+                }
+                _ => false,
+            })
+            .map(|i| Selection {
+                object_position: i as u8 + 1,
+                operating_current_ma: current_ma,
+                max_operating_current_ma: current_ma,
+                capability_mismatch: false,
+                pps_voltage_mv: Some(voltage_mv),
+                min_operating_current_ma: None,
+            })
+    }
+
+    /// Minimum operating current of a request for `current_ma`.
+    fn min_operating_current_ma(&self, current_ma: u32) -> Option<u32> {
+        self.give_back_current_ma.map(|ma| ma.min(current_ma))
+    }
+
+    /// Current requested when operating from vSafe5V.
+    fn vsafe5v_current_ma(&self) -> u32 {
+        self.ranges
+            .iter()
+            .find(|r| r.contains(VSAFE5V_MV))
+            .or(self.ranges.first())
+            .map_or(0, |r| r.max_current_ma)
+    }
+}
+
+impl SinkPolicy for SinkConfig<'_> {
+    fn select(&mut self, source_capabilities: &[PowerDataObject]) -> Selection {
+        if let Some(selection) = self.select_pps(source_capabilities) {
+            return selection;
+        }
+
+        let mut best: Option<(u64, Selection)> = None;
+        for (i, pdo) in source_capabilities.iter().enumerate() {
+            let (min_voltage_mv, max_voltage_mv, max_current_ma) = match pdo {
+                PowerDataObject::FixedSupply(pdo) => {
+                    (pdo.voltage_mv(), pdo.voltage_mv(), pdo.max_current_ma())
+                }
+                PowerDataObject::VariableSupply(pdo) => (
+                    pdo.min_voltage_mv(),
+                    pdo.max_voltage_mv(),
+                    pdo.max_current_ma(),
+                ),
+                _ => continue,
+            };
+
+            for range in self.ranges {
+                if min_voltage_mv < range.min_voltage_mv
+                    || max_voltage_mv > range.max_voltage_mv
+                    || max_current_ma < range.min_current_ma
+                {
+                    continue;
+                }
+
+                let current_ma = max_current_ma.min(range.max_current_ma);
+                let Some(score) = self.score(min_voltage_mv, max_voltage_mv, current_ma) else {
+                    continue;
+                };
+                if best.map_or(true, |(best_score, _)| score > best_score) {
+                    let selection = Selection {
+                        object_position: i as u8 + 1,
+                        operating_current_ma: current_ma,
+                        max_operating_current_ma: current_ma,
+                        capability_mismatch: false,
+                        pps_voltage_mv: None,
+                        min_operating_current_ma: self.min_operating_current_ma(current_ma),
+                    };
+                    best = Some((score, selection));
+                }
+            }
+        }
+
+        best.map(|(_, selection)| selection).unwrap_or_else(|| {
+            // Nothing fits, fall back to vSafe5V which is always the first PDO.
+            let available_ma = match source_capabilities.first() {
+                Some(PowerDataObject::FixedSupply(pdo)) => pdo.max_current_ma(),
+                _ => 0,
+            };
+            let current_ma = self.vsafe5v_current_ma().min(available_ma);
+            Selection {
+                object_position: 1,
+                operating_current_ma: current_ma,
+                max_operating_current_ma: current_ma,
+                capability_mismatch: true,
+                pps_voltage_mv: None,
+                min_operating_current_ma: self.min_operating_current_ma(current_ma),
+            }
+        })
+    }
+
+    fn sink_capabilities(&self, pdos: &mut [u32]) -> usize {
+        let Some((first, others)) = pdos.split_first_mut() else {
+            return 0;
+        };
+
+        let mut vsafe5v =
+            sink_capabilities::FixedSupply::from_mv_ma(VSAFE5V_MV, self.vsafe5v_current_ma());
+        vsafe5v.set_higher_capabilty(self.ranges.iter().any(|r| r.max_voltage_mv > VSAFE5V_MV));
+        *first = vsafe5v.into();
+
+        let mut n = 1;
+        let ranges = self
+            .ranges
+            .iter()
+            .filter(|r| r.min_voltage_mv != VSAFE5V_MV || r.max_voltage_mv != VSAFE5V_MV);
+        for (pdo, range) in others.iter_mut().zip(ranges) {
+            *pdo = if range.min_voltage_mv == range.max_voltage_mv {
+                sink_capabilities::FixedSupply::from_mv_ma(
+                    range.max_voltage_mv,
+                    range.max_current_ma,
+                )
+                .into()
+            } else {
+                sink_capabilities::VariableSupply::from_mv_ma(
+                    range.min_voltage_mv,
+                    range.max_voltage_mv,
+                    range.max_current_ma,
+                )
+                .into()
+            };
+            n += 1;
+        }
+        n
+    }
+
+    async fn renegotiate(&mut self) {
+        match self.pps {
+            Some(pps) => pps.changed.wait().await,
+            None => pending().await,
+        }
+    }
+
+    async fn command(&mut self) -> SinkCommand {
+        let commands = self.commands;
+        let command = async {
+            match commands {
+                Some(commands) => commands.receive().await,
+                None => pending().await,
+            }
+        };
+        match select(self.renegotiate(), command).await {
+            Either::First(()) => SinkCommand::Renegotiate,
+            Either::Second(command) => command,
+        }
+    }
+
+    fn select_voltage(
+        &mut self,
+        source_capabilities: &[PowerDataObject],
+        voltage_mv: u32,
+    ) -> Option<Selection> {
+        let mut config = SinkConfig {
+            preference: Preference::ExactVoltage(voltage_mv),
+            pps: None,
+            ..*self
+        };
+        Some(config.select(source_capabilities)).filter(|s| !s.capability_mismatch)
+    }
+
+    fn notify(&mut self, event: SinkEvent<'_>) {
+        let Some(pps) = self.pps else {
+            return;
+        };
+        match event {
+            SinkEvent::ContractEstablished {
+                voltage_mv,
+                operating_current_ma,
+                pdo: PowerDataObject::Pps(_),
+            } => pps.set_accepted(Some((voltage_mv, operating_current_ma))),
+            SinkEvent::ContractEstablished { .. }
+            | SinkEvent::SoftReset
+            | SinkEvent::HardReset
+            | SinkEvent::Detached => pps.set_accepted(None),
+            SinkEvent::ContractRejected => pps.restore(),
+            _ => {}
+        }
+    }
+}
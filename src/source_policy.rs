@@ -0,0 +1,164 @@
+//! Capabilities offered by a source and evaluation of sink requests.
+
+use core::future::pending;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+
+use crate::protocol::source_capabilities::PowerDataObject;
+use crate::protocol::PortDataRole;
+use crate::sink_policy::Selection;
+
+/// Response of the source to a request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Evaluation {
+    Accept,
+    /// The request cannot be satisfied.
+    Reject,
+    /// The request can be satisfied later.
+    Wait,
+}
+
+/// Decides which capabilities a source offers and which requests it accepts.
+#[allow(async_fn_in_trait)]
+pub trait SourcePolicy {
+    /// Writes the PDOs of the Source_Capabilities message into `pdos` and
+    /// returns how many were written.
+    fn source_capabilities(&self, pdos: &mut [PowerDataObject]) -> usize;
+
+    /// Evaluates a request of the sink for the capability `pdo`.
+    fn evaluate(&mut self, pdo: &PowerDataObject, request: &Selection) -> Evaluation;
+
+    /// Returns `true` when a dual-role port accepts to become sink.
+    fn accept_swap_to_sink(&mut self) -> bool {
+        false
+    }
+
+    /// Data role the port swaps to with DR_Swap once in an explicit contract,
+    /// `None` to keep the initial data role.
+    fn preferred_data_role(&self) -> Option<PortDataRole> {
+        None
+    }
+
+    /// Returns `true` when the port accepts a data role swap requested by
+    /// the port partner.
+    fn accept_data_role_swap(&mut self) -> bool {
+        false
+    }
+
+    /// Called with the new data role after a data role swap, e.g. to switch
+    /// the USB controller between device and host mode.
+    fn data_role_swapped(&mut self, _data_role: PortDataRole) {}
+
+    /// Resolves with the next command for the source in an explicit
+    /// contract. Never resolves by default.
+    async fn command(&mut self) -> SourceCommand {
+        pending().await
+    }
+}
+
+/// Command from the application to a source in an explicit contract.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SourceCommand {
+    /// Ask a dual-role port partner to become source with PR_Swap. The roles
+    /// stay when it answers with Reject or Wait.
+    PowerRoleSwap,
+}
+
+/// Queue of commands shared with the application to control a running
+/// source.
+pub struct SourceCommands {
+    channel: Channel<CriticalSectionRawMutex, SourceCommand, 4>,
+}
+
+impl SourceCommands {
+    pub const fn new() -> Self {
+        Self {
+            channel: Channel::new(),
+        }
+    }
+
+    /// Queues `command`, waits while the queue is full.
+    pub async fn send(&self, command: SourceCommand) {
+        self.channel.send(command).await
+    }
+
+    /// Queues `command` and returns `false` when the queue is full.
+    pub fn try_send(&self, command: SourceCommand) -> bool {
+        self.channel.try_send(command).is_ok()
+    }
+
+    /// Waits for the next queued command.
+    pub async fn receive(&self) -> SourceCommand {
+        self.channel.receive().await
+    }
+}
+
+impl Default for SourceCommands {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Controls the VBUS output of a source.
+#[allow(async_fn_in_trait)]
+pub trait PowerSupply {
+    /// Transitions VBUS to the capability `pdo` as requested by the sink and
+    /// returns when the output is within its new range.
+    async fn transition(&mut self, pdo: &PowerDataObject, request: &Selection);
+
+    /// Switches VBUS off and returns when it reached vSafe0V.
+    async fn disable(&mut self);
+
+    /// Switches VBUS on and returns when it reached vSafe5V.
+    async fn enable(&mut self);
+}
+
+/// Source with a fixed list of capabilities.
+///
+/// Accepts every request within the offered capabilities. Battery and AVS
+/// capabilities are advertised but requests for them are rejected.
+#[derive(Clone, Copy)]
+pub struct SourceConfig<'a> {
+    /// Capabilities, the first one must be a vSafe5V fixed supply.
+    pub capabilities: &'a [PowerDataObject],
+    /// Commands of the application, e.g. to swap the power role.
+    pub commands: Option<&'a SourceCommands>,
+}
+
+impl SourcePolicy for SourceConfig<'_> {
+    fn source_capabilities(&self, pdos: &mut [PowerDataObject]) -> usize {
+        let n = self.capabilities.len().min(pdos.len());
+        pdos[..n].copy_from_slice(&self.capabilities[..n]);
+        n
+    }
+
+    fn evaluate(&mut self, pdo: &PowerDataObject, request: &Selection) -> Evaluation {
+        let max_current_ma = match (*pdo, request.pps_voltage_mv) {
+            (PowerDataObject::FixedSupply(pdo), None) => pdo.max_current_ma(),
+            (PowerDataObject::VariableSupply(pdo), None) => pdo.max_current_ma(),
+            (PowerDataObject::Pps(pdo), Some(voltage_mv))
+                if (pdo.min_voltage_mv()..=pdo.max_voltage_mv()).contains(&voltage_mv) =>
+            {
+                pdo.max_current_ma()
+            }
+            _ => return Evaluation::Reject,
+        };
+        // The maximum may only be exceeded when the sink reports a mismatch.
+        if request.operating_current_ma > max_current_ma
+            || (request.max_operating_current_ma > max_current_ma && !request.capability_mismatch)
+        {
+            return Evaluation::Reject;
+        }
+        Evaluation::Accept
+    }
+
+    async fn command(&mut self) -> SourceCommand {
+        match self.commands {
+            Some(commands) => commands.receive().await,
+            None => pending().await,
+        }
+    }
+}
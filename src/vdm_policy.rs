@@ -0,0 +1,282 @@
+//! Answers to the discovery commands of structured Vendor Defined Messages,
+//! entry into the DisplayPort alternate mode, structured VDMs sent by the
+//! application and handling of unstructured Vendor Defined Messages.
+
+use core::fmt;
+use core::future::pending;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::mutex::Mutex;
+use embassy_sync::signal::Signal;
+
+use crate::phy::Sop;
+use crate::protocol::displayport::{PinAssignment, PinAssignments};
+use crate::protocol::{StructuredVdmHeader, UnstructuredVdmHeader, VdmCommandType};
+
+/// Provides the identity, SVIDs and modes reported to a port partner that
+/// sends Discover Identity, Discover SVIDs and Discover Modes.
+///
+/// Commands for which nothing is reported are answered with NAK.
+///
+/// As DFP the port enters the DisplayPort alternate mode of the port partner
+/// when [`displayport_pin_assignments`](Self::displayport_pin_assignments)
+/// is not empty.
+#[allow(async_fn_in_trait)]
+pub trait VdmPolicy {
+    /// Writes the VDOs of the Discover Identity response, starting with the
+    /// ID Header VDO, into `vdos` and returns how many were written.
+    fn discover_identity(&self, _vdos: &mut [u32]) -> usize {
+        0
+    }
+
+    /// Writes the supported SVIDs into `svids` and returns how many were
+    /// written.
+    fn discover_svids(&self, _svids: &mut [u16]) -> usize {
+        0
+    }
+
+    /// Writes the mode VDOs of `svid` into `modes` and returns how many were
+    /// written.
+    fn discover_modes(&self, _svid: u16, _modes: &mut [u32]) -> usize {
+        0
+    }
+
+    /// Pin assignments the port supports as DFP_D, none by default.
+    fn displayport_pin_assignments(&self) -> PinAssignments {
+        PinAssignments::from(0)
+    }
+
+    /// Called when the port partner accepted DP Configure, e.g. to switch
+    /// the mux to `pin_assignment`, or with `None` when the mode was exited
+    /// and the mux must return to USB.
+    fn displayport_configured(&mut self, _pin_assignment: Option<PinAssignment>) {}
+
+    /// Called with the HPD state reported by the UFP_D, e.g. to drive the HPD
+    /// input of the DisplayPort source.
+    fn displayport_hpd(&mut self, _hpd_state: bool, _irq_hpd: bool) {}
+
+    /// Resolves with the next structured VDM to send, which is done once the
+    /// port is in an explicit contract. Never resolves by default.
+    async fn request(&mut self) -> VdmRequest {
+        pending().await
+    }
+
+    /// Called with the outcome of the last [`request`](Self::request).
+    fn response(&mut self, _response: Result<VdmResponse, VdmError>) {}
+}
+
+/// Structured VDM sent on behalf of the application.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct VdmRequest {
+    pub sop: Sop,
+    pub header: StructuredVdmHeader,
+    vdos: [u32; 6],
+    num_vdos: usize,
+}
+
+impl VdmRequest {
+    /// Request with `header` and up to 6 `vdos` for `sop`.
+    pub fn new(sop: Sop, header: StructuredVdmHeader, vdos: &[u32]) -> Self {
+        let mut request = Self {
+            sop,
+            header,
+            vdos: [0; 6],
+            num_vdos: vdos.len().min(6),
+        };
+        request.vdos[..request.num_vdos].copy_from_slice(&vdos[..request.num_vdos]);
+        request
+    }
+
+    pub fn vdos(&self) -> &[u32] {
+        &self.vdos[..self.num_vdos]
+    }
+}
+
+/// ACK of the recipient to a [`VdmRequest`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct VdmResponse {
+    pub header: StructuredVdmHeader,
+    vdos: [u32; 6],
+    num_vdos: usize,
+}
+
+impl VdmResponse {
+    pub(crate) fn new(header: StructuredVdmHeader, vdos: &[u32]) -> Self {
+        let VdmRequest { vdos, num_vdos, .. } = VdmRequest::new(Sop::Sop, header, vdos);
+        Self {
+            header,
+            vdos,
+            num_vdos,
+        }
+    }
+
+    pub fn vdos(&self) -> &[u32] {
+        &self.vdos[..self.num_vdos]
+    }
+}
+
+/// Reason a [`VdmRequest`] was not acknowledged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum VdmError {
+    /// The recipient does not support the command.
+    Nak,
+    /// The recipient is not able to respond now, the request may be repeated
+    /// later.
+    Busy,
+    /// No response within tVDMSenderResponse, also when the recipient did
+    /// not acknowledge the message or the port was reset or detached
+    /// meanwhile.
+    Timeout,
+}
+
+impl VdmError {
+    /// Error for a response with `command_type`, `None` for an ACK.
+    pub(crate) fn from_command_type(command_type: VdmCommandType) -> Option<Self> {
+        match command_type {
+            VdmCommandType::Ack => None,
+            VdmCommandType::Busy => Some(Self::Busy),
+            _ => Some(Self::Nak),
+        }
+    }
+}
+
+/// Structured VDMs of the application, sent by a running port whose
+/// [`VdmPolicy`] forwards them, see [`VdmConfig::requests`].
+pub struct VdmRequests {
+    /// Held by the application for the duration of a request.
+    busy: Mutex<CriticalSectionRawMutex, ()>,
+    requests: Channel<CriticalSectionRawMutex, VdmRequest, 1>,
+    responses: Signal<CriticalSectionRawMutex, Result<VdmResponse, VdmError>>,
+}
+
+impl VdmRequests {
+    pub const fn new() -> Self {
+        Self {
+            busy: Mutex::new(()),
+            requests: Channel::new(),
+            responses: Signal::new(),
+        }
+    }
+
+    /// Sends `request` once the port is in an explicit contract and returns
+    /// the response. Concurrent requests are sent one after the other.
+    pub async fn request(&self, request: VdmRequest) -> Result<VdmResponse, VdmError> {
+        let _busy = self.busy.lock().await;
+        self.responses.reset();
+        self.requests.send(request).await;
+        self.responses.wait().await
+    }
+
+    /// Waits for the next request of the application.
+    pub async fn receive(&self) -> VdmRequest {
+        self.requests.receive().await
+    }
+
+    /// Passes the outcome of the last received request to the application.
+    pub fn respond(&self, response: Result<VdmResponse, VdmError>) {
+        self.responses.signal(response);
+    }
+}
+
+impl Default for VdmRequests {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for VdmRequests {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VdmRequests").finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for VdmRequests {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "VdmRequests")
+    }
+}
+
+/// Modes supported for a Standard or Vendor ID.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SvidModes<'a> {
+    pub svid: u16,
+    pub modes: &'a [u32],
+}
+
+/// Static identity and modes of a device.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct VdmConfig<'a> {
+    /// VDOs of the Discover Identity response, starting with the ID Header
+    /// VDO. Empty to NAK Discover Identity.
+    pub identity: &'a [u32],
+    pub svids: &'a [SvidModes<'a>],
+    /// Structured VDMs of the application.
+    pub requests: Option<&'a VdmRequests>,
+}
+
+impl VdmPolicy for VdmConfig<'_> {
+    fn discover_identity(&self, vdos: &mut [u32]) -> usize {
+        let n = self.identity.len().min(vdos.len());
+        vdos[..n].copy_from_slice(&self.identity[..n]);
+        n
+    }
+
+    fn discover_svids(&self, svids: &mut [u16]) -> usize {
+        for (dst, src) in svids.iter_mut().zip(self.svids) {
+            *dst = src.svid;
+        }
+        self.svids.len().min(svids.len())
+    }
+
+    fn discover_modes(&self, svid: u16, modes: &mut [u32]) -> usize {
+        let Some(svid_modes) = self.svids.iter().find(|s| s.svid == svid) else {
+            return 0;
+        };
+        let n = svid_modes.modes.len().min(modes.len());
+        modes[..n].copy_from_slice(&svid_modes.modes[..n]);
+        n
+    }
+
+    async fn request(&mut self) -> VdmRequest {
+        match self.requests {
+            Some(requests) => requests.receive().await,
+            None => pending().await,
+        }
+    }
+
+    fn response(&mut self, response: Result<VdmResponse, VdmError>) {
+        if let Some(requests) = self.requests {
+            requests.respond(response);
+        }
+    }
+}
+
+/// Handles the unstructured Vendor Defined Messages of one vendor, see
+/// [`PolicyEngine::with_unstructured_vdm`](crate::policy_engine::PolicyEngine::with_unstructured_vdm).
+pub trait UnstructuredVdmHandler {
+    /// Called with the header and the VDOs of an unstructured VDM received
+    /// from the port partner.
+    ///
+    /// Writes the reply, starting with its header, into `reply` and returns
+    /// the number of objects written, or 0 to not reply.
+    fn handle(&mut self, header: UnstructuredVdmHeader, vdos: &[u32], reply: &mut [u32]) -> usize;
+}
+
+/// Placeholder for ports without Vendor Defined Message support, NAKs all
+/// discovery commands and handles no unstructured VDMs.
+pub struct NoVdm;
+
+impl VdmPolicy for NoVdm {}
+
+impl UnstructuredVdmHandler for NoVdm {
+    fn handle(&mut self, _: UnstructuredVdmHeader, _: &[u32], _: &mut [u32]) -> usize {
+        0
+    }
+}
@@ -0,0 +1,79 @@
+mod common;
+
+use common::partner::PDO_5V_3A;
+use common::{run_sink, run_sink_with};
+use embassy_time::Duration;
+use usb_pd::protocol::*;
+use usb_pd::protocol_engine::HardReset;
+use usb_pd::sink_policy::{PowerRange, Preference, SinkConfig};
+
+const PDO_9V_3A: u32 = (180 << 10) | 300;
+
+/// BIST Data Object requesting BIST Carrier Mode 2.
+const BIST_CARRIER_MODE: u32 = 0b0101 << 28;
+
+/// BIST Data Object requesting BIST Test Data.
+const BIST_TEST_DATA: u32 = 0b1000 << 28;
+
+#[test]
+fn carrier_mode() {
+    run_sink(|mut source| async move {
+        source.negotiate(&[PDO_5V_3A]).await;
+        source
+            .send_data(DataMessageType::Bist, &[BIST_CARRIER_MODE])
+            .await;
+        source.expect_nothing(Duration::from_millis(70)).await;
+        let duration = source.take_bist_carrier().unwrap();
+        assert!(duration >= Duration::from_millis(30));
+        assert!(duration <= Duration::from_millis(60));
+
+        // The sink communicates again after the carrier.
+        source.send_control(ControlMessageType::GetSinkCap).await;
+        source.expect_data(DataMessageType::SinkCapabilities).await;
+    })
+    .unwrap();
+}
+
+#[test]
+fn test_data_mode_ignores_messages() {
+    let result = run_sink(|mut source| async move {
+        source.negotiate(&[PDO_5V_3A]).await;
+        source
+            .send_data(DataMessageType::Bist, &[BIST_TEST_DATA, 1, 2, 3, 4, 5, 6])
+            .await;
+        source
+            .send_data(DataMessageType::Bist, &[BIST_TEST_DATA, 1, 2, 3, 4, 5, 6])
+            .await;
+        source.send_control(ControlMessageType::GetSinkCap).await;
+        source.send_control(ControlMessageType::SoftReset).await;
+        source
+            .send_data(DataMessageType::SourceCapabilites, &[PDO_5V_3A])
+            .await;
+        source.expect_nothing(Duration::from_millis(100)).await;
+
+        source.send_hard_reset().await;
+        core::future::pending::<()>().await;
+    });
+    assert!(matches!(result, Err(HardReset)));
+}
+
+#[test]
+fn bist_is_ignored_above_vsafe5v() {
+    let config = SinkConfig {
+        ranges: &[PowerRange::fixed(9000, 1500)],
+        preference: Preference::HighestPower,
+        pps: None,
+        commands: None,
+        give_back_current_ma: None,
+    };
+    run_sink_with(config, |mut source| async move {
+        let request = source.negotiate(&[PDO_5V_3A, PDO_9V_3A]).await;
+        assert_eq!(request.object_position().value(), 2);
+        source
+            .send_data(DataMessageType::Bist, &[BIST_CARRIER_MODE])
+            .await;
+        source.expect_nothing(Duration::from_millis(70)).await;
+        assert_eq!(source.take_bist_carrier(), None);
+    })
+    .unwrap();
+}
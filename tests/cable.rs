@@ -0,0 +1,167 @@
+mod common;
+
+use common::supply::SimSupply;
+use common::{run, Source};
+use usb_pd::policy_engine::PolicyEngine;
+use usb_pd::protocol::source_capabilities::*;
+use usb_pd::protocol::*;
+use usb_pd::source_policy::SourceConfig;
+
+/// ID Header VDO of a passive cable.
+const ID_HEADER_PASSIVE_CABLE: u32 = 0b011 << 27;
+
+/// Cable VDO of a passive USB 3.2 Gen 2 cable rated for 20V and 5A.
+const CABLE_20V_5A: u32 = (0b10 << 5) | 0b010;
+
+/// Cable VDO of a passive USB 2.0 cable rated for 20V and 3A.
+const CABLE_20V_3A: u32 = 0b01 << 5;
+
+/// Cable VDO of a passive USB 2.0 cable rated for 30V and 5A.
+const CABLE_30V_5A: u32 = (0b01 << 9) | (0b10 << 5);
+
+/// Cable VDO of a passive USB 2.0 cable rated for 40V and 5A.
+const CABLE_40V_5A: u32 = (0b10 << 9) | (0b10 << 5);
+
+/// Cable VDO of a passive USB 2.0 cable rated for 50V and 5A.
+const CABLE_50V_5A: u32 = (0b11 << 9) | (0b10 << 5);
+
+fn source_config(capabilities: &[(u32, u32)]) -> SourceConfig<'static> {
+    let capabilities: Vec<_> = capabilities
+        .iter()
+        .map(|&(mv, ma)| PowerDataObject::FixedSupply(FixedSupply::from_mv_ma(mv, ma)))
+        .collect();
+    SourceConfig {
+        capabilities: capabilities.leak(),
+        commands: None,
+    }
+}
+
+/// Decodes advertised fixed supplies into voltage and current pairs.
+fn fixed_supplies(pdos: &[u32]) -> Vec<(u32, u32)> {
+    pdos.iter()
+        .map(|&pdo| match PowerDataObject::from(pdo) {
+            PowerDataObject::FixedSupply(pdo) => (pdo.voltage_mv(), pdo.max_current_ma()),
+            pdo => panic!("Unexpected {pdo:?}"),
+        })
+        .collect()
+}
+
+/// Runs a source with VCONN and returns the PDOs it advertises through
+/// `cable`.
+fn advertised_pdos(config: SourceConfig<'static>, cable: Option<u32>) -> Vec<u32> {
+    let supply = SimSupply::default();
+    let mut advertised = vec![];
+    run(
+        Source(&supply),
+        |pe| PolicyEngine::new(pe, config).with_vconn(&supply),
+        |mut sink| {
+            let advertised = &mut advertised;
+            async move {
+                sink.cable = cable.map(|cable| vec![ID_HEADER_PASSIVE_CABLE, 0, 0, cable]);
+                *advertised = sink.expect_data(DataMessageType::SourceCapabilites).await;
+            }
+        },
+    )
+    .unwrap();
+    advertised
+}
+
+/// Like [`advertised_pdos`] for fixed supplies given as voltage and current
+/// pairs.
+fn advertised_capabilities(capabilities: &[(u32, u32)], cable: Option<u32>) -> Vec<(u32, u32)> {
+    fixed_supplies(&advertised_pdos(source_config(capabilities), cable))
+}
+
+#[test]
+fn five_amp_cable_keeps_capabilities() {
+    let capabilities = [(5000, 5000), (9000, 3000), (20000, 5000)];
+    assert_eq!(
+        advertised_capabilities(&capabilities, Some(CABLE_20V_5A)),
+        capabilities
+    );
+}
+
+#[test]
+fn three_amp_cable_limits_current() {
+    let capabilities = [(5000, 5000), (9000, 3000), (20000, 5000)];
+    assert_eq!(
+        advertised_capabilities(&capabilities, Some(CABLE_20V_3A)),
+        [(5000, 3000), (9000, 3000), (20000, 3000)]
+    );
+}
+
+#[test]
+fn cable_without_e_marker_limits_current() {
+    let capabilities = [(5000, 5000), (20000, 5000)];
+    assert_eq!(
+        advertised_capabilities(&capabilities, None),
+        [(5000, 3000), (20000, 3000)]
+    );
+}
+
+#[test]
+fn capabilities_above_cable_voltage_are_removed() {
+    let capabilities = [(5000, 3000), (28000, 5000), (20000, 5000)];
+    assert_eq!(
+        advertised_capabilities(&capabilities, Some(CABLE_20V_5A)),
+        [(5000, 3000), (20000, 5000)]
+    );
+    assert_eq!(
+        advertised_capabilities(&capabilities, Some(CABLE_50V_5A)),
+        capabilities
+    );
+}
+
+#[test]
+fn adjustable_supplies_are_limited_to_cable_voltage() {
+    let capabilities = [
+        PowerDataObject::FixedSupply(FixedSupply::from_mv_ma(5000, 3000)),
+        PowerDataObject::Pps(SprPps::from_mv_ma(3300, 21000, 5000)),
+        PowerDataObject::EprAvs(EprAvs::from_mv_w(15000, 48000, 140)),
+    ];
+    // The PPS range ends below 30V.
+    let limited_to = |max_voltage_mv| {
+        [
+            capabilities[0],
+            capabilities[1],
+            PowerDataObject::EprAvs(EprAvs::from_mv_w(15000, max_voltage_mv, 140)),
+        ]
+    };
+    let limited_20v_3a = [
+        capabilities[0],
+        PowerDataObject::Pps(SprPps::from_mv_ma(3300, 20000, 3000)),
+        // 3A at the minimum voltage of 15V.
+        PowerDataObject::EprAvs(EprAvs::from_mv_w(15000, 20000, 45)),
+    ];
+    for (cable, expected) in [
+        (CABLE_20V_3A, limited_20v_3a),
+        (CABLE_30V_5A, limited_to(30000)),
+        (CABLE_40V_5A, limited_to(40000)),
+        (CABLE_50V_5A, capabilities),
+    ] {
+        let config = SourceConfig {
+            capabilities: capabilities.to_vec().leak(),
+            commands: None,
+        };
+        let pdos: Vec<_> = advertised_pdos(config, Some(cable))
+            .into_iter()
+            .map(PowerDataObject::from)
+            .collect();
+        assert_eq!(pdos, expected);
+    }
+}
+
+#[test]
+fn source_without_vconn_assumes_three_amp_cable() {
+    let supply = SimSupply::default();
+    run(
+        Source(&supply),
+        |pe| PolicyEngine::new(pe, source_config(&[(5000, 5000)])),
+        |mut sink| async move {
+            sink.cable = Some(vec![ID_HEADER_PASSIVE_CABLE, 0, 0, CABLE_20V_5A]);
+            let pdos = sink.expect_data(DataMessageType::SourceCapabilites).await;
+            assert_eq!(fixed_supplies(&pdos), [(5000, 3000)]);
+        },
+    )
+    .unwrap();
+}
@@ -0,0 +1,171 @@
+mod common;
+
+use bilge::prelude::*;
+use common::partner::{Received, PDO_5V_3A};
+use common::run_sink_with;
+use usb_pd::protocol::*;
+use usb_pd::protocol_engine::HardReset;
+use usb_pd::sink_policy::{PowerRange, Preference, SinkCommand, SinkCommands, SinkConfig};
+
+const PDO_9V_3A: u32 = (180 << 10) | 300;
+
+const RANGES: [PowerRange; 2] = [PowerRange::fixed(5000, 1500), PowerRange::fixed(9000, 1500)];
+
+/// Sink able to operate from 5V and 9V, preferring 5V.
+fn config(commands: &SinkCommands) -> SinkConfig<'_> {
+    SinkConfig {
+        ranges: &RANGES,
+        preference: Preference::ExactVoltage(5000),
+        pps: None,
+        commands: Some(commands),
+        give_back_current_ma: None,
+    }
+}
+
+#[test]
+fn request_voltage() {
+    let commands = SinkCommands::new();
+    let commands = &commands;
+    run_sink_with(config(commands), |mut source| async move {
+        let request = source.negotiate(&[PDO_5V_3A, PDO_9V_3A]).await;
+        assert_eq!(request.object_position().value(), 1);
+
+        commands.send(SinkCommand::RequestVoltage(9000)).await;
+        let request = Request::from(source.expect_data(DataMessageType::Request).await[0]);
+        assert_eq!(request.object_position().value(), 2);
+        source.send_control(ControlMessageType::Accept).await;
+        source.send_control(ControlMessageType::PsRdy).await;
+
+        // The voltage is kept with new capabilities.
+        let request = source.negotiate(&[PDO_5V_3A, PDO_9V_3A]).await;
+        assert_eq!(request.object_position().value(), 2);
+
+        commands.send(SinkCommand::Renegotiate).await;
+        let request = Request::from(source.expect_data(DataMessageType::Request).await[0]);
+        assert_eq!(request.object_position().value(), 1);
+    })
+    .unwrap();
+}
+
+#[test]
+fn give_back() {
+    let commands = SinkCommands::new();
+    let commands = &commands;
+    run_sink_with(config(commands), |mut source| async move {
+        let request = source.negotiate(&[PDO_5V_3A]).await;
+        assert!(!request.give_back_flag());
+
+        commands.send(SinkCommand::GiveBack(Some(500))).await;
+        let request = Request::from(source.expect_data(DataMessageType::Request).await[0]);
+        assert!(request.give_back_flag());
+        assert_eq!(request.min_operating_current(), u10::new(50));
+        source.send_control(ControlMessageType::Accept).await;
+        source.send_control(ControlMessageType::PsRdy).await;
+        source.send_control(ControlMessageType::GotoMin).await;
+        source.send_control(ControlMessageType::PsRdy).await;
+
+        commands.send(SinkCommand::GiveBack(None)).await;
+        let request = Request::from(source.expect_data(DataMessageType::Request).await[0]);
+        assert!(!request.give_back_flag());
+        assert_eq!(request.min_operating_current(), u10::new(150));
+        source.send_control(ControlMessageType::Accept).await;
+        source.send_control(ControlMessageType::PsRdy).await;
+        source.send_control(ControlMessageType::GotoMin).await;
+        source.expect_control(ControlMessageType::Reject).await;
+    })
+    .unwrap();
+}
+
+#[test]
+fn unavailable_voltage_falls_back_to_policy() {
+    let commands = SinkCommands::new();
+    let commands = &commands;
+    run_sink_with(config(commands), |mut source| async move {
+        source.negotiate(&[PDO_5V_3A, PDO_9V_3A]).await;
+        commands.send(SinkCommand::RequestVoltage(20000)).await;
+        let request = Request::from(source.expect_data(DataMessageType::Request).await[0]);
+        assert_eq!(request.object_position().value(), 1);
+    })
+    .unwrap();
+}
+
+#[test]
+fn command_waits_for_chunked_message() {
+    let commands = SinkCommands::new();
+    let commands = &commands;
+    run_sink_with(config(commands), |mut source| async move {
+        source.revision = SpecificationRevision::Revision3_0;
+        source.negotiate(&[PDO_5V_3A, PDO_9V_3A]).await;
+
+        // The command arrives between the chunks of an extended message.
+        let message_type = ExtendedMessageType::SecurityRequest;
+        let payload: Vec<u8> = (0..40).collect();
+        let data_size = u9::new(payload.len() as u16);
+        let ext_header = ExtendedHeader::new(data_size, false, u4::new(0), true);
+        assert!(
+            source
+                .transmit_chunk(message_type, ext_header, &payload[..26])
+                .await
+        );
+        commands.send(SinkCommand::RequestVoltage(9000)).await;
+        match source.expect_any().await {
+            Received::Extended(t, request, _) if t == message_type && request.request_chunk() => {}
+            msg => panic!("Expected chunk request, received {msg:?}"),
+        }
+        let ext_header = ExtendedHeader::new(data_size, false, u4::new(1), true);
+        assert!(
+            source
+                .transmit_chunk(message_type, ext_header, &payload[26..])
+                .await
+        );
+        source
+            .expect_control(ControlMessageType::NotSupported)
+            .await;
+
+        let request = Request::from(source.expect_data(DataMessageType::Request).await[0]);
+        assert_eq!(request.object_position().value(), 2);
+    })
+    .unwrap();
+}
+
+#[test]
+fn get_source_capabilities() {
+    let commands = SinkCommands::new();
+    let commands = &commands;
+    run_sink_with(config(commands), |mut source| async move {
+        source.negotiate(&[PDO_5V_3A]).await;
+        commands.send(SinkCommand::GetSourceCapabilities).await;
+        source
+            .expect_control(ControlMessageType::GetSourceCap)
+            .await;
+        source.negotiate(&[PDO_5V_3A, PDO_9V_3A]).await;
+    })
+    .unwrap();
+}
+
+#[test]
+fn soft_reset() {
+    let commands = SinkCommands::new();
+    let commands = &commands;
+    run_sink_with(config(commands), |mut source| async move {
+        source.negotiate(&[PDO_5V_3A]).await;
+        commands.send(SinkCommand::SoftReset).await;
+        source.expect_control(ControlMessageType::SoftReset).await;
+        source.send_control(ControlMessageType::Accept).await;
+        source.negotiate(&[PDO_5V_3A]).await;
+    })
+    .unwrap();
+}
+
+#[test]
+fn hard_reset() {
+    let commands = SinkCommands::new();
+    let commands = &commands;
+    let result = run_sink_with(config(commands), |mut source| async move {
+        source.negotiate(&[PDO_5V_3A]).await;
+        commands.send(SinkCommand::HardReset).await;
+        assert_eq!(source.expect_any().await, Received::HardReset);
+        core::future::pending::<()>().await;
+    });
+    assert!(matches!(result, Err(HardReset)));
+}
@@ -0,0 +1,157 @@
+//! In-memory PHY pair connecting two ports.
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Timer};
+use usb_pd::phy::{CcPhy, DualRoleCcPhy, PdPhy, RpLevel, RxError, Sop, TxError};
+use usb_pd::protocol::PortPowerRole;
+
+enum Frame {
+    Message(Sop, Vec<u8>),
+    Corrupted,
+    HardReset,
+    /// Start of a BIST carrier lasting for the given time.
+    BistCarrier(Duration),
+}
+
+/// The wire between two [`LoopbackPhy`] instances.
+pub struct Link {
+    a_to_b: Channel<NoopRawMutex, Frame, 8>,
+    b_to_a: Channel<NoopRawMutex, Frame, 8>,
+}
+
+impl Link {
+    pub fn new() -> Self {
+        Self {
+            a_to_b: Channel::new(),
+            b_to_a: Channel::new(),
+        }
+    }
+
+    /// Returns both ends of the link.
+    pub fn split(&self) -> (LoopbackPhy<'_>, LoopbackPhy<'_>) {
+        (
+            LoopbackPhy {
+                rx: &self.b_to_a,
+                tx: &self.a_to_b,
+                bist_carrier: None,
+            },
+            LoopbackPhy {
+                rx: &self.a_to_b,
+                tx: &self.b_to_a,
+                bist_carrier: None,
+            },
+        )
+    }
+}
+
+pub struct LoopbackPhy<'a> {
+    rx: &'a Channel<NoopRawMutex, Frame, 8>,
+    tx: &'a Channel<NoopRawMutex, Frame, 8>,
+    /// Duration of the last BIST carrier seen while receiving.
+    bist_carrier: Option<Duration>,
+}
+
+impl LoopbackPhy<'_> {
+    /// Returns the duration of the BIST carrier transmitted by the other end
+    /// since the last call.
+    pub fn take_bist_carrier(&mut self) -> Option<Duration> {
+        self.bist_carrier.take()
+    }
+
+    /// Transmits a message which the other end receives with a CRC error.
+    pub async fn transmit_corrupted(&mut self) {
+        self.tx.send(Frame::Corrupted).await;
+    }
+}
+
+impl PdPhy for LoopbackPhy<'_> {
+    async fn receive(&mut self, buf: &mut [u8]) -> Result<usize, RxError> {
+        loop {
+            if let (Sop::Sop, n) = self.receive_sop(buf).await? {
+                return Ok(n);
+            }
+        }
+    }
+
+    async fn transmit(&mut self, buf: &[u8]) -> Result<(), TxError> {
+        self.transmit_sop(Sop::Sop, buf).await
+    }
+
+    async fn transmit_hard_reset(&mut self) -> Result<(), TxError> {
+        self.tx.send(Frame::HardReset).await;
+        Ok(())
+    }
+
+    async fn receive_sop(&mut self, buf: &mut [u8]) -> Result<(Sop, usize), RxError> {
+        loop {
+            match self.rx.receive().await {
+                Frame::Message(_, data) if data.len() > buf.len() => return Err(RxError::Overrun),
+                Frame::Message(sop, data) => {
+                    buf[..data.len()].copy_from_slice(&data);
+                    return Ok((sop, data.len()));
+                }
+                Frame::Corrupted => return Err(RxError::Crc),
+                Frame::HardReset => return Err(RxError::HardReset),
+                Frame::BistCarrier(duration) => self.bist_carrier = Some(duration),
+            }
+        }
+    }
+
+    async fn transmit_sop(&mut self, sop: Sop, buf: &[u8]) -> Result<(), TxError> {
+        self.tx.send(Frame::Message(sop, buf.to_vec())).await;
+        Ok(())
+    }
+
+    async fn transmit_bist_carrier(&mut self, duration: Duration) -> Result<(), TxError> {
+        self.tx.send(Frame::BistCarrier(duration)).await;
+        Timer::after(duration).await;
+        Ok(())
+    }
+}
+
+/// CC line with an Rp level controlled by the test.
+pub struct SimCc {
+    rp_level: Cell<RpLevel>,
+    changed: Signal<NoopRawMutex, RpLevel>,
+    power_role: Cell<Option<PortPowerRole>>,
+}
+
+impl SimCc {
+    pub fn new(rp_level: RpLevel) -> Self {
+        Self {
+            rp_level: Cell::new(rp_level),
+            changed: Signal::new(),
+            power_role: Cell::new(None),
+        }
+    }
+
+    /// Returns the role of the last pull resistor switch.
+    pub fn power_role(&self) -> Option<PortPowerRole> {
+        self.power_role.get()
+    }
+
+    pub fn set(&self, rp_level: RpLevel) {
+        self.rp_level.set(rp_level);
+        self.changed.signal(rp_level);
+    }
+}
+
+impl CcPhy for SimCc {
+    fn rp_level(&self) -> RpLevel {
+        self.rp_level.get()
+    }
+
+    async fn wait_for_rp_level_change(&self) -> RpLevel {
+        self.changed.wait().await
+    }
+}
+
+impl DualRoleCcPhy for &SimCc {
+    fn set_power_role(&mut self, power_role: PortPowerRole) {
+        self.power_role.set(Some(power_role));
+    }
+}
@@ -0,0 +1,236 @@
+//! Test harness to run a [`PolicyEngine`] against a simulated partner.
+#![allow(dead_code)]
+
+pub mod loopback;
+pub mod partner;
+pub mod supply;
+
+use core::future::Future;
+
+use bilge::prelude::*;
+use embassy_futures::block_on;
+use embassy_futures::select::{select, Either};
+use loopback::{Link, LoopbackPhy};
+use partner::SimPartner;
+use supply::SimSupply;
+use usb_pd::phy::{CcPhy, DualRoleCcPhy, VconnSwitch};
+use usb_pd::policy_engine::PolicyEngine;
+use usb_pd::protocol::source_capabilities::{FixedSupply, PowerDataObject};
+use usb_pd::protocol::*;
+use usb_pd::protocol_engine::{HardReset, ProtocolEngine};
+use usb_pd::sink_policy::{PowerRange, Preference, Selection, SinkCommand, SinkConfig, SinkPolicy};
+use usb_pd::source_policy::{Evaluation, SourceCommand, SourceConfig, SourcePolicy};
+use usb_pd::vdm_policy::{UnstructuredVdmHandler, VdmPolicy};
+
+/// Operating current of the sink under test.
+pub const OPERATING_CURRENT_MA: u32 = 1500;
+
+/// Sink only accepting vSafe5V.
+pub const SINK_CONFIG: SinkConfig<'static> = SinkConfig {
+    ranges: &[PowerRange::fixed(5000, OPERATING_CURRENT_MA)],
+    preference: Preference::HighestPower,
+    pps: None,
+    commands: None,
+    give_back_current_ma: None,
+};
+
+/// Policy engine of the port under test.
+pub type Port<S, C, V, M, U> = PolicyEngine<LoopbackPhy<'static>, S, C, V, M, U>;
+
+/// Power role the port under test runs in, see [`run`].
+#[allow(async_fn_in_trait)]
+pub trait Role<E> {
+    /// Power role of the simulated partner.
+    fn partner_power_role(&self) -> PortPowerRole;
+
+    /// Runs `port` until it stops.
+    async fn run(self, port: &mut E) -> Result<(), HardReset>;
+}
+
+/// Runs the port with `PolicyEngine::run_sink`.
+pub struct Sink;
+
+/// Runs the port with `PolicyEngine::run_source`, VBUS comes from the
+/// supply.
+pub struct Source<'a>(pub &'a SimSupply);
+
+/// Runs the port with `PolicyEngine::run_dual_role`, starting in the power
+/// role.
+pub struct DualRole<'a>(pub PortPowerRole, pub &'a SimSupply);
+
+impl<S, C, V, M, U> Role<Port<S, C, V, M, U>> for Sink
+where
+    S: SinkPolicy,
+    C: CcPhy,
+    V: VconnSwitch,
+    M: VdmPolicy,
+    U: UnstructuredVdmHandler,
+{
+    fn partner_power_role(&self) -> PortPowerRole {
+        PortPowerRole::Source
+    }
+
+    async fn run(self, port: &mut Port<S, C, V, M, U>) -> Result<(), HardReset> {
+        port.run_sink().await
+    }
+}
+
+impl<S, C, V, M, U> Role<Port<S, C, V, M, U>> for Source<'_>
+where
+    S: SourcePolicy,
+    C: CcPhy,
+    V: VconnSwitch,
+    M: VdmPolicy,
+    U: UnstructuredVdmHandler,
+{
+    fn partner_power_role(&self) -> PortPowerRole {
+        PortPowerRole::Sink
+    }
+
+    async fn run(self, port: &mut Port<S, C, V, M, U>) -> Result<(), HardReset> {
+        let mut supply = self.0;
+        port.run_source(&mut supply).await
+    }
+}
+
+impl<S, C, V, M, U> Role<Port<S, C, V, M, U>> for DualRole<'_>
+where
+    S: SinkPolicy + SourcePolicy,
+    C: DualRoleCcPhy,
+    V: VconnSwitch,
+    M: VdmPolicy,
+    U: UnstructuredVdmHandler,
+{
+    fn partner_power_role(&self) -> PortPowerRole {
+        match self.0 {
+            PortPowerRole::Sink => PortPowerRole::Source,
+            PortPowerRole::Source => PortPowerRole::Sink,
+        }
+    }
+
+    async fn run(self, port: &mut Port<S, C, V, M, U>) -> Result<(), HardReset> {
+        let mut supply = self.1;
+        port.run_dual_role(self.0, &mut supply).await
+    }
+}
+
+/// Runs `script` with a simulated partner against the port built by `port`
+/// from its protocol engine.
+///
+/// Returns `Ok(())` when the script finishes and the result of the port when
+/// it stops first.
+pub fn run<E, R, B, F, Fut>(role: R, port: B, script: F) -> Result<(), HardReset>
+where
+    R: Role<E>,
+    B: FnOnce(ProtocolEngine<LoopbackPhy<'static>>) -> E,
+    F: FnOnce(SimPartner<'static>) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let link: &'static Link = Box::leak(Box::new(Link::new()));
+    let (port_phy, partner_phy) = link.split();
+    let mut port = port(ProtocolEngine::new(port_phy));
+    let mut partner = SimPartner::new(partner_phy);
+    if role.partner_power_role() == PortPowerRole::Sink {
+        partner.power_role = PortPowerRole::Sink;
+        partner.data_role = PortDataRole::UpstreamFacingPort;
+    }
+    block_on(async {
+        match select(role.run(&mut port), script(partner)).await {
+            Either::First(result) => result,
+            Either::Second(()) => Ok(()),
+        }
+    })
+}
+
+/// Source offering `capabilities`.
+pub fn source_config(capabilities: &[PowerDataObject]) -> SourceConfig<'static> {
+    SourceConfig {
+        capabilities: capabilities.to_vec().leak(),
+        commands: None,
+    }
+}
+
+/// Source only offering vSafe5V at 1.5A.
+pub fn vsafe5v_source_config() -> SourceConfig<'static> {
+    source_config(&[PowerDataObject::FixedSupply(FixedSupply::from_mv_ma(
+        5000, 1500,
+    ))])
+}
+
+/// Request for the 5V capability at 1A.
+pub fn request_5v() -> u32 {
+    let current = u10::new(100);
+    Request::new(
+        current,
+        current,
+        u4::new(0),
+        false,
+        false,
+        false,
+        false,
+        u3::new(1),
+        false,
+    )
+    .into()
+}
+
+/// Runs `script` with a simulated source against the sink policy engine.
+pub fn run_sink<F, Fut>(script: F) -> Result<(), HardReset>
+where
+    F: FnOnce(SimPartner<'static>) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    run_sink_with(SINK_CONFIG, script)
+}
+
+/// Like [`run_sink`] but with a custom sink configuration.
+pub fn run_sink_with<F, Fut>(config: SinkConfig<'_>, script: F) -> Result<(), HardReset>
+where
+    F: FnOnce(SimPartner<'static>) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    run(Sink, |pe| PolicyEngine::new(pe, config), script)
+}
+
+/// Dual-role policy combining a sink and a source configuration.
+pub struct DualRoleConfig<'a> {
+    pub sink: SinkConfig<'a>,
+    pub source: SourceConfig<'a>,
+    pub accept_power_role_swap: bool,
+}
+
+impl SinkPolicy for DualRoleConfig<'_> {
+    fn select(&mut self, source_capabilities: &[PowerDataObject]) -> Selection {
+        self.sink.select(source_capabilities)
+    }
+
+    fn sink_capabilities(&self, pdos: &mut [u32]) -> usize {
+        self.sink.sink_capabilities(pdos)
+    }
+
+    fn accept_swap_to_source(&mut self) -> bool {
+        self.accept_power_role_swap
+    }
+
+    async fn command(&mut self) -> SinkCommand {
+        SinkPolicy::command(&mut self.sink).await
+    }
+}
+
+impl SourcePolicy for DualRoleConfig<'_> {
+    fn source_capabilities(&self, pdos: &mut [PowerDataObject]) -> usize {
+        self.source.source_capabilities(pdos)
+    }
+
+    fn evaluate(&mut self, pdo: &PowerDataObject, request: &Selection) -> Evaluation {
+        self.source.evaluate(pdo, request)
+    }
+
+    fn accept_swap_to_sink(&mut self) -> bool {
+        self.accept_power_role_swap
+    }
+
+    async fn command(&mut self) -> SourceCommand {
+        SourcePolicy::command(&mut self.source).await
+    }
+}
@@ -0,0 +1,433 @@
+//! Scriptable USB PD port partner on the other end of a loopback link.
+
+use bilge::prelude::*;
+use embassy_time::{with_timeout, Duration};
+use usb_pd::phy::{PdPhy, RxError, Sop};
+use usb_pd::protocol::*;
+
+use super::loopback::LoopbackPhy;
+
+/// Time to wait for a GoodCRC message.
+const TIMEOUT_GOODCRC: Duration = Duration::from_millis(3);
+
+/// Time after which a test fails when the port does not send anything.
+const TIMEOUT_EXPECT: Duration = Duration::from_secs(1);
+
+/// Maximum payload size of a single chunk.
+const MAX_CHUNK_LEN: usize = 26;
+
+/// Fixed 5V 3A supply.
+pub const PDO_5V_3A: u32 = (100 << 10) | 300;
+
+#[derive(Debug, PartialEq)]
+pub enum Received {
+    Control(ControlMessageType),
+    Data(DataMessageType, Vec<u32>),
+    /// Single chunk of an extended message including padding.
+    Extended(ExtendedMessageType, ExtendedHeader, Vec<u8>),
+    HardReset,
+}
+
+pub struct SimPartner<'a> {
+    phy: LoopbackPhy<'a>,
+    /// Power role used in transmitted headers.
+    pub power_role: PortPowerRole,
+    /// Data role used in transmitted headers.
+    pub data_role: PortDataRole,
+    /// Specification revision used in transmitted headers.
+    pub revision: SpecificationRevision,
+    /// Message ID of the next transmitted message.
+    pub tx_message_id: u3,
+    /// Header of the last message received from the port.
+    pub rx_header: Option<Header>,
+    /// Discover Identity response of the e-marker in the cable, without the
+    /// VDM header. SOP' messages are not acknowledged when `None`.
+    pub cable: Option<Vec<u32>>,
+    cable_tx_message_id: u3,
+    drop_goodcrc: usize,
+    corrupt: usize,
+}
+
+impl<'a> SimPartner<'a> {
+    /// Creates a partner acting as source.
+    pub fn new(phy: LoopbackPhy<'a>) -> Self {
+        Self {
+            phy,
+            power_role: PortPowerRole::Source,
+            data_role: PortDataRole::DownstreamFacingPort,
+            revision: SpecificationRevision::Revision2_0,
+            tx_message_id: u3::new(0),
+            rx_header: None,
+            cable: None,
+            cable_tx_message_id: u3::new(0),
+            drop_goodcrc: 0,
+            corrupt: 0,
+        }
+    }
+
+    /// Returns the duration of the BIST carrier the port transmitted since the
+    /// last call.
+    pub fn take_bist_carrier(&mut self) -> Option<Duration> {
+        self.phy.take_bist_carrier()
+    }
+
+    /// Do not answer the next `n` received messages with GoodCRC.
+    pub fn drop_goodcrc(&mut self, n: usize) {
+        self.drop_goodcrc = n;
+    }
+
+    /// Transmit the next `n` messages with an invalid CRC.
+    pub fn corrupt(&mut self, n: usize) {
+        self.corrupt = n;
+    }
+
+    fn header(&self, message_type: u5, num_objects: usize) -> Header {
+        Header::new(
+            message_type,
+            self.data_role,
+            self.revision,
+            self.power_role,
+            self.tx_message_id,
+            u3::new(num_objects as u8),
+            false,
+        )
+    }
+
+    /// Transmits a message with retries and returns `true` when it was
+    /// acknowledged with a GoodCRC.
+    pub async fn transmit(&mut self, message_type: u5, objects: &[u32]) -> bool {
+        let header = self.header(message_type, objects.len());
+        self.transmit_with_header(header, objects).await
+    }
+
+    async fn transmit_with_header(&mut self, header: Header, objects: &[u32]) -> bool {
+        let mut buf = u16::from(header).to_le_bytes().to_vec();
+        for obj in objects {
+            buf.extend_from_slice(&obj.to_le_bytes());
+        }
+
+        let mut ok = false;
+        let retry_count = match self.revision {
+            SpecificationRevision::Revision3_0 => 2,
+            _ => 3,
+        };
+        for _retry in 0..=retry_count {
+            if self.corrupt > 0 {
+                self.corrupt -= 1;
+                self.phy.transmit_corrupted().await;
+            } else {
+                self.phy.transmit(&buf).await.unwrap();
+            }
+
+            let mut goodcrc_buf = [0_u8; 2];
+            if let Ok(Ok(2)) =
+                with_timeout(TIMEOUT_GOODCRC, self.phy.receive(&mut goodcrc_buf)).await
+            {
+                let goodcrc = Header::from(u16::from_le_bytes(goodcrc_buf));
+                if goodcrc.message_type() == ControlMessageType::GoodCRC.into()
+                    && goodcrc.number_of_data_objects() == u3::new(0)
+                    && goodcrc.message_id() == self.tx_message_id
+                {
+                    ok = true;
+                    break;
+                }
+            }
+        }
+
+        self.tx_message_id = self.tx_message_id.wrapping_add(u3::new(1));
+        ok
+    }
+
+    pub async fn send_control(&mut self, message_type: ControlMessageType) {
+        if message_type == ControlMessageType::SoftReset {
+            self.tx_message_id = u3::new(0);
+            self.rx_header = None;
+        }
+        assert!(
+            self.transmit(message_type.into(), &[]).await,
+            "{message_type:?} not acknowledged"
+        );
+    }
+
+    pub async fn send_data(&mut self, message_type: DataMessageType, objects: &[u32]) {
+        assert!(
+            self.transmit(message_type.into(), objects).await,
+            "{message_type:?} not acknowledged"
+        );
+    }
+
+    /// Transmits a single chunk of an extended message.
+    pub async fn transmit_chunk(
+        &mut self,
+        message_type: ExtendedMessageType,
+        ext_header: ExtendedHeader,
+        data: &[u8],
+    ) -> bool {
+        let mut bytes = u16::from(ext_header).to_le_bytes().to_vec();
+        bytes.extend_from_slice(data);
+        bytes.resize(bytes.len().div_ceil(4) * 4, 0);
+        let objects: Vec<u32> = bytes
+            .chunks_exact(4)
+            .map(|obj| u32::from_le_bytes(obj.try_into().unwrap()))
+            .collect();
+        let mut header = self.header(message_type.into(), objects.len());
+        header.set_extended(true);
+        self.transmit_with_header(header, &objects).await
+    }
+
+    /// Sends an extended message in chunks and answers the chunk requests.
+    pub async fn send_extended(&mut self, message_type: ExtendedMessageType, payload: &[u8]) {
+        for (i, chunk) in payload.chunks(MAX_CHUNK_LEN).enumerate() {
+            if i > 0 {
+                match self.expect_any().await {
+                    Received::Extended(t, request, _)
+                        if t == message_type
+                            && request.request_chunk()
+                            && usize::from(request.chunk_number().value()) == i => {}
+                    msg => panic!("Expected chunk request {i}, received {msg:?}"),
+                }
+            }
+            let ext_header =
+                ExtendedHeader::new(u9::new(payload.len() as u16), false, u4::new(i as u8), true);
+            assert!(
+                self.transmit_chunk(message_type, ext_header, chunk).await,
+                "{message_type:?} chunk {i} not acknowledged"
+            );
+        }
+    }
+
+    /// Receives all chunks of an extended message from the port.
+    pub async fn expect_extended(&mut self, message_type: ExtendedMessageType) -> Vec<u8> {
+        let mut payload = Vec::new();
+        for i in 0.. {
+            let (ext_header, data) = match self.expect_any().await {
+                Received::Extended(t, ext_header, data) if t == message_type => (ext_header, data),
+                msg => panic!("Expected {message_type:?}, received {msg:?}"),
+            };
+            assert!(ext_header.chunked() && !ext_header.request_chunk());
+            assert_eq!(ext_header.chunk_number().value(), i);
+
+            let data_size = usize::from(ext_header.data_size().value());
+            let len = (data_size - payload.len()).min(MAX_CHUNK_LEN);
+            payload.extend_from_slice(&data[..len]);
+            if payload.len() == data_size {
+                break;
+            }
+
+            let request = ExtendedHeader::new(u9::new(0), true, u4::new(i + 1), true);
+            assert!(self.transmit_chunk(message_type, request, &[]).await);
+        }
+        payload
+    }
+
+    pub async fn send_hard_reset(&mut self) {
+        self.phy.transmit_hard_reset().await.unwrap();
+        self.tx_message_id = u3::new(0);
+        self.rx_header = None;
+        self.cable_tx_message_id = u3::new(0);
+    }
+
+    /// Receives the next message from the port and answers it with GoodCRC.
+    pub async fn receive(&mut self) -> Received {
+        loop {
+            let mut buf = [0_u8; 30];
+            let n = match self.phy.receive_sop(&mut buf).await {
+                Ok((Sop::Sop, n)) => n,
+                Ok((sop, n)) => {
+                    self.respond_as_cable(sop, &buf[..n]).await;
+                    continue;
+                }
+                Err(RxError::HardReset) => {
+                    self.tx_message_id = u3::new(0);
+                    self.rx_header = None;
+                    self.cable_tx_message_id = u3::new(0);
+                    return Received::HardReset;
+                }
+                Err(err) => panic!("Port transmitted invalid message: {err:?}"),
+            };
+
+            let header = Header::from(u16::from_le_bytes([buf[0], buf[1]]));
+            let num_objects = usize::from(header.number_of_data_objects().value());
+            assert_eq!(n, 2 + 4 * num_objects, "Invalid message length");
+            if num_objects == 0 && header.message_type() == ControlMessageType::GoodCRC.into() {
+                // Late GoodCRC for a message we already gave up on.
+                continue;
+            }
+
+            if self.drop_goodcrc > 0 {
+                self.drop_goodcrc -= 1;
+            } else {
+                let mut goodcrc = self.header(ControlMessageType::GoodCRC.into(), 0);
+                goodcrc.set_message_id(header.message_id());
+                self.phy
+                    .transmit(&u16::from(goodcrc).to_le_bytes())
+                    .await
+                    .unwrap();
+            }
+            self.rx_header = Some(header);
+
+            if header.extended() {
+                let ext_header = ExtendedHeader::from(u16::from_le_bytes([buf[2], buf[3]]));
+                return Received::Extended(
+                    ExtendedMessageType::from(header.message_type()),
+                    ext_header,
+                    buf[4..n].to_vec(),
+                );
+            }
+            if num_objects == 0 {
+                let message_type = ControlMessageType::from(header.message_type());
+                if message_type == ControlMessageType::SoftReset {
+                    self.tx_message_id = u3::new(0);
+                }
+                return Received::Control(message_type);
+            }
+            let objects = buf[2..n]
+                .chunks_exact(4)
+                .map(|obj| u32::from_le_bytes(obj.try_into().unwrap()))
+                .collect();
+            return Received::Data(DataMessageType::from(header.message_type()), objects);
+        }
+    }
+
+    /// Answers a message sent to the cable plug when the cable has an e-marker.
+    async fn respond_as_cable(&mut self, sop: Sop, buf: &[u8]) {
+        let Some(vdos) = self.cable.clone() else {
+            return;
+        };
+        assert_eq!(sop, Sop::SopPrime, "Port talked to the far end cable plug");
+        let header = Header::from(u16::from_le_bytes([buf[0], buf[1]]));
+        assert!(!header.cable_plug(), "Port sent a message as cable plug");
+
+        let mut goodcrc = header;
+        goodcrc.set_message_type(ControlMessageType::GoodCRC.into());
+        goodcrc.set_number_of_data_objects(u3::new(0));
+        goodcrc.set_cable_plug(true);
+        self.phy
+            .transmit_sop(sop, &u16::from(goodcrc).to_le_bytes())
+            .await
+            .unwrap();
+        if header.message_type() != DataMessageType::VendorDefined.into() || buf.len() < 6 {
+            return;
+        }
+
+        let mut vdm_header =
+            StructuredVdmHeader::from(u32::from_le_bytes(buf[2..6].try_into().unwrap()));
+        let mut objects = vec![];
+        if vdm_header.svid() == PD_SID && vdm_header.command() == VdmCommand::DiscoverIdentity {
+            vdm_header.set_command_type(VdmCommandType::Ack);
+            objects.push(u32::from(vdm_header));
+            objects.extend_from_slice(&vdos);
+        } else {
+            vdm_header.set_command_type(VdmCommandType::Nak);
+            objects.push(u32::from(vdm_header));
+        }
+
+        let mut response = Header::new(
+            DataMessageType::VendorDefined.into(),
+            PortDataRole::UpstreamFacingPort,
+            header.specification_revision(),
+            PortPowerRole::Sink,
+            self.cable_tx_message_id,
+            u3::new(objects.len() as u8),
+            false,
+        );
+        response.set_cable_plug(true);
+        self.cable_tx_message_id = self.cable_tx_message_id.wrapping_add(u3::new(1));
+        let mut buf = u16::from(response).to_le_bytes().to_vec();
+        for obj in objects {
+            buf.extend_from_slice(&obj.to_le_bytes());
+        }
+        self.phy.transmit_sop(sop, &buf).await.unwrap();
+
+        let mut goodcrc_buf = [0_u8; 2];
+        match with_timeout(TIMEOUT_GOODCRC, self.phy.receive_sop(&mut goodcrc_buf)).await {
+            Ok(Ok((Sop::SopPrime, 2))) => {
+                let goodcrc = Header::from(u16::from_le_bytes(goodcrc_buf));
+                assert_eq!(goodcrc.message_type(), ControlMessageType::GoodCRC.into());
+                assert_eq!(goodcrc.message_id(), response.message_id());
+            }
+            rx => panic!("Expected GoodCRC from port, received {rx:?}"),
+        }
+    }
+
+    /// Receives the next message and fails the test if it does not arrive in time.
+    pub async fn expect_any(&mut self) -> Received {
+        with_timeout(TIMEOUT_EXPECT, self.receive())
+            .await
+            .expect("Timeout waiting for message from port")
+    }
+
+    pub async fn expect_control(&mut self, message_type: ControlMessageType) {
+        assert_eq!(self.expect_any().await, Received::Control(message_type));
+    }
+
+    pub async fn expect_data(&mut self, message_type: DataMessageType) -> Vec<u32> {
+        match self.expect_any().await {
+            Received::Data(t, objects) if t == message_type => objects,
+            msg => panic!("Expected {message_type:?}, received {msg:?}"),
+        }
+    }
+
+    /// Receives a structured VDM and returns its header and VDOs.
+    pub async fn expect_vdm(&mut self) -> (StructuredVdmHeader, Vec<u32>) {
+        let objects = self.expect_data(DataMessageType::VendorDefined).await;
+        (StructuredVdmHeader::from(objects[0]), objects[1..].to_vec())
+    }
+
+    /// Sends a structured VDM request and returns the response of the port.
+    pub async fn vdm_request(
+        &mut self,
+        svid: u16,
+        command: VdmCommand,
+    ) -> (StructuredVdmHeader, Vec<u32>) {
+        let header = StructuredVdmHeader::request(svid, command, self.revision);
+        self.send_data(DataMessageType::VendorDefined, &[header.into()])
+            .await;
+        self.expect_vdm().await
+    }
+
+    /// Answers the structured VDM `request` of the port.
+    pub async fn send_vdm_response(
+        &mut self,
+        request: StructuredVdmHeader,
+        command_type: VdmCommandType,
+        vdos: &[u32],
+    ) {
+        let mut header = request;
+        header.set_command_type(command_type);
+        let mut objects = vec![header.into()];
+        objects.extend_from_slice(vdos);
+        self.send_data(DataMessageType::VendorDefined, &objects)
+            .await;
+    }
+
+    /// Asserts that the port stays silent for `duration`.
+    pub async fn expect_nothing(&mut self, duration: Duration) {
+        if let Ok(msg) = with_timeout(duration, self.receive()).await {
+            panic!("Expected no message, received {msg:?}");
+        }
+    }
+
+    /// Advertises `pdos` and returns the request objects received in response.
+    pub async fn send_source_capabilities(&mut self, pdos: &[u32]) -> Request {
+        self.send_data(DataMessageType::SourceCapabilites, pdos)
+            .await;
+        let objects = self.expect_data(DataMessageType::Request).await;
+        assert_eq!(objects.len(), 1);
+        Request::from(objects[0])
+    }
+
+    /// Sends a request as sink and returns the response of the source.
+    pub async fn request(&mut self, request: u32) -> Received {
+        self.send_data(DataMessageType::Request, &[request]).await;
+        self.expect_any().await
+    }
+
+    /// Runs a complete power negotiation and accepts the request.
+    pub async fn negotiate(&mut self, pdos: &[u32]) -> Request {
+        let request = self.send_source_capabilities(pdos).await;
+        self.send_control(ControlMessageType::Accept).await;
+        self.send_control(ControlMessageType::PsRdy).await;
+        request
+    }
+}
@@ -0,0 +1,55 @@
+//! Power supply recording the VBUS and VCONN changes requested by a port.
+
+use core::cell::RefCell;
+
+use usb_pd::phy::VconnSwitch;
+use usb_pd::protocol::source_capabilities::PowerDataObject;
+use usb_pd::sink_policy::Selection;
+use usb_pd::source_policy::PowerSupply;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SupplyEvent {
+    Transition(Selection),
+    Disable,
+    Enable,
+    VconnDisable,
+    VconnEnable,
+}
+
+#[derive(Default)]
+pub struct SimSupply {
+    events: RefCell<Vec<SupplyEvent>>,
+}
+
+impl SimSupply {
+    /// Returns the events since the last call.
+    pub fn take_events(&self) -> Vec<SupplyEvent> {
+        self.events.take()
+    }
+}
+
+impl PowerSupply for &SimSupply {
+    async fn transition(&mut self, _pdo: &PowerDataObject, request: &Selection) {
+        self.events
+            .borrow_mut()
+            .push(SupplyEvent::Transition(*request));
+    }
+
+    async fn disable(&mut self) {
+        self.events.borrow_mut().push(SupplyEvent::Disable);
+    }
+
+    async fn enable(&mut self) {
+        self.events.borrow_mut().push(SupplyEvent::Enable);
+    }
+}
+
+impl VconnSwitch for &SimSupply {
+    async fn enable(&mut self) {
+        self.events.borrow_mut().push(SupplyEvent::VconnEnable);
+    }
+
+    async fn disable(&mut self) {
+        self.events.borrow_mut().push(SupplyEvent::VconnDisable);
+    }
+}
@@ -0,0 +1,227 @@
+mod common;
+
+use core::cell::Cell;
+
+use common::partner::{Received, PDO_5V_3A};
+use common::supply::SimSupply;
+use common::{request_5v, run, Sink, Source, SINK_CONFIG};
+use embassy_time::Duration;
+use usb_pd::policy_engine::PolicyEngine;
+use usb_pd::protocol::source_capabilities::*;
+use usb_pd::protocol::*;
+use usb_pd::sink_policy::{Selection, SinkConfig, SinkPolicy};
+use usb_pd::source_policy::{Evaluation, SourceConfig, SourcePolicy};
+
+/// Sink and source policy with data role swap settings.
+struct DataRolePolicy<'a> {
+    sink: SinkConfig<'a>,
+    source: SourceConfig<'a>,
+    preferred_data_role: Option<PortDataRole>,
+    accept_data_role_swap: bool,
+    data_role: &'a Cell<Option<PortDataRole>>,
+}
+
+impl<'a> DataRolePolicy<'a> {
+    fn new(data_role: &'a Cell<Option<PortDataRole>>) -> Self {
+        let capabilities = [PowerDataObject::FixedSupply(FixedSupply::from_mv_ma(
+            5000, 1500,
+        ))];
+        Self {
+            sink: SINK_CONFIG,
+            source: SourceConfig {
+                capabilities: Box::leak(Box::new(capabilities)),
+                commands: None,
+            },
+            preferred_data_role: None,
+            accept_data_role_swap: false,
+            data_role,
+        }
+    }
+}
+
+impl SinkPolicy for DataRolePolicy<'_> {
+    fn select(&mut self, source_capabilities: &[PowerDataObject]) -> Selection {
+        self.sink.select(source_capabilities)
+    }
+
+    fn sink_capabilities(&self, pdos: &mut [u32]) -> usize {
+        self.sink.sink_capabilities(pdos)
+    }
+
+    fn preferred_data_role(&self) -> Option<PortDataRole> {
+        self.preferred_data_role
+    }
+
+    fn accept_data_role_swap(&mut self) -> bool {
+        self.accept_data_role_swap
+    }
+
+    fn data_role_swapped(&mut self, data_role: PortDataRole) {
+        self.data_role.set(Some(data_role));
+    }
+}
+
+impl SourcePolicy for DataRolePolicy<'_> {
+    fn source_capabilities(&self, pdos: &mut [PowerDataObject]) -> usize {
+        self.source.source_capabilities(pdos)
+    }
+
+    fn evaluate(&mut self, pdo: &PowerDataObject, request: &Selection) -> Evaluation {
+        self.source.evaluate(pdo, request)
+    }
+
+    fn preferred_data_role(&self) -> Option<PortDataRole> {
+        self.preferred_data_role
+    }
+
+    fn accept_data_role_swap(&mut self) -> bool {
+        self.accept_data_role_swap
+    }
+
+    fn data_role_swapped(&mut self, data_role: PortDataRole) {
+        self.data_role.set(Some(data_role));
+    }
+}
+
+#[test]
+fn sink_accepts_data_role_swap() {
+    let data_role = Cell::new(None);
+    let policy = DataRolePolicy {
+        accept_data_role_swap: true,
+        ..DataRolePolicy::new(&data_role)
+    };
+    run(
+        Sink,
+        |pe| PolicyEngine::new(pe, policy),
+        |mut source| async move {
+            source.negotiate(&[PDO_5V_3A]).await;
+            source.send_control(ControlMessageType::DrSwap).await;
+            source.expect_control(ControlMessageType::Accept).await;
+            source.data_role = PortDataRole::UpstreamFacingPort;
+
+            source.send_control(ControlMessageType::GetSinkCap).await;
+            source.expect_data(DataMessageType::SinkCapabilities).await;
+            assert_eq!(
+                source.rx_header.unwrap().port_data_role(),
+                PortDataRole::DownstreamFacingPort
+            );
+        },
+    )
+    .unwrap();
+    assert_eq!(data_role.get(), Some(PortDataRole::DownstreamFacingPort));
+}
+
+#[test]
+fn sink_rejects_data_role_swap() {
+    let data_role = Cell::new(None);
+    run(
+        Sink,
+        |pe| PolicyEngine::new(pe, DataRolePolicy::new(&data_role)),
+        |mut source| async move {
+            source.negotiate(&[PDO_5V_3A]).await;
+            source.send_control(ControlMessageType::DrSwap).await;
+            source.expect_control(ControlMessageType::Reject).await;
+            assert_eq!(
+                source.rx_header.unwrap().port_data_role(),
+                PortDataRole::UpstreamFacingPort
+            );
+        },
+    )
+    .unwrap();
+    assert_eq!(data_role.get(), None);
+}
+
+#[test]
+fn sink_swaps_to_preferred_data_role() {
+    let data_role = Cell::new(None);
+    let policy = DataRolePolicy {
+        preferred_data_role: Some(PortDataRole::DownstreamFacingPort),
+        ..DataRolePolicy::new(&data_role)
+    };
+    run(
+        Sink,
+        |pe| PolicyEngine::new(pe, policy),
+        |mut source| async move {
+            source.negotiate(&[PDO_5V_3A]).await;
+            source.expect_control(ControlMessageType::DrSwap).await;
+            source.send_control(ControlMessageType::Accept).await;
+            source.data_role = PortDataRole::UpstreamFacingPort;
+
+            source.send_control(ControlMessageType::GetSinkCap).await;
+            source.expect_data(DataMessageType::SinkCapabilities).await;
+            assert_eq!(
+                source.rx_header.unwrap().port_data_role(),
+                PortDataRole::DownstreamFacingPort
+            );
+        },
+    )
+    .unwrap();
+    assert_eq!(data_role.get(), Some(PortDataRole::DownstreamFacingPort));
+}
+
+#[test]
+fn source_swaps_to_preferred_data_role_once() {
+    let data_role = Cell::new(None);
+    let policy = DataRolePolicy {
+        preferred_data_role: Some(PortDataRole::UpstreamFacingPort),
+        ..DataRolePolicy::new(&data_role)
+    };
+    let supply = SimSupply::default();
+    run(
+        Source(&supply),
+        |pe| PolicyEngine::new(pe, policy),
+        |mut sink| async move {
+            sink.expect_data(DataMessageType::SourceCapabilites).await;
+            assert_eq!(
+                sink.request(request_5v()).await,
+                Received::Control(ControlMessageType::Accept)
+            );
+            sink.expect_control(ControlMessageType::PsRdy).await;
+
+            sink.expect_control(ControlMessageType::DrSwap).await;
+            sink.send_control(ControlMessageType::Reject).await;
+            sink.expect_nothing(Duration::from_millis(100)).await;
+
+            sink.send_control(ControlMessageType::GetSourceCap).await;
+            sink.expect_data(DataMessageType::SourceCapabilites).await;
+            assert_eq!(
+                sink.rx_header.unwrap().port_data_role(),
+                PortDataRole::DownstreamFacingPort
+            );
+        },
+    )
+    .unwrap();
+    assert_eq!(data_role.get(), None);
+}
+
+#[test]
+fn source_accepts_data_role_swap() {
+    let data_role = Cell::new(None);
+    let policy = DataRolePolicy {
+        accept_data_role_swap: true,
+        ..DataRolePolicy::new(&data_role)
+    };
+    let supply = SimSupply::default();
+    run(
+        Source(&supply),
+        |pe| PolicyEngine::new(pe, policy),
+        |mut sink| async move {
+            sink.expect_data(DataMessageType::SourceCapabilites).await;
+            sink.request(request_5v()).await;
+            sink.expect_control(ControlMessageType::PsRdy).await;
+
+            sink.send_control(ControlMessageType::DrSwap).await;
+            sink.expect_control(ControlMessageType::Accept).await;
+            sink.data_role = PortDataRole::DownstreamFacingPort;
+
+            sink.send_control(ControlMessageType::GetSourceCap).await;
+            sink.expect_data(DataMessageType::SourceCapabilites).await;
+            assert_eq!(
+                sink.rx_header.unwrap().port_data_role(),
+                PortDataRole::UpstreamFacingPort
+            );
+        },
+    )
+    .unwrap();
+    assert_eq!(data_role.get(), Some(PortDataRole::UpstreamFacingPort));
+}
@@ -0,0 +1,283 @@
+mod common;
+
+use core::cell::RefCell;
+
+use bilge::prelude::*;
+use common::partner::{Received, SimPartner};
+use common::supply::SimSupply;
+use common::{request_5v, run, vsafe5v_source_config, Source};
+use embassy_time::Duration;
+use usb_pd::policy_engine::PolicyEngine;
+use usb_pd::protocol::displayport::*;
+use usb_pd::protocol::*;
+use usb_pd::vdm_policy::VdmPolicy;
+
+/// ID Header VDO of a PDUSB peripheral supporting modal operation.
+const ID_HEADER: u32 = (0b010 << 27) | (1 << 26) | 0x1234;
+
+/// DisplayPort Capabilities of a monitor with a USB-C receptacle: UFP_D
+/// capable (01b), DP v1.3 signaling, receptacle and UFP_D pin assignments C
+/// and D.
+const UFP_D_CAPABILITIES: u32 = 0x000C_0045;
+
+/// DisplayPort Capabilities of a DFP_D only receptacle (10b) with DFP_D pin
+/// assignments C and D.
+const DFP_D_CAPABILITIES: u32 = 0x0000_0C46;
+
+#[derive(Debug, PartialEq)]
+enum Event {
+    Configured(Option<PinAssignment>),
+    Hpd(bool, bool),
+}
+
+/// DFP_D supporting pin assignments C and D that records the callbacks.
+struct DisplayPortHost<'a> {
+    events: &'a RefCell<Vec<Event>>,
+}
+
+impl VdmPolicy for DisplayPortHost<'_> {
+    fn displayport_pin_assignments(&self) -> PinAssignments {
+        PinAssignments::from(0b1100)
+    }
+
+    fn displayport_configured(&mut self, pin_assignment: Option<PinAssignment>) {
+        self.events
+            .borrow_mut()
+            .push(Event::Configured(pin_assignment));
+    }
+
+    fn displayport_hpd(&mut self, hpd_state: bool, irq_hpd: bool) {
+        self.events
+            .borrow_mut()
+            .push(Event::Hpd(hpd_state, irq_hpd));
+    }
+}
+
+/// Runs the power negotiation as sink.
+async fn negotiate(sink: &mut SimPartner<'_>) {
+    sink.expect_data(DataMessageType::SourceCapabilites).await;
+    assert_eq!(
+        sink.request(request_5v()).await,
+        Received::Control(ControlMessageType::Accept)
+    );
+    sink.expect_control(ControlMessageType::PsRdy).await;
+}
+
+/// Expects a VDM request with `command` and answers it with ACK and `vdos`.
+async fn ack(sink: &mut SimPartner<'_>, command: VdmCommand, vdos: &[u32]) -> Vec<u32> {
+    let (request, request_vdos) = sink.expect_vdm().await;
+    assert_eq!(request.command(), command);
+    assert_eq!(request.command_type(), VdmCommandType::Request);
+    sink.send_vdm_response(request, VdmCommandType::Ack, vdos)
+        .await;
+    request_vdos
+}
+
+/// Answers the discovery commands of the source with a DisplayPort UFP_D.
+async fn discover(sink: &mut SimPartner<'_>) {
+    discover_with(sink, UFP_D_CAPABILITIES).await
+}
+
+/// Answers the discovery commands of the source with the DisplayPort mode
+/// `capabilities`.
+async fn discover_with(sink: &mut SimPartner<'_>, capabilities: u32) {
+    ack(sink, VdmCommand::DiscoverIdentity, &[ID_HEADER, 0, 0]).await;
+    ack(sink, VdmCommand::DiscoverSvids, &[0x1234_FF01, 0]).await;
+    ack(sink, VdmCommand::DiscoverModes, &[capabilities]).await;
+}
+
+/// Waits until the source handled all previous messages.
+async fn sync(sink: &mut SimPartner<'_>) {
+    sink.send_control(ControlMessageType::GetSourceCap).await;
+    negotiate(sink).await;
+}
+
+fn status(multi_function_preferred: bool, hpd_state: bool, irq_hpd: bool) -> u32 {
+    let mut status = DisplayPortStatus::from(0);
+    status.set_ufp_d_connected(true);
+    status.set_multi_function_preferred(multi_function_preferred);
+    status.set_hpd_state(hpd_state);
+    status.set_irq_hpd(irq_hpd);
+    status.into()
+}
+
+#[test]
+fn capabilities_decoding() {
+    let capabilities = DisplayPortCapabilities::from(UFP_D_CAPABILITIES);
+    assert!(capabilities.ufp_d_capable());
+    assert!(!capabilities.dfp_d_capable());
+    assert!(capabilities.receptacle());
+    assert_eq!(capabilities.ufp_d_pins(), PinAssignments::from(0b1100));
+    assert!(DisplayPortCapabilities::from(DFP_D_CAPABILITIES).dfp_d_capable());
+    assert!(!DisplayPortCapabilities::from(DFP_D_CAPABILITIES).ufp_d_capable());
+}
+
+#[test]
+fn source_ignores_dfp_d_only_partner() {
+    let events = RefCell::new(vec![]);
+    let host = DisplayPortHost { events: &events };
+    let supply = SimSupply::default();
+    run(
+        Source(&supply),
+        |pe| PolicyEngine::new(pe, vsafe5v_source_config()).with_vdm(host),
+        |mut sink| async move {
+            negotiate(&mut sink).await;
+            discover_with(&mut sink, DFP_D_CAPABILITIES).await;
+            // No Enter Mode follows.
+            sync(&mut sink).await;
+        },
+    )
+    .unwrap();
+    assert!(events.take().is_empty());
+}
+
+#[test]
+fn source_enters_displayport_mode() {
+    let events = RefCell::new(vec![]);
+    let events = &events;
+    let host = DisplayPortHost { events };
+    let supply = SimSupply::default();
+    run(
+        Source(&supply),
+        |pe| PolicyEngine::new(pe, vsafe5v_source_config()).with_vdm(host),
+        |mut sink| async move {
+            negotiate(&mut sink).await;
+            discover(&mut sink).await;
+            ack(&mut sink, VdmCommand::EnterMode, &[]).await;
+            let vdos = ack(
+                &mut sink,
+                VdmCommand::DisplayPortStatusUpdate,
+                &[status(false, true, false)],
+            )
+            .await;
+            assert!(DisplayPortStatus::from(vdos[0]).dfp_d_connected());
+            let vdos = ack(&mut sink, VdmCommand::DisplayPortConfigure, &[]).await;
+            let configure = DisplayPortConfigure::from(vdos[0]);
+            assert_eq!(configure.configuration(), DisplayPortConfiguration::UfpD);
+            assert_eq!(configure.pin_assignment(), PinAssignment::C.into());
+            sync(&mut sink).await;
+            assert_eq!(
+                events.take(),
+                [
+                    Event::Configured(Some(PinAssignment::C)),
+                    Event::Hpd(true, false)
+                ]
+            );
+
+            let mut attention =
+                StructuredVdmHeader::request(DISPLAYPORT_SID, VdmCommand::Attention, sink.revision);
+            attention.set_object_position(u3::new(1));
+            sink.send_data(
+                DataMessageType::VendorDefined,
+                &[attention.into(), status(false, true, true)],
+            )
+            .await;
+            sync(&mut sink).await;
+            assert_eq!(events.take(), [Event::Hpd(true, true)]);
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn multi_function_preference_selects_pin_assignment_d() {
+    let events = RefCell::new(vec![]);
+    let host = DisplayPortHost { events: &events };
+    let supply = SimSupply::default();
+    run(
+        Source(&supply),
+        |pe| PolicyEngine::new(pe, vsafe5v_source_config()).with_vdm(host),
+        |mut sink| async move {
+            negotiate(&mut sink).await;
+            discover(&mut sink).await;
+            ack(&mut sink, VdmCommand::EnterMode, &[]).await;
+            ack(
+                &mut sink,
+                VdmCommand::DisplayPortStatusUpdate,
+                &[status(true, false, false)],
+            )
+            .await;
+            let vdos = ack(&mut sink, VdmCommand::DisplayPortConfigure, &[]).await;
+            let configure = DisplayPortConfigure::from(vdos[0]);
+            assert_eq!(configure.pin_assignment(), PinAssignment::D.into());
+            sync(&mut sink).await;
+        },
+    )
+    .unwrap();
+    assert_eq!(events.take()[0], Event::Configured(Some(PinAssignment::D)));
+}
+
+#[test]
+fn partner_without_displayport_stays_in_usb() {
+    let events = RefCell::new(vec![]);
+    let host = DisplayPortHost { events: &events };
+    let supply = SimSupply::default();
+    run(
+        Source(&supply),
+        |pe| PolicyEngine::new(pe, vsafe5v_source_config()).with_vdm(host),
+        |mut sink| async move {
+            negotiate(&mut sink).await;
+            ack(&mut sink, VdmCommand::DiscoverIdentity, &[ID_HEADER, 0, 0]).await;
+            ack(&mut sink, VdmCommand::DiscoverSvids, &[0x1234_0000]).await;
+            sink.expect_nothing(Duration::from_millis(100)).await;
+        },
+    )
+    .unwrap();
+    assert!(events.take().is_empty());
+}
+
+#[test]
+fn refused_mode_entry_stays_in_usb() {
+    let events = RefCell::new(vec![]);
+    let host = DisplayPortHost { events: &events };
+    let supply = SimSupply::default();
+    run(
+        Source(&supply),
+        |pe| PolicyEngine::new(pe, vsafe5v_source_config()).with_vdm(host),
+        |mut sink| async move {
+            negotiate(&mut sink).await;
+            discover(&mut sink).await;
+            let (request, _) = sink.expect_vdm().await;
+            assert_eq!(request.command(), VdmCommand::EnterMode);
+            sink.send_vdm_response(request, VdmCommandType::Nak, &[])
+                .await;
+            sink.expect_nothing(Duration::from_millis(100)).await;
+        },
+    )
+    .unwrap();
+    assert!(events.take().is_empty());
+}
+
+#[test]
+fn hard_reset_exits_displayport_mode() {
+    let events = RefCell::new(vec![]);
+    let host = DisplayPortHost { events: &events };
+    let supply = SimSupply::default();
+    let result = run(
+        Source(&supply),
+        |pe| PolicyEngine::new(pe, vsafe5v_source_config()).with_vdm(host),
+        |mut sink| async move {
+            negotiate(&mut sink).await;
+            discover(&mut sink).await;
+            ack(&mut sink, VdmCommand::EnterMode, &[]).await;
+            ack(
+                &mut sink,
+                VdmCommand::DisplayPortStatusUpdate,
+                &[status(false, false, false)],
+            )
+            .await;
+            ack(&mut sink, VdmCommand::DisplayPortConfigure, &[]).await;
+            sink.send_hard_reset().await;
+            sink.expect_nothing(Duration::from_secs(2)).await;
+        },
+    );
+    assert!(result.is_err());
+    assert_eq!(
+        events.take(),
+        [
+            Event::Configured(Some(PinAssignment::C)),
+            Event::Hpd(false, false),
+            Event::Configured(None),
+        ]
+    );
+}
@@ -0,0 +1,255 @@
+mod common;
+
+use bilge::prelude::*;
+use common::loopback::SimCc;
+use common::partner::{Received, PDO_5V_3A};
+use common::supply::{SimSupply, SupplyEvent};
+use common::{run, DualRole, DualRoleConfig, SINK_CONFIG};
+use embassy_time::{Duration, Instant};
+use usb_pd::phy::RpLevel;
+use usb_pd::policy_engine::PolicyEngine;
+use usb_pd::protocol::source_capabilities::*;
+use usb_pd::protocol::*;
+use usb_pd::protocol_engine::HardReset;
+use usb_pd::sink_policy::{SinkCommand, SinkCommands, SinkConfig};
+use usb_pd::source_policy::{SourceCommand, SourceCommands, SourceConfig};
+
+const ACCEPT: Received = Received::Control(ControlMessageType::Accept);
+
+fn config(accept_power_role_swap: bool) -> DualRoleConfig<'static> {
+    let capabilities = [PowerDataObject::FixedSupply(FixedSupply::from_mv_ma(
+        5000, 1500,
+    ))];
+    DualRoleConfig {
+        sink: SINK_CONFIG,
+        source: SourceConfig {
+            capabilities: Box::leak(Box::new(capabilities)),
+            commands: None,
+        },
+        accept_power_role_swap,
+    }
+}
+
+fn request(current_ma: u32) -> u32 {
+    let current = u10::new((current_ma / 10) as u16);
+    Request::new(
+        current,
+        current,
+        u4::new(0),
+        false,
+        false,
+        false,
+        false,
+        u3::new(1),
+        false,
+    )
+    .into()
+}
+
+#[test]
+fn swap_from_sink_to_source() {
+    let cc_phy = SimCc::new(RpLevel::Current3_0A);
+    let cc_phy = &cc_phy;
+    let supply = SimSupply::default();
+    let supply = &supply;
+    run(
+        DualRole(PortPowerRole::Sink, supply),
+        |pe| PolicyEngine::new(pe.with_cc_phy(cc_phy), config(true)),
+        |mut partner| async move {
+            partner.negotiate(&[PDO_5V_3A]).await;
+            assert_eq!(cc_phy.power_role(), Some(PortPowerRole::Sink));
+
+            partner.send_control(ControlMessageType::PrSwap).await;
+            partner.expect_control(ControlMessageType::Accept).await;
+            // The initial source switched off VBUS.
+            partner.send_control(ControlMessageType::PsRdy).await;
+            partner.power_role = PortPowerRole::Sink;
+            partner.expect_control(ControlMessageType::PsRdy).await;
+            assert_eq!(
+                partner.rx_header.unwrap().port_power_role(),
+                PortPowerRole::Source
+            );
+            assert_eq!(cc_phy.power_role(), Some(PortPowerRole::Source));
+            assert_eq!(supply.take_events(), [SupplyEvent::Enable]);
+
+            partner
+                .expect_data(DataMessageType::SourceCapabilites)
+                .await;
+            assert_eq!(partner.request(request(1000)).await, ACCEPT);
+            partner.expect_control(ControlMessageType::PsRdy).await;
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn swap_from_source_to_sink() {
+    let cc_phy = SimCc::new(RpLevel::Current3_0A);
+    let cc_phy = &cc_phy;
+    let supply = SimSupply::default();
+    let supply = &supply;
+    run(
+        DualRole(PortPowerRole::Source, supply),
+        |pe| PolicyEngine::new(pe.with_cc_phy(cc_phy), config(true)),
+        |mut partner| async move {
+            partner
+                .expect_data(DataMessageType::SourceCapabilites)
+                .await;
+            assert_eq!(partner.request(request(1000)).await, ACCEPT);
+            partner.expect_control(ControlMessageType::PsRdy).await;
+            supply.take_events();
+
+            partner.send_control(ControlMessageType::PrSwap).await;
+            partner.expect_control(ControlMessageType::Accept).await;
+            partner.expect_control(ControlMessageType::PsRdy).await;
+            assert_eq!(
+                partner.rx_header.unwrap().port_power_role(),
+                PortPowerRole::Sink
+            );
+            assert_eq!(cc_phy.power_role(), Some(PortPowerRole::Sink));
+            assert_eq!(supply.take_events(), [SupplyEvent::Disable]);
+
+            // The new source switched on VBUS.
+            partner.power_role = PortPowerRole::Source;
+            partner.send_control(ControlMessageType::PsRdy).await;
+            let request = partner.negotiate(&[PDO_5V_3A]).await;
+            assert_eq!(request.object_position(), u3::new(1));
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn swap_rejected_by_policy() {
+    let cc_phy = SimCc::new(RpLevel::Current3_0A);
+    let supply = SimSupply::default();
+    run(
+        DualRole(PortPowerRole::Sink, &supply),
+        |pe| PolicyEngine::new(pe.with_cc_phy(&cc_phy), config(false)),
+        |mut partner| async move {
+            partner.negotiate(&[PDO_5V_3A]).await;
+            partner.send_control(ControlMessageType::PrSwap).await;
+            partner.expect_control(ControlMessageType::Reject).await;
+
+            partner.send_control(ControlMessageType::GetSinkCap).await;
+            partner.expect_data(DataMessageType::SinkCapabilities).await;
+        },
+    )
+    .unwrap();
+    assert_eq!(cc_phy.power_role(), Some(PortPowerRole::Sink));
+    assert!(supply.take_events().is_empty());
+}
+
+#[test]
+fn missing_ps_rdy_ends_swap() {
+    let cc_phy = SimCc::new(RpLevel::Current3_0A);
+    let supply = SimSupply::default();
+    let start = Instant::now();
+    let result = run(
+        DualRole(PortPowerRole::Sink, &supply),
+        |pe| PolicyEngine::new(pe.with_cc_phy(&cc_phy), config(true)),
+        |mut partner| async move {
+            partner.negotiate(&[PDO_5V_3A]).await;
+            partner.send_control(ControlMessageType::PrSwap).await;
+            partner.expect_control(ControlMessageType::Accept).await;
+            partner.expect_nothing(Duration::from_secs(2)).await;
+        },
+    );
+    assert!(matches!(result, Err(HardReset)));
+    assert!(start.elapsed() >= Duration::from_millis(900));
+    assert_eq!(cc_phy.power_role(), Some(PortPowerRole::Sink));
+}
+
+#[test]
+fn sink_requests_swap_to_source() {
+    static COMMANDS: SinkCommands = SinkCommands::new();
+    let cc_phy = SimCc::new(RpLevel::Current3_0A);
+    let cc_phy = &cc_phy;
+    let supply = SimSupply::default();
+    let supply = &supply;
+    let config = DualRoleConfig {
+        sink: SinkConfig {
+            commands: Some(&COMMANDS),
+            ..SINK_CONFIG
+        },
+        ..config(false)
+    };
+    run(
+        DualRole(PortPowerRole::Sink, supply),
+        |pe| PolicyEngine::new(pe.with_cc_phy(cc_phy), config),
+        |mut partner| async move {
+            partner.negotiate(&[PDO_5V_3A]).await;
+
+            // Refusals keep the roles.
+            for response in [ControlMessageType::Reject, ControlMessageType::Wait] {
+                assert!(COMMANDS.try_send(SinkCommand::PowerRoleSwap));
+                partner.expect_control(ControlMessageType::PrSwap).await;
+                partner.send_control(response).await;
+                partner.send_control(ControlMessageType::GetSinkCap).await;
+                partner.expect_data(DataMessageType::SinkCapabilities).await;
+            }
+            assert_eq!(cc_phy.power_role(), Some(PortPowerRole::Sink));
+
+            assert!(COMMANDS.try_send(SinkCommand::PowerRoleSwap));
+            partner.expect_control(ControlMessageType::PrSwap).await;
+            partner.send_control(ControlMessageType::Accept).await;
+            partner.send_control(ControlMessageType::PsRdy).await;
+            partner.power_role = PortPowerRole::Sink;
+            partner.expect_control(ControlMessageType::PsRdy).await;
+            assert_eq!(cc_phy.power_role(), Some(PortPowerRole::Source));
+            assert_eq!(supply.take_events(), [SupplyEvent::Enable]);
+
+            partner
+                .expect_data(DataMessageType::SourceCapabilites)
+                .await;
+            assert_eq!(partner.request(request(1000)).await, ACCEPT);
+            partner.expect_control(ControlMessageType::PsRdy).await;
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn source_requests_swap_to_sink() {
+    static COMMANDS: SourceCommands = SourceCommands::new();
+    let cc_phy = SimCc::new(RpLevel::Current3_0A);
+    let cc_phy = &cc_phy;
+    let supply = SimSupply::default();
+    let supply = &supply;
+    let mut config = config(false);
+    config.source.commands = Some(&COMMANDS);
+    run(
+        DualRole(PortPowerRole::Source, supply),
+        |pe| PolicyEngine::new(pe.with_cc_phy(cc_phy), config),
+        |mut partner| async move {
+            partner
+                .expect_data(DataMessageType::SourceCapabilites)
+                .await;
+            assert_eq!(partner.request(request(1000)).await, ACCEPT);
+            partner.expect_control(ControlMessageType::PsRdy).await;
+            supply.take_events();
+
+            for response in [ControlMessageType::Reject, ControlMessageType::Wait] {
+                assert!(COMMANDS.try_send(SourceCommand::PowerRoleSwap));
+                partner.expect_control(ControlMessageType::PrSwap).await;
+                partner.send_control(response).await;
+                partner.expect_nothing(Duration::from_millis(50)).await;
+            }
+            assert_eq!(cc_phy.power_role(), Some(PortPowerRole::Source));
+            assert!(supply.take_events().is_empty());
+
+            assert!(COMMANDS.try_send(SourceCommand::PowerRoleSwap));
+            partner.expect_control(ControlMessageType::PrSwap).await;
+            partner.send_control(ControlMessageType::Accept).await;
+            partner.expect_control(ControlMessageType::PsRdy).await;
+            assert_eq!(cc_phy.power_role(), Some(PortPowerRole::Sink));
+            assert_eq!(supply.take_events(), [SupplyEvent::Disable]);
+
+            partner.power_role = PortPowerRole::Source;
+            partner.send_control(ControlMessageType::PsRdy).await;
+            let request = partner.negotiate(&[PDO_5V_3A]).await;
+            assert_eq!(request.object_position(), u3::new(1));
+        },
+    )
+    .unwrap();
+}
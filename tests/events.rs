@@ -0,0 +1,180 @@
+mod common;
+
+use std::cell::RefCell;
+
+use common::loopback::Link;
+use common::partner::PDO_5V_3A;
+use common::{run, Sink, SINK_CONFIG};
+use embassy_futures::block_on;
+use embassy_futures::select::select;
+use usb_pd::policy_engine::PolicyEngine;
+use usb_pd::protocol::source_capabilities::PowerDataObject;
+use usb_pd::protocol::*;
+use usb_pd::protocol_engine::{HardReset, ProtocolEngine};
+use usb_pd::sink_policy::{Selection, SinkConfig, SinkEvent, SinkPolicy};
+
+/// Owned copy of a [`SinkEvent`].
+#[derive(Debug, PartialEq)]
+enum Event {
+    Attached,
+    SourceCapabilitiesReceived(usize),
+    ContractEstablished(u32, u32),
+    ContractRejected,
+    Wait,
+    SoftReset,
+    HardReset,
+    Detached,
+}
+
+/// Sink recording its events.
+struct Recorder<'a> {
+    config: SinkConfig<'static>,
+    events: &'a RefCell<Vec<Event>>,
+}
+
+impl SinkPolicy for Recorder<'_> {
+    fn select(&mut self, source_capabilities: &[PowerDataObject]) -> Selection {
+        self.config.select(source_capabilities)
+    }
+
+    fn sink_capabilities(&self, pdos: &mut [u32]) -> usize {
+        self.config.sink_capabilities(pdos)
+    }
+
+    fn notify(&mut self, event: SinkEvent<'_>) {
+        self.events.borrow_mut().push(match event {
+            SinkEvent::Attached => Event::Attached,
+            SinkEvent::SourceCapabilitiesReceived(pdos) => {
+                Event::SourceCapabilitiesReceived(pdos.len())
+            }
+            SinkEvent::ContractEstablished {
+                voltage_mv,
+                operating_current_ma,
+                ..
+            } => Event::ContractEstablished(voltage_mv, operating_current_ma),
+            SinkEvent::ContractRejected => Event::ContractRejected,
+            SinkEvent::Wait => Event::Wait,
+            SinkEvent::SoftReset => Event::SoftReset,
+            SinkEvent::HardReset => Event::HardReset,
+            SinkEvent::Detached => Event::Detached,
+        });
+    }
+}
+
+fn recorder(events: &RefCell<Vec<Event>>) -> Recorder<'_> {
+    Recorder {
+        config: SINK_CONFIG,
+        events,
+    }
+}
+
+#[test]
+fn contract_is_reported_after_ps_rdy() {
+    let events = RefCell::new(Vec::new());
+    let events = &events;
+    run(
+        Sink,
+        |pe| PolicyEngine::new(pe, recorder(events)),
+        |mut source| async move {
+            source.send_source_capabilities(&[PDO_5V_3A]).await;
+            assert_eq!(
+                *events.borrow(),
+                [Event::Attached, Event::SourceCapabilitiesReceived(1)]
+            );
+            source.send_control(ControlMessageType::Accept).await;
+            source.send_control(ControlMessageType::PsRdy).await;
+            // Sync with the sink before looking at the events.
+            source.send_control(ControlMessageType::GetSinkCap).await;
+            source.expect_data(DataMessageType::SinkCapabilities).await;
+            assert_eq!(
+                events.borrow()[2..],
+                [Event::ContractEstablished(5000, 1500)]
+            );
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn rejected_request_is_reported() {
+    let events = RefCell::new(Vec::new());
+    let events = &events;
+    run(
+        Sink,
+        |pe| PolicyEngine::new(pe, recorder(events)),
+        |mut source| async move {
+            source.send_source_capabilities(&[PDO_5V_3A]).await;
+            source.send_control(ControlMessageType::Reject).await;
+            source.send_control(ControlMessageType::GetSinkCap).await;
+            source.expect_data(DataMessageType::SinkCapabilities).await;
+            assert_eq!(events.borrow()[2..], [Event::ContractRejected]);
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn wait_is_reported() {
+    let events = RefCell::new(Vec::new());
+    let events = &events;
+    run(
+        Sink,
+        |pe| PolicyEngine::new(pe, recorder(events)),
+        |mut source| async move {
+            source.negotiate(&[PDO_5V_3A]).await;
+            source.send_source_capabilities(&[PDO_5V_3A]).await;
+            source.send_control(ControlMessageType::Wait).await;
+            source.expect_data(DataMessageType::Request).await;
+            assert_eq!(
+                events.borrow()[3..],
+                [Event::SourceCapabilitiesReceived(1), Event::Wait]
+            );
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn resets_are_reported() {
+    let events = RefCell::new(Vec::new());
+    let events = &events;
+    let result = run(
+        Sink,
+        |pe| PolicyEngine::new(pe, recorder(events)),
+        |mut source| async move {
+            source.negotiate(&[PDO_5V_3A]).await;
+            source.send_control(ControlMessageType::SoftReset).await;
+            source.expect_control(ControlMessageType::Accept).await;
+            source.negotiate(&[PDO_5V_3A]).await;
+            source.send_hard_reset().await;
+            core::future::pending::<()>().await;
+        },
+    );
+    assert!(matches!(result, Err(HardReset)));
+    assert_eq!(
+        *events.borrow(),
+        [
+            Event::Attached,
+            Event::SourceCapabilitiesReceived(1),
+            Event::ContractEstablished(5000, 1500),
+            Event::SoftReset,
+            Event::SourceCapabilitiesReceived(1),
+            Event::ContractEstablished(5000, 1500),
+            Event::HardReset,
+        ]
+    );
+}
+
+#[test]
+fn detach_is_reported() {
+    let events = RefCell::new(Vec::new());
+    let link = Link::new();
+    let (sink_phy, _source_phy) = link.split();
+    let mut sink = PolicyEngine::new(ProtocolEngine::new(sink_phy), recorder(&events));
+    block_on(async {
+        // Detach while waiting for source capabilities.
+        select(sink.run_sink(), embassy_time::Timer::after_millis(10)).await;
+        sink.detach().await;
+    });
+    assert_eq!(*events.borrow(), [Event::Attached, Event::Detached]);
+}
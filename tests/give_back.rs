@@ -0,0 +1,128 @@
+mod common;
+
+use std::cell::RefCell;
+
+use bilge::prelude::*;
+use common::partner::{Received, PDO_5V_3A};
+use common::{run, run_sink, Sink, SINK_CONFIG};
+use embassy_time::{Duration, Instant};
+use usb_pd::policy_engine::PolicyEngine;
+use usb_pd::protocol::source_capabilities::PowerDataObject;
+use usb_pd::protocol::*;
+use usb_pd::protocol_engine::HardReset;
+use usb_pd::sink_policy::{Selection, SinkConfig, SinkEvent, SinkPolicy};
+
+/// Sink giving back all but 500mA, recording the GotoMin callbacks and the
+/// established contracts.
+struct BatterySink<'a> {
+    config: SinkConfig<'static>,
+    log: &'a RefCell<Vec<String>>,
+}
+
+impl SinkPolicy for BatterySink<'_> {
+    fn select(&mut self, source_capabilities: &[PowerDataObject]) -> Selection {
+        self.config.select(source_capabilities)
+    }
+
+    fn sink_capabilities(&self, pdos: &mut [u32]) -> usize {
+        self.config.sink_capabilities(pdos)
+    }
+
+    fn goto_min(&mut self, min_operating_current_ma: u32) {
+        self.log
+            .borrow_mut()
+            .push(format!("goto_min {min_operating_current_ma}"));
+    }
+
+    fn notify(&mut self, event: SinkEvent<'_>) {
+        if let SinkEvent::ContractEstablished {
+            operating_current_ma,
+            ..
+        } = event
+        {
+            self.log
+                .borrow_mut()
+                .push(format!("contract {operating_current_ma}"));
+        }
+    }
+}
+
+fn battery_sink(log: &RefCell<Vec<String>>) -> BatterySink<'_> {
+    BatterySink {
+        config: SinkConfig {
+            give_back_current_ma: Some(500),
+            ..SINK_CONFIG
+        },
+        log,
+    }
+}
+
+#[test]
+fn request_carries_give_back() {
+    let log = RefCell::new(Vec::new());
+    run(
+        Sink,
+        |pe| PolicyEngine::new(pe, battery_sink(&log)),
+        |mut source| async move {
+            let request = source.negotiate(&[PDO_5V_3A]).await;
+            assert!(request.give_back_flag());
+            assert_eq!(request.operating_curent(), u10::new(150));
+            assert_eq!(request.min_operating_current(), u10::new(50));
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn goto_min_reduces_load_before_ps_rdy() {
+    let log = RefCell::new(Vec::new());
+    let log = &log;
+    run(
+        Sink,
+        |pe| PolicyEngine::new(pe, battery_sink(log)),
+        |mut source| async move {
+            source.negotiate(&[PDO_5V_3A]).await;
+            source.send_control(ControlMessageType::GotoMin).await;
+            source.expect_nothing(Duration::from_millis(50)).await;
+            assert_eq!(log.borrow()[1..], ["goto_min 500"]);
+
+            source.send_control(ControlMessageType::PsRdy).await;
+            source.send_control(ControlMessageType::GetSinkCap).await;
+            source.expect_data(DataMessageType::SinkCapabilities).await;
+            assert_eq!(
+                *log.borrow(),
+                ["contract 1500", "goto_min 500", "contract 500"]
+            );
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn missing_ps_rdy_after_goto_min_triggers_hard_reset() {
+    let log = RefCell::new(Vec::new());
+    let result = run(
+        Sink,
+        |pe| PolicyEngine::new(pe, battery_sink(&log)),
+        |mut source| async move {
+            source.negotiate(&[PDO_5V_3A]).await;
+            source.send_control(ControlMessageType::GotoMin).await;
+            let start = Instant::now();
+            assert_eq!(source.expect_any().await, Received::HardReset);
+            assert!(start.elapsed() >= Duration::from_millis(500));
+            core::future::pending::<()>().await;
+        },
+    );
+    assert!(matches!(result, Err(HardReset)));
+}
+
+#[test]
+fn goto_min_without_give_back_is_rejected() {
+    run_sink(|mut source| async move {
+        let request = source.negotiate(&[PDO_5V_3A]).await;
+        assert!(!request.give_back_flag());
+        source.send_control(ControlMessageType::GotoMin).await;
+        source.expect_control(ControlMessageType::Reject).await;
+    })
+    .unwrap();
+}
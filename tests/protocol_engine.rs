@@ -0,0 +1,403 @@
+mod common;
+
+use core::cell::Cell;
+use core::future::{pending, Future};
+use std::rc::Rc;
+
+use bilge::prelude::*;
+use common::loopback::{Link, LoopbackPhy};
+use common::partner::{Received, SimPartner, PDO_5V_3A};
+use embassy_futures::block_on;
+use embassy_futures::join::join;
+use embassy_time::Duration;
+use usb_pd::phy::{PdPhy, RxError, Sop, TxError};
+use usb_pd::protocol::*;
+use usb_pd::protocol_engine::{Message, ProtocolEngine, MAX_EXTENDED_MESSAGE_LEN};
+
+/// Runs `port` and `partner` concurrently on both ends of a loopback link.
+fn run<F1, F2, Fut1, Fut2>(port: F1, partner: F2)
+where
+    F1: FnOnce(ProtocolEngine<LoopbackPhy<'static>>) -> Fut1,
+    F2: FnOnce(SimPartner<'static>) -> Fut2,
+    Fut1: Future<Output = ()>,
+    Fut2: Future<Output = ()>,
+{
+    let link: &'static Link = Box::leak(Box::new(Link::new()));
+    let (phy, partner_phy) = link.split();
+    let mut source = SimPartner::new(partner_phy);
+    source.revision = SpecificationRevision::Revision3_0;
+    block_on(join(port(ProtocolEngine::new(phy)), partner(source)));
+}
+
+fn payload(len: usize) -> Vec<u8> {
+    (0..len).map(|i| i as u8).collect()
+}
+
+#[test]
+fn receive_chunked_extended_message() {
+    run(
+        |mut engine| async move {
+            let mut buf = [0; MAX_EXTENDED_MESSAGE_LEN / 4];
+            let msg = engine.receive(&mut buf).await.unwrap();
+            assert_eq!(
+                msg,
+                Message::Extended(ExtendedMessageType::ManufacturerInfo, &payload(100))
+            );
+        },
+        |mut source| async move {
+            source
+                .send_extended(ExtendedMessageType::ManufacturerInfo, &payload(100))
+                .await;
+        },
+    );
+}
+
+#[test]
+fn receive_truncated_extended_message() {
+    run(
+        |mut engine| async move {
+            let mut buf = [0; 7];
+            let msg = engine.receive(&mut buf).await.unwrap();
+            assert_eq!(
+                msg,
+                Message::Extended(ExtendedMessageType::ManufacturerInfo, &payload(28))
+            );
+        },
+        |mut source| async move {
+            source
+                .send_extended(ExtendedMessageType::ManufacturerInfo, &payload(60))
+                .await;
+        },
+    );
+}
+
+#[test]
+fn receive_interrupted_extended_message() {
+    run(
+        |mut engine| async move {
+            let msg = engine.receive(&mut []).await.unwrap();
+            assert_eq!(msg, Message::Control(ControlMessageType::GetSinkCap));
+        },
+        |mut source| async move {
+            let ext_header = ExtendedHeader::new(u9::new(60), false, u4::new(0), true);
+            let chunk = &payload(60)[..26];
+            source
+                .transmit_chunk(ExtendedMessageType::ManufacturerInfo, ext_header, chunk)
+                .await;
+            source.expect_any().await;
+            source.send_control(ControlMessageType::GetSinkCap).await;
+        },
+    );
+}
+
+#[test]
+fn oversized_extended_message_is_aborted() {
+    run(
+        |mut engine| async move {
+            let msg = engine.receive(&mut []).await.unwrap();
+            assert_eq!(msg, Message::Control(ControlMessageType::GetSinkCap));
+        },
+        |mut source| async move {
+            let ext_header = ExtendedHeader::new(u9::new(500), false, u4::new(0), true);
+            let chunk = &payload(500)[..26];
+            assert!(
+                source
+                    .transmit_chunk(ExtendedMessageType::ManufacturerInfo, ext_header, chunk)
+                    .await
+            );
+            source.expect_nothing(Duration::from_millis(50)).await;
+            source.send_control(ControlMessageType::GetSinkCap).await;
+        },
+    );
+}
+
+#[test]
+fn transmit_chunked_extended_message() {
+    run(
+        |mut engine| async move {
+            let data = payload(MAX_EXTENDED_MESSAGE_LEN);
+            let msg = Message::Extended(ExtendedMessageType::Status, &data);
+            assert!(engine.transmit(&msg).await.unwrap());
+        },
+        |mut source| async move {
+            let received = source.expect_extended(ExtendedMessageType::Status).await;
+            assert_eq!(received, payload(MAX_EXTENDED_MESSAGE_LEN));
+        },
+    );
+}
+
+#[test]
+fn transmit_single_chunk() {
+    run(
+        |mut engine| async move {
+            let data = payload(7);
+            let msg = Message::Extended(ExtendedMessageType::Status, &data);
+            assert!(engine.transmit(&msg).await.unwrap());
+        },
+        |mut source| async move {
+            let received = source.expect_extended(ExtendedMessageType::Status).await;
+            assert_eq!(received, payload(7));
+        },
+    );
+}
+
+#[test]
+fn missing_chunk_request_aborts_transmission() {
+    run(
+        |mut engine| async move {
+            let data = payload(40);
+            let msg = Message::Extended(ExtendedMessageType::Status, &data);
+            assert!(!engine.transmit(&msg).await.unwrap());
+        },
+        |mut source| async move {
+            source.expect_any().await;
+            source.expect_nothing(Duration::from_millis(50)).await;
+        },
+    );
+}
+
+#[test]
+fn revision_3_messages_are_not_sent_with_revision_2() {
+    run(
+        |mut engine| async move {
+            let msg = engine.receive(&mut [0; 7]).await.unwrap();
+            assert!(matches!(
+                msg,
+                Message::Data(DataMessageType::SourceCapabilites, _)
+            ));
+            let data = payload(7);
+            for msg in [
+                Message::Control(ControlMessageType::NotSupported),
+                Message::Control(ControlMessageType::GetStatus),
+                Message::Data(DataMessageType::Alert, &[0]),
+                Message::Extended(ExtendedMessageType::Status, &data),
+            ] {
+                assert!(!engine.transmit(&msg).await.unwrap());
+            }
+            let msg = Message::Control(ControlMessageType::Reject);
+            assert!(engine.transmit(&msg).await.unwrap());
+        },
+        |mut source| async move {
+            source.revision = SpecificationRevision::Revision2_0;
+            source
+                .send_data(DataMessageType::SourceCapabilites, &[PDO_5V_3A])
+                .await;
+            source.expect_control(ControlMessageType::Reject).await;
+            assert_eq!(
+                source.rx_header.unwrap().specification_revision(),
+                SpecificationRevision::Revision2_0
+            );
+        },
+    );
+}
+
+#[test]
+fn message_id_wraps_around() {
+    run(
+        |mut engine| async move {
+            for _ in 0..10 {
+                let msg = Message::Control(ControlMessageType::Accept);
+                assert!(engine.transmit(&msg).await.unwrap());
+            }
+        },
+        |mut source| async move {
+            for i in 0..10 {
+                source.expect_control(ControlMessageType::Accept).await;
+                let message_id = source.rx_header.unwrap().message_id();
+                assert_eq!(message_id, u3::new(i % 8));
+            }
+        },
+    );
+}
+
+#[test]
+fn duplicate_message_is_dropped() {
+    run(
+        |mut engine| async move {
+            let msg = engine.receive(&mut []).await.unwrap();
+            assert_eq!(msg, Message::Control(ControlMessageType::GetSinkCap));
+            let msg = engine.receive(&mut []).await.unwrap();
+            assert_eq!(msg, Message::Control(ControlMessageType::Accept));
+        },
+        |mut source| async move {
+            source.send_control(ControlMessageType::GetSinkCap).await;
+            // Retransmission with the same message ID.
+            source.tx_message_id = u3::new(0);
+            source.send_control(ControlMessageType::GetSinkCap).await;
+            source.send_control(ControlMessageType::Accept).await;
+        },
+    );
+}
+
+#[test]
+fn soft_reset_resets_message_ids() {
+    run(
+        |mut engine| async move {
+            for _ in 0..3 {
+                let msg = Message::Control(ControlMessageType::Accept);
+                assert!(engine.transmit(&msg).await.unwrap());
+            }
+            let msg = engine.receive(&mut []).await.unwrap();
+            assert_eq!(msg, Message::Control(ControlMessageType::GetSinkCap));
+            let msg = engine.receive(&mut []).await.unwrap();
+            assert_eq!(msg, Message::Control(ControlMessageType::SoftReset));
+            let msg = Message::Control(ControlMessageType::Accept);
+            assert!(engine.transmit(&msg).await.unwrap());
+        },
+        |mut source| async move {
+            for _ in 0..3 {
+                source.expect_control(ControlMessageType::Accept).await;
+            }
+            source.send_control(ControlMessageType::GetSinkCap).await;
+            // Uses message ID 0 like the GetSinkCap message before.
+            source.send_control(ControlMessageType::SoftReset).await;
+            source.expect_control(ControlMessageType::Accept).await;
+            assert_eq!(source.rx_header.unwrap().message_id(), u3::new(0));
+        },
+    );
+}
+
+#[test]
+fn hard_reset_resets_message_ids() {
+    run(
+        |mut engine| async move {
+            for _ in 0..2 {
+                let msg = Message::Control(ControlMessageType::Accept);
+                assert!(engine.transmit(&msg).await.unwrap());
+            }
+            let msg = engine.receive(&mut []).await.unwrap();
+            assert_eq!(msg, Message::Control(ControlMessageType::GetSinkCap));
+            assert!(engine.receive(&mut []).await.is_err());
+
+            let msg = engine.receive(&mut []).await.unwrap();
+            assert_eq!(msg, Message::Control(ControlMessageType::GetSinkCap));
+            let msg = Message::Control(ControlMessageType::Accept);
+            assert!(engine.transmit(&msg).await.unwrap());
+
+            engine.transmit_hard_reset().await;
+            assert!(engine.transmit(&msg).await.unwrap());
+        },
+        |mut source| async move {
+            for _ in 0..2 {
+                source.expect_control(ControlMessageType::Accept).await;
+            }
+            source.send_control(ControlMessageType::GetSinkCap).await;
+            source.send_hard_reset().await;
+
+            // Both sides start again with message ID 0.
+            source.send_control(ControlMessageType::GetSinkCap).await;
+            source.expect_control(ControlMessageType::Accept).await;
+            assert_eq!(source.rx_header.unwrap().message_id(), u3::new(0));
+
+            assert_eq!(source.expect_any().await, Received::HardReset);
+            source.expect_control(ControlMessageType::Accept).await;
+            assert_eq!(source.rx_header.unwrap().message_id(), u3::new(0));
+        },
+    );
+}
+
+/// Discover Identity response of a passive 5A cable.
+fn cable_vdos() -> Vec<u32> {
+    vec![0x1800_0000, 0, 0, 0x0000_0040]
+}
+
+fn discover_identity() -> u32 {
+    StructuredVdmHeader::request(
+        PD_SID,
+        VdmCommand::DiscoverIdentity,
+        SpecificationRevision::Revision3_0,
+    )
+    .into()
+}
+
+#[test]
+fn sop_prime_uses_own_message_ids() {
+    run(
+        |mut engine| async move {
+            let msg = Message::Control(ControlMessageType::Accept);
+            for _ in 0..2 {
+                assert!(engine.transmit(&msg).await.unwrap());
+            }
+
+            let request = [discover_identity()];
+            let request = Message::Data(DataMessageType::VendorDefined, &request);
+            assert!(engine.transmit_sop(Sop::SopPrime, &request).await.unwrap());
+            let mut buf = [0; 7];
+            let Message::Data(DataMessageType::VendorDefined, objects) =
+                engine.receive_sop(Sop::SopPrime, &mut buf).await.unwrap()
+            else {
+                panic!("Expected VDM from cable");
+            };
+            assert_eq!(objects[1..], cable_vdos());
+
+            assert!(engine.transmit(&msg).await.unwrap());
+        },
+        |mut source| async move {
+            source.cable = Some(cable_vdos());
+            for i in 0..3 {
+                source.expect_control(ControlMessageType::Accept).await;
+                assert_eq!(source.rx_header.unwrap().message_id(), u3::new(i));
+            }
+        },
+    );
+}
+
+#[test]
+fn sop_prime_without_e_marker_is_not_acknowledged() {
+    run(
+        |mut engine| async move {
+            let request = [discover_identity()];
+            let request = Message::Data(DataMessageType::VendorDefined, &request);
+            assert!(!engine.transmit_sop(Sop::SopPrime, &request).await.unwrap());
+
+            let msg = Message::Control(ControlMessageType::Accept);
+            assert!(engine.transmit(&msg).await.unwrap());
+        },
+        |mut source| async move {
+            source.expect_control(ControlMessageType::Accept).await;
+            assert_eq!(source.rx_header.unwrap().message_id(), u3::new(0));
+        },
+    );
+}
+
+/// PHY without cable plug and BIST carrier support, counting the
+/// transmission attempts.
+struct SopOnlyPhy(Rc<Cell<usize>>);
+
+impl PdPhy for SopOnlyPhy {
+    async fn receive(&mut self, _buf: &mut [u8]) -> Result<usize, RxError> {
+        pending().await
+    }
+
+    async fn transmit(&mut self, _buf: &[u8]) -> Result<(), TxError> {
+        Ok(())
+    }
+
+    async fn transmit_hard_reset(&mut self) -> Result<(), TxError> {
+        Ok(())
+    }
+
+    async fn transmit_sop(&mut self, sop: Sop, buf: &[u8]) -> Result<(), TxError> {
+        self.0.set(self.0.get() + 1);
+        match sop {
+            Sop::Sop => self.transmit(buf).await,
+            _ => Err(TxError::Unsupported),
+        }
+    }
+}
+
+#[test]
+fn unsupported_sop_is_not_retried() {
+    let attempts = Rc::new(Cell::new(0));
+    let mut engine = ProtocolEngine::new(SopOnlyPhy(attempts.clone()));
+    let request = [discover_identity()];
+    let request = Message::Data(DataMessageType::VendorDefined, &request);
+    assert!(!block_on(engine.transmit_sop(Sop::SopPrime, &request)).unwrap());
+    assert_eq!(attempts.get(), 1);
+}
+
+#[test]
+fn unsupported_bist_carrier() {
+    let mut engine = ProtocolEngine::new(SopOnlyPhy(Rc::default()));
+    assert!(!block_on(engine.transmit_bist_carrier(Duration::from_millis(45))).unwrap());
+}
@@ -0,0 +1,745 @@
+mod common;
+
+use bilge::prelude::*;
+use common::loopback::{Link, SimCc};
+use common::partner::{Received, SimPartner, PDO_5V_3A};
+use common::{run, run_sink, run_sink_with, Sink, OPERATING_CURRENT_MA, SINK_CONFIG};
+use embassy_futures::block_on;
+use embassy_futures::select::select;
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use usb_pd::phy::RpLevel;
+use usb_pd::policy_engine::PolicyEngine;
+use usb_pd::protocol::source_capabilities::{FixedSupply, PowerDataObject};
+use usb_pd::protocol::*;
+use usb_pd::protocol_engine::{HardReset, ProtocolEngine};
+use usb_pd::sink_policy::{
+    PowerRange, PpsSetpoint, Preference, Selection, SinkCommand, SinkCommands, SinkConfig,
+    SinkPolicy,
+};
+
+const PDO_9V_3A: u32 = (180 << 10) | 300;
+const PDO_20V_2A: u32 = (400 << 10) | 200;
+const PDO_PPS_3V3_11V_3A: u32 = 0xC0DC_213C;
+
+#[test]
+fn negotiation() {
+    run_sink(|mut source| async move {
+        let request = source.negotiate(&[PDO_5V_3A]).await;
+        assert_eq!(request.object_position(), u3::new(1));
+        assert_eq!(
+            request.operating_curent().value(),
+            (OPERATING_CURRENT_MA / 10) as u16
+        );
+
+        source.send_control(ControlMessageType::GetSinkCap).await;
+        source.expect_data(DataMessageType::SinkCapabilities).await;
+    })
+    .unwrap();
+}
+
+#[test]
+fn missing_source_capabilities_are_requested() {
+    let start = Instant::now();
+    run_sink(|mut source| async move {
+        source
+            .expect_control(ControlMessageType::GetSourceCap)
+            .await;
+        assert!(start.elapsed() >= Duration::from_millis(250));
+        let request = source.negotiate(&[PDO_5V_3A]).await;
+        assert_eq!(request.object_position(), u3::new(1));
+
+        source.send_control(ControlMessageType::GetSinkCap).await;
+        source.expect_data(DataMessageType::SinkCapabilities).await;
+    })
+    .unwrap();
+}
+
+#[test]
+fn missing_source_capabilities_are_requested_without_sink_tx_ok() {
+    for rp_level in [RpLevel::Default, RpLevel::Current1_5A] {
+        let cc = SimCc::new(rp_level);
+        let start = Instant::now();
+        let result = run(
+            Sink,
+            |pe| PolicyEngine::new(pe.with_cc_phy(&cc), SINK_CONFIG),
+            |mut source| async move {
+                source
+                    .expect_control(ControlMessageType::GetSourceCap)
+                    .await;
+                let elapsed = start.elapsed();
+                assert!(elapsed >= Duration::from_millis(250));
+                assert!(elapsed < Duration::from_millis(310));
+                assert_eq!(source.expect_any().await, Received::HardReset);
+                core::future::pending::<()>().await;
+            },
+        );
+        assert!(matches!(result, Err(HardReset)));
+    }
+}
+
+#[test]
+fn missing_source_capabilities_trigger_hard_reset() {
+    let start = Instant::now();
+    let result = run_sink(|mut source| async move {
+        source
+            .expect_control(ControlMessageType::GetSourceCap)
+            .await;
+        assert_eq!(source.expect_any().await, Received::HardReset);
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(310));
+        assert!(elapsed <= Duration::from_millis(620));
+        core::future::pending::<()>().await;
+    });
+    assert!(matches!(result, Err(HardReset)));
+}
+
+#[test]
+fn sink_wait_cap_spans_message_exchanges() {
+    let cc = SimCc::new(RpLevel::Current1_5A);
+    let start = Instant::now();
+    let result = run(
+        Sink,
+        |pe| PolicyEngine::new(pe.with_cc_phy(&cc), SINK_CONFIG),
+        |mut source| async move {
+            source
+                .expect_control(ControlMessageType::GetSourceCap)
+                .await;
+            // Keep the sink busy, the timer keeps running meanwhile.
+            for _ in 0..4 {
+                source.send_control(ControlMessageType::Ping).await;
+                Timer::after_millis(50).await;
+            }
+            assert_eq!(source.expect_any().await, Received::HardReset);
+            let elapsed = start.elapsed();
+            assert!(elapsed >= Duration::from_millis(310));
+            assert!(elapsed <= Duration::from_millis(620));
+            core::future::pending::<()>().await;
+        },
+    );
+    assert!(matches!(result, Err(HardReset)));
+}
+
+#[test]
+fn sink_gives_up_after_hard_reset_count() {
+    let link: &'static Link = Box::leak(Box::new(Link::new()));
+    let (sink_phy, source_phy) = link.split();
+    let mut sink = PolicyEngine::new(ProtocolEngine::new(sink_phy), SINK_CONFIG);
+    let mut source = SimPartner::new(source_phy);
+    let restart_sink = async {
+        loop {
+            let _ = sink.run_sink().await;
+        }
+    };
+    let script = async {
+        for _ in 0..3 {
+            source
+                .expect_control(ControlMessageType::GetSourceCap)
+                .await;
+            assert_eq!(source.expect_any().await, Received::HardReset);
+        }
+        // Only Get_Source_Cap, the sink stays on the Type-C current.
+        source
+            .expect_control(ControlMessageType::GetSourceCap)
+            .await;
+        source.expect_nothing(Duration::from_secs(1)).await;
+
+        // A contract resets the counter.
+        source.negotiate(&[PDO_5V_3A]).await;
+        source.send_control(ControlMessageType::SoftReset).await;
+        source.expect_control(ControlMessageType::Accept).await;
+        source
+            .expect_control(ControlMessageType::GetSourceCap)
+            .await;
+        assert_eq!(source.expect_any().await, Received::HardReset);
+    };
+    block_on(select(restart_sink, script));
+}
+
+#[test]
+fn commanded_hard_resets_do_not_count() {
+    static COMMANDS: SinkCommands = SinkCommands::new();
+    let link: &'static Link = Box::leak(Box::new(Link::new()));
+    let (sink_phy, source_phy) = link.split();
+    let config = SinkConfig {
+        commands: Some(&COMMANDS),
+        ..SINK_CONFIG
+    };
+    let mut sink = PolicyEngine::new(ProtocolEngine::new(sink_phy), config);
+    let mut source = SimPartner::new(source_phy);
+    let restart_sink = async {
+        loop {
+            let _ = sink.run_sink().await;
+        }
+    };
+    let script = async {
+        for _ in 0..3 {
+            source.negotiate(&[PDO_5V_3A]).await;
+            COMMANDS.send(SinkCommand::HardReset).await;
+            assert_eq!(source.expect_any().await, Received::HardReset);
+        }
+        // The full budget is still available for missing capabilities.
+        for _ in 0..3 {
+            source
+                .expect_control(ControlMessageType::GetSourceCap)
+                .await;
+            assert_eq!(source.expect_any().await, Received::HardReset);
+        }
+    };
+    block_on(select(restart_sink, script));
+}
+
+#[test]
+fn missing_ps_rdy_counts_toward_hard_reset_count() {
+    let link: &'static Link = Box::leak(Box::new(Link::new()));
+    let (sink_phy, source_phy) = link.split();
+    let mut sink = PolicyEngine::new(ProtocolEngine::new(sink_phy), SINK_CONFIG);
+    let mut source = SimPartner::new(source_phy);
+    let restart_sink = async {
+        loop {
+            let _ = sink.run_sink().await;
+        }
+    };
+    let script = async {
+        for _ in 0..3 {
+            source.send_source_capabilities(&[PDO_5V_3A]).await;
+            source.send_control(ControlMessageType::Accept).await;
+            assert_eq!(source.expect_any().await, Received::HardReset);
+        }
+        // The sink stops without resetting the source again.
+        source.send_source_capabilities(&[PDO_5V_3A]).await;
+        source.send_control(ControlMessageType::Accept).await;
+        source
+            .expect_control(ControlMessageType::GetSourceCap)
+            .await;
+    };
+    block_on(select(restart_sink, script));
+}
+
+#[test]
+fn wait_repeats_request() {
+    run_sink(|mut source| async move {
+        source.negotiate(&[PDO_5V_3A]).await;
+        source.send_source_capabilities(&[PDO_5V_3A]).await;
+        // The sink starts tSinkRequest once it received Wait.
+        let start = Instant::now();
+        source.send_control(ControlMessageType::Wait).await;
+
+        // The sink stays responsive while waiting.
+        source.send_control(ControlMessageType::GetSinkCap).await;
+        source.expect_data(DataMessageType::SinkCapabilities).await;
+        source.expect_data(DataMessageType::Request).await;
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        source.send_control(ControlMessageType::Accept).await;
+        source.send_control(ControlMessageType::PsRdy).await;
+        source.expect_nothing(Duration::from_millis(200)).await;
+    })
+    .unwrap();
+}
+
+#[test]
+fn reject_keeps_contract() {
+    run_sink(|mut source| async move {
+        source.negotiate(&[PDO_5V_3A]).await;
+        source.send_source_capabilities(&[PDO_5V_3A]).await;
+        source.send_control(ControlMessageType::Reject).await;
+        source.expect_nothing(Duration::from_millis(700)).await;
+        source.send_control(ControlMessageType::GetSinkCap).await;
+        source.expect_data(DataMessageType::SinkCapabilities).await;
+    })
+    .unwrap();
+}
+
+#[test]
+fn reject_without_contract_falls_back_to_vsafe5v() {
+    let config = SinkConfig {
+        ranges: &[PowerRange::fixed(5000, 1500), PowerRange::fixed(9000, 1500)],
+        preference: Preference::HighestPower,
+        pps: None,
+        commands: None,
+        give_back_current_ma: None,
+    };
+    run_sink_with(config, |mut source| async move {
+        let request = source
+            .send_source_capabilities(&[PDO_5V_3A, PDO_9V_3A])
+            .await;
+        assert_eq!(request.object_position(), u3::new(2));
+        source.send_control(ControlMessageType::Reject).await;
+        let request = Request::from(source.expect_data(DataMessageType::Request).await[0]);
+        assert_eq!(request.object_position(), u3::new(1));
+        assert!(request.capability_mismatch());
+        source.send_control(ControlMessageType::Accept).await;
+        source.send_control(ControlMessageType::PsRdy).await;
+    })
+    .unwrap();
+}
+
+#[test]
+fn rejected_vsafe5v_waits_for_capabilities() {
+    let result = run_sink(|mut source| async move {
+        source.send_source_capabilities(&[PDO_5V_3A]).await;
+        source.send_control(ControlMessageType::Reject).await;
+        let start = Instant::now();
+        assert_eq!(source.expect_any().await, Received::HardReset);
+        assert!(start.elapsed() >= Duration::from_millis(310));
+        core::future::pending::<()>().await;
+    });
+    assert!(matches!(result, Err(HardReset)));
+}
+
+#[test]
+fn negotiation_selects_configured_voltage() {
+    let config = SinkConfig {
+        ranges: &[PowerRange::new(5000, 12000, 500, 2000)],
+        preference: Preference::HighestVoltage,
+        pps: None,
+        commands: None,
+        give_back_current_ma: None,
+    };
+    run_sink_with(config, |mut source| async move {
+        let request = source.negotiate(&[PDO_5V_3A, PDO_9V_3A, PDO_20V_2A]).await;
+        assert_eq!(request.object_position(), u3::new(2));
+        assert_eq!(request.operating_curent().value(), 200);
+        assert_eq!(request.min_operating_current().value(), 200);
+        assert!(!request.capability_mismatch());
+    })
+    .unwrap();
+}
+
+#[test]
+fn negotiation_capability_mismatch() {
+    let config = SinkConfig {
+        ranges: &[PowerRange::fixed(5000, 500), PowerRange::fixed(15000, 3000)],
+        preference: Preference::ExactVoltage(15000),
+        pps: None,
+        commands: None,
+        give_back_current_ma: None,
+    };
+    run_sink_with(config, |mut source| async move {
+        let pdo_5v_300ma = FixedSupply::from_mv_ma(5000, 300).into();
+        let request = source.negotiate(&[pdo_5v_300ma, PDO_9V_3A]).await;
+        assert_eq!(request.object_position(), u3::new(1));
+        assert_eq!(request.operating_curent().value(), 30);
+        assert!(request.capability_mismatch());
+    })
+    .unwrap();
+}
+
+/// Policy always making the same selection, whether it is valid or not.
+struct SelectionPolicy(Selection);
+
+impl SinkPolicy for SelectionPolicy {
+    fn select(&mut self, _source_capabilities: &[PowerDataObject]) -> Selection {
+        self.0
+    }
+
+    fn sink_capabilities(&self, pdos: &mut [u32]) -> usize {
+        SINK_CONFIG.sink_capabilities(pdos)
+    }
+}
+
+/// Selection of the fixed supply at `object_position`.
+fn fixed_selection(object_position: u8, current_ma: u32) -> Selection {
+    Selection {
+        object_position,
+        operating_current_ma: current_ma,
+        max_operating_current_ma: current_ma,
+        capability_mismatch: false,
+        pps_voltage_mv: None,
+        min_operating_current_ma: None,
+    }
+}
+
+/// Runs the sink with `selection` against a 5V and a 9V supply and checks
+/// that it requests vSafe5V at `current_ma` instead.
+fn assert_vsafe5v_fallback(selection: Selection, current_ma: u32) {
+    run(
+        Sink,
+        |pe| PolicyEngine::new(pe, SelectionPolicy(selection)),
+        |mut source| async move {
+            let request = source.negotiate(&[PDO_5V_3A, PDO_9V_3A]).await;
+            assert_eq!(request.object_position(), u3::new(1));
+            assert_eq!(request.operating_curent().value(), (current_ma / 10) as u16);
+            assert!(request.capability_mismatch());
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn invalid_object_position_falls_back_to_vsafe5v() {
+    for object_position in [0, 3, 8] {
+        assert_vsafe5v_fallback(
+            fixed_selection(object_position, OPERATING_CURRENT_MA),
+            OPERATING_CURRENT_MA,
+        );
+    }
+}
+
+#[test]
+fn oversized_selection_falls_back_to_vsafe5v() {
+    let pps = |voltage_mv, current_ma| Selection {
+        pps_voltage_mv: Some(voltage_mv),
+        ..fixed_selection(2, current_ma)
+    };
+    // The current is limited to the 3A of vSafe5V.
+    for selection in [
+        fixed_selection(2, 20_000),
+        Selection {
+            min_operating_current_ma: Some(20_000),
+            ..fixed_selection(2, OPERATING_CURRENT_MA)
+        },
+        pps(9000, 7000),
+        pps(90_000, OPERATING_CURRENT_MA),
+    ] {
+        let current_ma = selection.operating_current_ma.min(3000);
+        assert_vsafe5v_fallback(selection, current_ma);
+    }
+}
+
+#[test]
+fn revision_2_source() {
+    run_sink(|mut source| async move {
+        source.negotiate(&[PDO_5V_3A]).await;
+        let header = source.rx_header.unwrap();
+        assert_eq!(
+            header.specification_revision(),
+            SpecificationRevision::Revision2_0
+        );
+
+        // Revision 2.0 has no Not_Supported message.
+        source.send_control(ControlMessageType::GetSourceCap).await;
+        source.expect_control(ControlMessageType::Reject).await;
+    })
+    .unwrap();
+}
+
+#[test]
+fn revision_3_source() {
+    run_sink(|mut source| async move {
+        source.revision = SpecificationRevision::Revision3_0;
+        source.negotiate(&[PDO_5V_3A]).await;
+        let header = source.rx_header.unwrap();
+        assert_eq!(
+            header.specification_revision(),
+            SpecificationRevision::Revision3_0
+        );
+
+        source.send_control(ControlMessageType::GetSourceCap).await;
+        source
+            .expect_control(ControlMessageType::NotSupported)
+            .await;
+    })
+    .unwrap();
+}
+
+#[test]
+fn revision_3_retry_count() {
+    run_sink(|mut source| async move {
+        source.revision = SpecificationRevision::Revision3_0;
+        source.drop_goodcrc(3);
+        source
+            .send_data(DataMessageType::SourceCapabilites, &[PDO_5V_3A])
+            .await;
+        for _ in 0..3 {
+            source.expect_data(DataMessageType::Request).await;
+        }
+        source.expect_control(ControlMessageType::SoftReset).await;
+        source.send_control(ControlMessageType::Accept).await;
+
+        source.negotiate(&[PDO_5V_3A]).await;
+    })
+    .unwrap();
+}
+
+const RANGES_5V: [PowerRange; 1] = [PowerRange::fixed(5000, 500)];
+
+fn pps_config(pps: &PpsSetpoint) -> SinkConfig<'_> {
+    SinkConfig {
+        ranges: &RANGES_5V,
+        preference: Preference::HighestPower,
+        pps: Some(pps),
+        commands: None,
+        give_back_current_ma: None,
+    }
+}
+
+#[test]
+fn pps_negotiation() {
+    let pps = PpsSetpoint::new(9000, 2000);
+    run_sink_with(pps_config(&pps), |mut source| async move {
+        let request = source.negotiate(&[PDO_5V_3A, PDO_PPS_3V3_11V_3A]).await;
+        let request = PpsRequest::from(u32::from(request));
+        assert_eq!(request.object_position(), u3::new(2));
+        assert_eq!(request.output_voltage().value(), 450);
+        assert_eq!(request.operating_current().value(), 40);
+    })
+    .unwrap();
+}
+
+#[test]
+fn pps_setpoint_change() {
+    let pps = PpsSetpoint::new(9000, 2000);
+    let pps = &pps;
+    run_sink_with(pps_config(pps), |mut source| async move {
+        source.negotiate(&[PDO_5V_3A, PDO_PPS_3V3_11V_3A]).await;
+
+        pps.set(5500, 3000);
+        let objects = source.expect_data(DataMessageType::Request).await;
+        let request = PpsRequest::from(objects[0]);
+        assert_eq!(request.object_position(), u3::new(2));
+        assert_eq!(request.output_voltage().value(), 275);
+        assert_eq!(request.operating_current().value(), 60);
+        source.send_control(ControlMessageType::Accept).await;
+        source.send_control(ControlMessageType::PsRdy).await;
+
+        // Not supported by the source, fall back to the fixed supply.
+        pps.set(12000, 1000);
+        let objects = source.expect_data(DataMessageType::Request).await;
+        assert_eq!(Request::from(objects[0]).object_position(), u3::new(1));
+    })
+    .unwrap();
+}
+
+#[test]
+fn pps_periodic_request() {
+    let pps = PpsSetpoint::new(9000, 2000);
+    run_sink_with(pps_config(&pps), |mut source| async move {
+        source.negotiate(&[PDO_5V_3A, PDO_PPS_3V3_11V_3A]).await;
+        let contract = Instant::now();
+
+        let msg = with_timeout(Duration::from_secs(11), source.receive())
+            .await
+            .expect("PPS request not repeated");
+        assert!(contract.elapsed() > Duration::from_secs(9));
+        let Received::Data(DataMessageType::Request, objects) = msg else {
+            panic!("Expected Request, received {msg:?}");
+        };
+        assert_eq!(PpsRequest::from(objects[0]).output_voltage().value(), 450);
+        source.send_control(ControlMessageType::Accept).await;
+        source.send_control(ControlMessageType::PsRdy).await;
+    })
+    .unwrap();
+}
+
+#[test]
+fn pps_periodic_request_spans_messages() {
+    let pps = PpsSetpoint::new(9000, 2000);
+    run_sink_with(pps_config(&pps), |mut source| async move {
+        source.negotiate(&[PDO_5V_3A, PDO_PPS_3V3_11V_3A]).await;
+        let contract = Instant::now();
+        for _ in 0..3 {
+            source.expect_nothing(Duration::from_secs(3)).await;
+            source.send_control(ControlMessageType::Ping).await;
+        }
+
+        let msg = with_timeout(Duration::from_secs(2), source.receive())
+            .await
+            .expect("PPS request not repeated");
+        assert!(contract.elapsed() > Duration::from_secs(9));
+        assert!(contract.elapsed() < Duration::from_millis(10_500));
+        assert!(matches!(msg, Received::Data(DataMessageType::Request, _)));
+        source.send_control(ControlMessageType::Accept).await;
+        source.send_control(ControlMessageType::PsRdy).await;
+    })
+    .unwrap();
+}
+
+#[test]
+fn rejected_pps_setpoint_is_restored() {
+    let pps = PpsSetpoint::new(9000, 2000);
+    let pps = &pps;
+    run_sink_with(pps_config(pps), |mut source| async move {
+        source.negotiate(&[PDO_5V_3A, PDO_PPS_3V3_11V_3A]).await;
+
+        pps.set(10000, 2000);
+        let request = PpsRequest::from(source.expect_data(DataMessageType::Request).await[0]);
+        assert_eq!(request.output_voltage().value(), 500);
+        source.send_control(ControlMessageType::Reject).await;
+        source.expect_nothing(Duration::from_millis(100)).await;
+        assert_eq!(pps.get(), (9000, 2000));
+
+        // The periodic request keeps asking for the contract voltage.
+        let msg = with_timeout(Duration::from_secs(11), source.receive())
+            .await
+            .expect("PPS request not repeated");
+        let Received::Data(DataMessageType::Request, objects) = msg else {
+            panic!("Expected Request, received {msg:?}");
+        };
+        assert_eq!(PpsRequest::from(objects[0]).output_voltage().value(), 450);
+        source.send_control(ControlMessageType::Accept).await;
+        source.send_control(ControlMessageType::PsRdy).await;
+    })
+    .unwrap();
+}
+
+#[test]
+fn chunked_extended_message() {
+    run_sink(|mut source| async move {
+        source.revision = SpecificationRevision::Revision3_0;
+        source.negotiate(&[PDO_5V_3A]).await;
+
+        let payload: Vec<u8> = (0..60).collect();
+        source
+            .send_extended(ExtendedMessageType::SecurityRequest, &payload)
+            .await;
+        source
+            .expect_control(ControlMessageType::NotSupported)
+            .await;
+    })
+    .unwrap();
+}
+
+#[test]
+fn collision_avoidance() {
+    let pps = PpsSetpoint::new(9000, 2000);
+    let pps = &pps;
+    let cc = SimCc::new(RpLevel::Current3_0A);
+    let cc = &cc;
+    run(
+        Sink,
+        |pe| PolicyEngine::new(pe.with_cc_phy(cc), pps_config(pps)),
+        |mut source| async move {
+            source.revision = SpecificationRevision::Revision3_0;
+            source.negotiate(&[PDO_5V_3A, PDO_PPS_3V3_11V_3A]).await;
+
+            cc.set(RpLevel::Current1_5A);
+            pps.set(5500, 3000);
+            source.expect_nothing(Duration::from_millis(50)).await;
+
+            // The sink must still respond while waiting for SinkTxOk.
+            source.send_control(ControlMessageType::GetSinkCap).await;
+            source.expect_data(DataMessageType::SinkCapabilities).await;
+            source.expect_nothing(Duration::from_millis(50)).await;
+
+            cc.set(RpLevel::Current3_0A);
+            let objects = source.expect_data(DataMessageType::Request).await;
+            assert_eq!(PpsRequest::from(objects[0]).output_voltage().value(), 275);
+            source.send_control(ControlMessageType::Accept).await;
+            source.send_control(ControlMessageType::PsRdy).await;
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn no_collision_avoidance_with_revision_2() {
+    let pps = PpsSetpoint::new(9000, 2000);
+    let pps = &pps;
+    let cc = SimCc::new(RpLevel::Current1_5A);
+    run(
+        Sink,
+        |pe| PolicyEngine::new(pe.with_cc_phy(&cc), pps_config(pps)),
+        |mut source| async move {
+            source.negotiate(&[PDO_5V_3A, PDO_PPS_3V3_11V_3A]).await;
+
+            pps.set(5500, 3000);
+            source.expect_data(DataMessageType::Request).await;
+            source.send_control(ControlMessageType::Accept).await;
+            source.send_control(ControlMessageType::PsRdy).await;
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn request_rejected() {
+    run_sink(|mut source| async move {
+        source.send_source_capabilities(&[PDO_5V_3A]).await;
+        source.send_control(ControlMessageType::Reject).await;
+        source.expect_nothing(Duration::from_millis(50)).await;
+
+        source.negotiate(&[PDO_5V_3A]).await;
+    })
+    .unwrap();
+}
+
+#[test]
+fn request_wait() {
+    run_sink(|mut source| async move {
+        source.send_source_capabilities(&[PDO_5V_3A]).await;
+        source.send_control(ControlMessageType::Wait).await;
+
+        source.negotiate(&[PDO_5V_3A]).await;
+    })
+    .unwrap();
+}
+
+#[test]
+fn missing_goodcrc_is_retried() {
+    run_sink(|mut source| async move {
+        source.drop_goodcrc(2);
+        source
+            .send_data(DataMessageType::SourceCapabilites, &[PDO_5V_3A])
+            .await;
+        source.expect_data(DataMessageType::Request).await;
+        let message_id = source.rx_header.map(|h| h.message_id());
+        source.expect_data(DataMessageType::Request).await;
+        assert_eq!(source.rx_header.map(|h| h.message_id()), message_id);
+        source.expect_data(DataMessageType::Request).await;
+        assert_eq!(source.rx_header.map(|h| h.message_id()), message_id);
+
+        source.send_control(ControlMessageType::Accept).await;
+        source.send_control(ControlMessageType::PsRdy).await;
+    })
+    .unwrap();
+}
+
+#[test]
+fn corrupted_message_is_ignored() {
+    run_sink(|mut source| async move {
+        source.corrupt(2);
+        source.negotiate(&[PDO_5V_3A]).await;
+    })
+    .unwrap();
+}
+
+#[test]
+fn unacknowledged_request_triggers_soft_reset() {
+    run_sink(|mut source| async move {
+        source.drop_goodcrc(4);
+        source
+            .send_data(DataMessageType::SourceCapabilites, &[PDO_5V_3A])
+            .await;
+        for _ in 0..4 {
+            source.expect_data(DataMessageType::Request).await;
+        }
+        source.expect_control(ControlMessageType::SoftReset).await;
+        source.send_control(ControlMessageType::Accept).await;
+
+        source.negotiate(&[PDO_5V_3A]).await;
+    })
+    .unwrap();
+}
+
+#[test]
+fn soft_reset_from_source() {
+    run_sink(|mut source| async move {
+        source.negotiate(&[PDO_5V_3A]).await;
+
+        source.send_control(ControlMessageType::SoftReset).await;
+        source.expect_control(ControlMessageType::Accept).await;
+
+        source.negotiate(&[PDO_5V_3A]).await;
+    })
+    .unwrap();
+}
+
+#[test]
+fn missing_ps_rdy_stops_sink() {
+    let result = run_sink(|mut source| async move {
+        source.send_source_capabilities(&[PDO_5V_3A]).await;
+        source.send_control(ControlMessageType::Accept).await;
+        core::future::pending::<()>().await;
+    });
+    assert!(matches!(result, Err(HardReset)));
+}
+
+#[test]
+fn hard_reset_from_source() {
+    let result = run_sink(|mut source| async move {
+        source.negotiate(&[PDO_5V_3A]).await;
+        source.send_hard_reset().await;
+        core::future::pending::<()>().await;
+    });
+    assert!(matches!(result, Err(HardReset)));
+}
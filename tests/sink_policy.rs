@@ -0,0 +1,141 @@
+use usb_pd::protocol::source_capabilities::*;
+use usb_pd::sink_policy::*;
+
+fn source_capabilities() -> [PowerDataObject; 5] {
+    [
+        PowerDataObject::FixedSupply(FixedSupply::from_mv_ma(5000, 3000)),
+        PowerDataObject::FixedSupply(FixedSupply::from_mv_ma(9000, 3000)),
+        PowerDataObject::FixedSupply(FixedSupply::from_mv_ma(15000, 3000)),
+        PowerDataObject::FixedSupply(FixedSupply::from_mv_ma(20000, 2250)),
+        PowerDataObject::Pps(SprPps::from_mv_ma(3300, 21000, 3000)),
+    ]
+}
+
+fn select(ranges: &[PowerRange], preference: Preference) -> Selection {
+    SinkConfig {
+        ranges,
+        preference,
+        pps: None,
+        commands: None,
+        give_back_current_ma: None,
+    }
+    .select(&source_capabilities())
+}
+
+fn selection(object_position: u8, current_ma: u32) -> Selection {
+    Selection {
+        object_position,
+        operating_current_ma: current_ma,
+        max_operating_current_ma: current_ma,
+        capability_mismatch: false,
+        pps_voltage_mv: None,
+        min_operating_current_ma: None,
+    }
+}
+
+#[test]
+fn highest_power() {
+    // 15V 3A and 20V 2.25A are equal, the lower position wins.
+    let ranges = [PowerRange::new(5000, 20000, 1000, 3000)];
+    assert_eq!(
+        select(&ranges, Preference::HighestPower),
+        selection(3, 3000)
+    );
+
+    let ranges = [PowerRange::new(5000, 20000, 1000, 2000)];
+    assert_eq!(
+        select(&ranges, Preference::HighestPower),
+        selection(4, 2000)
+    );
+    let ranges = [PowerRange::new(5000, 20000, 2500, 3000)];
+    assert_eq!(
+        select(&ranges, Preference::HighestPower),
+        selection(3, 3000)
+    );
+}
+
+#[test]
+fn highest_voltage() {
+    let ranges = [PowerRange::new(5000, 15000, 500, 2000)];
+    assert_eq!(
+        select(&ranges, Preference::HighestVoltage),
+        selection(3, 2000)
+    );
+}
+
+#[test]
+fn exact_voltage() {
+    let ranges = [PowerRange::new(5000, 20000, 500, 2000)];
+    assert_eq!(
+        select(&ranges, Preference::ExactVoltage(9000)),
+        selection(2, 2000)
+    );
+}
+
+#[test]
+fn multiple_ranges() {
+    let ranges = [
+        PowerRange::fixed(5000, 500),
+        PowerRange::fixed(9000, 3000),
+        PowerRange::fixed(20000, 3000),
+    ];
+    assert_eq!(
+        select(&ranges, Preference::HighestVoltage),
+        selection(2, 3000)
+    );
+}
+
+#[test]
+fn capability_mismatch() {
+    let ranges = [
+        PowerRange::new(5000, 5000, 100, 900),
+        PowerRange::fixed(12000, 2000),
+    ];
+    let expected = Selection {
+        object_position: 1,
+        operating_current_ma: 900,
+        max_operating_current_ma: 900,
+        capability_mismatch: true,
+        pps_voltage_mv: None,
+        min_operating_current_ma: None,
+    };
+    assert_eq!(select(&ranges, Preference::ExactVoltage(12000)), expected);
+}
+
+#[test]
+fn give_back() {
+    let ranges = [PowerRange::fixed(9000, 2000)];
+    let mut config = SinkConfig {
+        ranges: &ranges,
+        preference: Preference::HighestPower,
+        pps: None,
+        commands: None,
+        give_back_current_ma: Some(300),
+    };
+    let expected = Selection {
+        min_operating_current_ma: Some(300),
+        ..selection(2, 2000)
+    };
+    assert_eq!(config.select(&source_capabilities()), expected);
+}
+
+#[test]
+fn sink_capabilities() {
+    let ranges = [
+        PowerRange::fixed(5000, 900),
+        PowerRange::new(9000, 15000, 1000, 2000),
+    ];
+    let config = SinkConfig {
+        ranges: &ranges,
+        preference: Preference::HighestPower,
+        pps: None,
+        commands: None,
+        give_back_current_ma: None,
+    };
+    let mut pdos = [0; 7];
+    assert_eq!(config.sink_capabilities(&mut pdos), 2);
+    // vSafe5V 900mA with higher capability flag
+    assert_eq!(pdos[0], (1 << 28) | (100 << 10) | 90);
+    // Variable 9-15V 2A
+    assert_eq!(pdos[1], (0b10 << 30) | (300 << 20) | (180 << 10) | 200);
+}
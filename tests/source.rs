@@ -0,0 +1,201 @@
+mod common;
+
+use bilge::prelude::*;
+use common::partner::Received;
+use common::supply::{SimSupply, SupplyEvent};
+use common::{run, source_config, Source};
+use embassy_time::{Duration, Instant};
+use usb_pd::policy_engine::PolicyEngine;
+use usb_pd::protocol::source_capabilities::*;
+use usb_pd::protocol::*;
+use usb_pd::protocol_engine::HardReset;
+use usb_pd::sink_policy::Selection;
+
+fn capabilities() -> [PowerDataObject; 3] {
+    [
+        PowerDataObject::FixedSupply(FixedSupply::from_mv_ma(5000, 3000)),
+        PowerDataObject::FixedSupply(FixedSupply::from_mv_ma(9000, 2000)),
+        PowerDataObject::Pps(SprPps::from_mv_ma(3300, 11000, 3000)),
+    ]
+}
+
+fn request(object_position: u8, current_ma: u32) -> u32 {
+    let current = u10::new((current_ma / 10) as u16);
+    Request::new(
+        current,
+        current,
+        u4::new(0),
+        false,
+        false,
+        false,
+        false,
+        u3::new(object_position),
+        false,
+    )
+    .into()
+}
+
+fn pps_request(object_position: u8, voltage_mv: u32, current_ma: u32) -> u32 {
+    PpsRequest::new(
+        u7::new((current_ma / 50) as u8),
+        u2::new(0),
+        u12::new((voltage_mv / 20) as u16),
+        false,
+        false,
+        false,
+        false,
+        false,
+        false,
+        false,
+        u3::new(object_position),
+        false,
+    )
+    .into()
+}
+
+fn selection(object_position: u8, current_ma: u32, pps_voltage_mv: Option<u32>) -> Selection {
+    Selection {
+        object_position,
+        operating_current_ma: current_ma,
+        max_operating_current_ma: current_ma,
+        capability_mismatch: false,
+        pps_voltage_mv,
+        min_operating_current_ma: None,
+    }
+}
+
+const ACCEPT: Received = Received::Control(ControlMessageType::Accept);
+const REJECT: Received = Received::Control(ControlMessageType::Reject);
+
+#[test]
+fn negotiation() {
+    let supply = SimSupply::default();
+    let supply = &supply;
+    run(
+        Source(supply),
+        |pe| PolicyEngine::new(pe, source_config(&capabilities())),
+        |mut sink| async move {
+            let pdos = sink.expect_data(DataMessageType::SourceCapabilites).await;
+            let expected: Vec<u32> = capabilities().into_iter().map(u32::from).collect();
+            assert_eq!(pdos, expected);
+
+            assert_eq!(sink.request(request(2, 1500)).await, ACCEPT);
+            sink.expect_control(ControlMessageType::PsRdy).await;
+            assert_eq!(
+                supply.take_events(),
+                [SupplyEvent::Transition(selection(2, 1500, None))]
+            );
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn pps_negotiation() {
+    let supply = SimSupply::default();
+    let supply = &supply;
+    run(
+        Source(supply),
+        |pe| PolicyEngine::new(pe, source_config(&capabilities())),
+        |mut sink| async move {
+            sink.expect_data(DataMessageType::SourceCapabilites).await;
+            assert_eq!(sink.request(pps_request(3, 12000, 2000)).await, REJECT);
+
+            sink.send_control(ControlMessageType::GetSourceCap).await;
+            sink.expect_data(DataMessageType::SourceCapabilites).await;
+            assert_eq!(sink.request(pps_request(3, 9000, 2000)).await, ACCEPT);
+            sink.expect_control(ControlMessageType::PsRdy).await;
+            assert_eq!(
+                supply.take_events(),
+                [SupplyEvent::Transition(selection(3, 2000, Some(9000)))]
+            );
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn invalid_requests_are_rejected() {
+    let supply = SimSupply::default();
+    let supply = &supply;
+    run(
+        Source(supply),
+        |pe| PolicyEngine::new(pe, source_config(&capabilities())),
+        |mut sink| async move {
+            sink.expect_data(DataMessageType::SourceCapabilites).await;
+            // More current than offered.
+            assert_eq!(sink.request(request(2, 2500)).await, REJECT);
+            // Invalid object position.
+            sink.send_control(ControlMessageType::GetSourceCap).await;
+            sink.expect_data(DataMessageType::SourceCapabilites).await;
+            assert_eq!(sink.request(request(4, 1000)).await, REJECT);
+            assert_eq!(supply.take_events(), []);
+
+            // The sink may still request a valid capability.
+            sink.send_data(DataMessageType::Request, &[request(1, 3000)])
+                .await;
+            sink.expect_control(ControlMessageType::Accept).await;
+            sink.expect_control(ControlMessageType::PsRdy).await;
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn unsupported_message() {
+    run(
+        Source(&SimSupply::default()),
+        |pe| PolicyEngine::new(pe, source_config(&capabilities())),
+        |mut sink| async move {
+            sink.revision = SpecificationRevision::Revision3_0;
+            sink.expect_data(DataMessageType::SourceCapabilites).await;
+            assert_eq!(sink.request(request(1, 1000)).await, ACCEPT);
+            sink.expect_control(ControlMessageType::PsRdy).await;
+
+            sink.send_control(ControlMessageType::GetSinkCap).await;
+            sink.expect_control(ControlMessageType::NotSupported).await;
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn source_capabilities_are_repeated() {
+    run(
+        Source(&SimSupply::default()),
+        |pe| PolicyEngine::new(pe, source_config(&capabilities())),
+        |mut sink| async move {
+            // All three attempts of the first message are lost.
+            sink.drop_goodcrc(3);
+            for _ in 0..3 {
+                sink.expect_data(DataMessageType::SourceCapabilites).await;
+            }
+            let start = Instant::now();
+            sink.expect_data(DataMessageType::SourceCapabilites).await;
+            assert!(start.elapsed() >= Duration::from_millis(100));
+
+            assert_eq!(sink.request(request(1, 1000)).await, ACCEPT);
+            sink.expect_control(ControlMessageType::PsRdy).await;
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn missing_request_triggers_hard_reset() {
+    let supply = SimSupply::default();
+    let result = run(
+        Source(&supply),
+        |pe| PolicyEngine::new(pe, source_config(&capabilities())),
+        |mut sink| async move {
+            sink.expect_data(DataMessageType::SourceCapabilites).await;
+            assert_eq!(sink.expect_any().await, Received::HardReset);
+            core::future::pending::<()>().await;
+        },
+    );
+    assert!(matches!(result, Err(HardReset)));
+    assert_eq!(
+        supply.take_events(),
+        [SupplyEvent::Disable, SupplyEvent::Enable]
+    );
+}
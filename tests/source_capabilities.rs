@@ -0,0 +1,116 @@
+use bilge::prelude::*;
+use usb_pd::protocol::source_capabilities::*;
+
+fn round_trip(raw: u32) -> PowerDataObject {
+    let pdo = PowerDataObject::from(raw);
+    assert_eq!(u32::from(pdo), raw);
+    pdo
+}
+
+#[test]
+fn first_fixed_supply() {
+    // 5V 3A, unconstrained power, dual-role data
+    let PowerDataObject::FixedSupply(pdo) = round_trip(0x0A01_912C) else {
+        panic!("Not a fixed supply");
+    };
+    assert_eq!(pdo.voltage_mv(), 5000);
+    assert_eq!(pdo.max_current_ma(), 3000);
+    assert_eq!(pdo.peak_current(), u2::new(0));
+    assert!(pdo.unconstrained_power());
+    assert!(pdo.dual_role_data());
+    assert!(!pdo.dual_role_power());
+    assert!(!pdo.usb_communications_capable());
+    assert!(!pdo.usb_suspend_supported());
+    assert!(!pdo.unchunked_extended_messages_supported());
+    assert!(!pdo.epr_mode_capable());
+}
+
+#[test]
+fn fixed_supply() {
+    let pdo = FixedSupply::from_mv_ma(20000, 5000);
+    assert_eq!(round_trip(pdo.into()), PowerDataObject::FixedSupply(pdo));
+    assert_eq!(u32::from(pdo), (400 << 10) | 500);
+}
+
+#[test]
+fn battery() {
+    let pdo = Battery::from_mv_mw(5000, 21000, 60000);
+    assert_eq!(round_trip(pdo.into()), PowerDataObject::Battery(pdo));
+    assert_eq!(pdo.min_voltage_mv(), 5000);
+    assert_eq!(pdo.max_voltage_mv(), 21000);
+    assert_eq!(pdo.max_power_mw(), 60000);
+}
+
+#[test]
+fn variable_supply() {
+    let pdo = VariableSupply::from_mv_ma(9000, 12000, 1500);
+    assert_eq!(round_trip(pdo.into()), PowerDataObject::VariableSupply(pdo));
+    assert_eq!(pdo.min_voltage_mv(), 9000);
+    assert_eq!(pdo.max_voltage_mv(), 12000);
+    assert_eq!(pdo.max_current_ma(), 1500);
+}
+
+#[test]
+fn pps() {
+    // 3.3V-11V 3A
+    let PowerDataObject::Pps(pdo) = round_trip(0xC0DC_213C) else {
+        panic!("Not a PPS APDO");
+    };
+    assert_eq!(pdo.min_voltage_mv(), 3300);
+    assert_eq!(pdo.max_voltage_mv(), 11000);
+    assert_eq!(pdo.max_current_ma(), 3000);
+    assert!(!pdo.pps_power_limited());
+    assert_eq!(pdo, SprPps::from_mv_ma(3300, 11000, 3000));
+}
+
+#[test]
+fn spr_avs() {
+    let pdo = SprAvs::from_ma(3000, 2250);
+    assert_eq!(round_trip(pdo.into()), PowerDataObject::SprAvs(pdo));
+    assert_eq!(pdo.max_current_15v_ma(), 3000);
+    assert_eq!(pdo.max_current_20v_ma(), 2250);
+}
+
+#[test]
+fn epr_avs() {
+    let pdo = EprAvs::from_mv_w(15000, 48000, 240);
+    assert_eq!(round_trip(pdo.into()), PowerDataObject::EprAvs(pdo));
+    assert_eq!(pdo.min_voltage_mv(), 15000);
+    assert_eq!(pdo.max_voltage_mv(), 48000);
+    assert_eq!(pdo.pdp_mw(), 240000);
+}
+
+#[test]
+fn out_of_range_values_are_clamped() {
+    let pdo = FixedSupply::from_mv_ma(60000, 20000);
+    assert_eq!(pdo.voltage_mv(), 51150);
+    assert_eq!(pdo.max_current_ma(), 10230);
+
+    let pdo = Battery::from_mv_mw(5000, 60000, 300_000);
+    assert_eq!(pdo.max_voltage_mv(), 51150);
+    assert_eq!(pdo.max_power_mw(), 255_750);
+
+    let pdo = VariableSupply::from_mv_ma(9000, 60000, 20000);
+    assert_eq!(pdo.max_voltage_mv(), 51150);
+    assert_eq!(pdo.max_current_ma(), 10230);
+
+    let pdo = SprPps::from_mv_ma(3300, 30000, 7000);
+    assert_eq!(pdo.max_voltage_mv(), 25500);
+    assert_eq!(pdo.max_current_ma(), 6350);
+
+    let pdo = SprAvs::from_ma(20000, 20000);
+    assert_eq!(pdo.max_current_15v_ma(), 10230);
+    assert_eq!(pdo.max_current_20v_ma(), 10230);
+
+    let pdo = EprAvs::from_mv_w(30000, 60000, 240);
+    assert_eq!(pdo.min_voltage_mv(), 25500);
+    assert_eq!(pdo.max_voltage_mv(), 51100);
+}
+
+#[test]
+fn reserved_apdo() {
+    assert_eq!(
+        round_trip(0xF000_0000),
+        PowerDataObject::Unknown(0xF000_0000)
+    );
+}
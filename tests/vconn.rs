@@ -0,0 +1,129 @@
+mod common;
+
+use common::partner::{Received, PDO_5V_3A};
+use common::supply::{SimSupply, SupplyEvent};
+use common::{request_5v, run, run_sink, vsafe5v_source_config, Sink, Source, SINK_CONFIG};
+use embassy_time::{Duration, Instant};
+use usb_pd::policy_engine::PolicyEngine;
+use usb_pd::protocol::source_capabilities::*;
+use usb_pd::protocol::*;
+use usb_pd::sink_policy::{Selection, SinkConfig, SinkPolicy};
+
+/// Sink that wants to be the DFP.
+struct DfpSink(SinkConfig<'static>);
+
+impl SinkPolicy for DfpSink {
+    fn select(&mut self, source_capabilities: &[PowerDataObject]) -> Selection {
+        self.0.select(source_capabilities)
+    }
+
+    fn sink_capabilities(&self, pdos: &mut [u32]) -> usize {
+        self.0.sink_capabilities(pdos)
+    }
+
+    fn preferred_data_role(&self) -> Option<PortDataRole> {
+        Some(PortDataRole::DownstreamFacingPort)
+    }
+}
+
+#[test]
+fn source_hands_over_vconn() {
+    let supply = SimSupply::default();
+    let supply = &supply;
+    run(
+        Source(supply),
+        |pe| PolicyEngine::new(pe, vsafe5v_source_config()).with_vconn(supply),
+        |mut sink| async move {
+            sink.expect_data(DataMessageType::SourceCapabilites).await;
+            assert_eq!(
+                sink.request(request_5v()).await,
+                Received::Control(ControlMessageType::Accept)
+            );
+            sink.expect_control(ControlMessageType::PsRdy).await;
+            assert_eq!(supply.take_events()[0], SupplyEvent::VconnEnable);
+
+            sink.send_control(ControlMessageType::VconnSwap).await;
+            sink.expect_control(ControlMessageType::Accept).await;
+            sink.send_control(ControlMessageType::PsRdy).await;
+
+            sink.send_control(ControlMessageType::GetSourceCap).await;
+            sink.expect_data(DataMessageType::SourceCapabilites).await;
+            assert_eq!(supply.take_events(), [SupplyEvent::VconnDisable]);
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn missing_ps_rdy_during_vconn_swap_triggers_hard_reset() {
+    let supply = SimSupply::default();
+    let supply = &supply;
+    run(
+        Source(supply),
+        |pe| PolicyEngine::new(pe, vsafe5v_source_config()).with_vconn(supply),
+        |mut sink| async move {
+            sink.expect_data(DataMessageType::SourceCapabilites).await;
+            sink.request(request_5v()).await;
+            sink.expect_control(ControlMessageType::PsRdy).await;
+            supply.take_events();
+
+            sink.send_control(ControlMessageType::VconnSwap).await;
+            sink.expect_control(ControlMessageType::Accept).await;
+            let start = Instant::now();
+            assert_eq!(sink.expect_any().await, Received::HardReset);
+            assert!(start.elapsed() >= Duration::from_millis(100));
+            assert!(supply.take_events().is_empty());
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn sink_takes_over_vconn() {
+    let supply = SimSupply::default();
+    let supply = &supply;
+    run(
+        Sink,
+        |pe| PolicyEngine::new(pe, SINK_CONFIG).with_vconn(supply),
+        |mut source| async move {
+            source.negotiate(&[PDO_5V_3A]).await;
+            source.send_control(ControlMessageType::VconnSwap).await;
+            source.expect_control(ControlMessageType::Accept).await;
+            source.expect_control(ControlMessageType::PsRdy).await;
+            assert_eq!(supply.take_events(), [SupplyEvent::VconnEnable]);
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn sink_without_vconn_rejects_swap() {
+    run_sink(|mut source| async move {
+        source.negotiate(&[PDO_5V_3A]).await;
+        source.send_control(ControlMessageType::VconnSwap).await;
+        source.expect_control(ControlMessageType::Reject).await;
+    })
+    .unwrap();
+}
+
+#[test]
+fn dfp_takes_over_vconn() {
+    let supply = SimSupply::default();
+    let supply = &supply;
+    run(
+        Sink,
+        |pe| PolicyEngine::new(pe, DfpSink(SINK_CONFIG)).with_vconn(supply),
+        |mut source| async move {
+            source.negotiate(&[PDO_5V_3A]).await;
+            source.expect_control(ControlMessageType::DrSwap).await;
+            source.send_control(ControlMessageType::Accept).await;
+            source.data_role = PortDataRole::UpstreamFacingPort;
+
+            source.expect_control(ControlMessageType::VconnSwap).await;
+            source.send_control(ControlMessageType::Accept).await;
+            source.expect_control(ControlMessageType::PsRdy).await;
+            assert_eq!(supply.take_events(), [SupplyEvent::VconnEnable]);
+        },
+    )
+    .unwrap();
+}
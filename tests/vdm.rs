@@ -0,0 +1,420 @@
+mod common;
+
+use std::cell::RefCell;
+
+use bilge::prelude::*;
+use common::loopback::SimCc;
+use common::partner::{Received, PDO_5V_3A};
+use common::supply::SimSupply;
+use common::{request_5v, run, run_sink, vsafe5v_source_config, Sink, Source, SINK_CONFIG};
+use embassy_futures::join::join;
+use embassy_time::Duration;
+use usb_pd::phy::{RpLevel, Sop};
+use usb_pd::policy_engine::PolicyEngine;
+use usb_pd::protocol::*;
+use usb_pd::vdm_policy::{
+    SvidModes, UnstructuredVdmHandler, VdmConfig, VdmError, VdmPolicy, VdmRequest, VdmRequests,
+};
+
+const DISPLAYPORT_SID: u16 = 0xFF01;
+
+/// ID Header VDO of a PDUSB peripheral with vendor ID 0x1234.
+const ID_HEADER: u32 = (0b010 << 27) | 0x1234;
+
+/// Vendor protocol echoing the VDOs of each message back with the vendor use
+/// bits incremented, records the received messages.
+struct EchoProtocol<'a>(&'a RefCell<Vec<Vec<u32>>>);
+
+impl UnstructuredVdmHandler for EchoProtocol<'_> {
+    fn handle(&mut self, header: UnstructuredVdmHeader, vdos: &[u32], reply: &mut [u32]) -> usize {
+        let mut message = vec![u32::from(header)];
+        message.extend_from_slice(vdos);
+        self.0.borrow_mut().push(message);
+        if vdos.is_empty() {
+            return 0;
+        }
+        let mut reply_header = header;
+        reply_header.set_vendor_use(u15::new(header.vendor_use().value() + 1));
+        reply[0] = reply_header.into();
+        reply[1..1 + vdos.len()].copy_from_slice(vdos);
+        1 + vdos.len()
+    }
+}
+
+/// Identity and modes of a DisplayPort sink device.
+const VDM_CONFIG: VdmConfig<'static> = VdmConfig {
+    identity: &[ID_HEADER, 0, 0x5678_0100],
+    svids: &[
+        SvidModes {
+            svid: DISPLAYPORT_SID,
+            modes: &[0x0000_0c05],
+        },
+        SvidModes {
+            svid: 0x1234,
+            modes: &[0x1, 0x2],
+        },
+        SvidModes {
+            svid: 0x5678,
+            modes: &[0x3],
+        },
+    ],
+    requests: None,
+};
+
+#[test]
+fn sink_answers_discover_identity() {
+    run(
+        Sink,
+        |pe| PolicyEngine::new(pe, SINK_CONFIG).with_vdm(VDM_CONFIG),
+        |mut source| async move {
+            source.negotiate(&[PDO_5V_3A]).await;
+            let (header, vdos) = source
+                .vdm_request(PD_SID, VdmCommand::DiscoverIdentity)
+                .await;
+            assert_eq!(header.command_type(), VdmCommandType::Ack);
+            assert_eq!(header.command(), VdmCommand::DiscoverIdentity);
+            assert_eq!(header.svid(), PD_SID);
+            assert_eq!(vdos, VDM_CONFIG.identity);
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn sink_answers_discover_svids() {
+    run(
+        Sink,
+        |pe| PolicyEngine::new(pe, SINK_CONFIG).with_vdm(VDM_CONFIG),
+        |mut source| async move {
+            source.negotiate(&[PDO_5V_3A]).await;
+            let (header, vdos) = source.vdm_request(PD_SID, VdmCommand::DiscoverSvids).await;
+            assert_eq!(header.command_type(), VdmCommandType::Ack);
+            // Two SVIDs per VDO, terminated by a zero SVID.
+            assert_eq!(vdos, [0xFF01_1234, 0x5678_0000]);
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn sink_answers_discover_modes() {
+    run(
+        Sink,
+        |pe| PolicyEngine::new(pe, SINK_CONFIG).with_vdm(VDM_CONFIG),
+        |mut source| async move {
+            source.negotiate(&[PDO_5V_3A]).await;
+            let (header, vdos) = source.vdm_request(0x1234, VdmCommand::DiscoverModes).await;
+            assert_eq!(header.command_type(), VdmCommandType::Ack);
+            assert_eq!(header.svid(), 0x1234);
+            assert_eq!(vdos, [0x1, 0x2]);
+
+            let (header, vdos) = source.vdm_request(0xABCD, VdmCommand::DiscoverModes).await;
+            assert_eq!(header.command_type(), VdmCommandType::Nak);
+            assert!(vdos.is_empty());
+        },
+    )
+    .unwrap();
+}
+
+/// VDM policy filling the whole buffer and claiming to have written more.
+struct OvercountingPolicy;
+
+impl VdmPolicy for OvercountingPolicy {
+    fn discover_identity(&self, vdos: &mut [u32]) -> usize {
+        vdos.fill(ID_HEADER);
+        vdos.len() + 4
+    }
+
+    fn discover_svids(&self, svids: &mut [u16]) -> usize {
+        svids.fill(0x1234);
+        svids.len() + 4
+    }
+
+    fn discover_modes(&self, _svid: u16, modes: &mut [u32]) -> usize {
+        modes.fill(0x1);
+        modes.len() + 4
+    }
+}
+
+#[test]
+fn discovery_responses_are_limited_to_six_vdos() {
+    run(
+        Sink,
+        |pe| PolicyEngine::new(pe, SINK_CONFIG).with_vdm(OvercountingPolicy),
+        |mut source| async move {
+            source.negotiate(&[PDO_5V_3A]).await;
+            for (svid, command) in [
+                (PD_SID, VdmCommand::DiscoverIdentity),
+                (PD_SID, VdmCommand::DiscoverSvids),
+                (0x1234, VdmCommand::DiscoverModes),
+            ] {
+                let (header, vdos) = source.vdm_request(svid, command).await;
+                assert_eq!(header.command_type(), VdmCommandType::Ack);
+                assert_eq!(vdos.len(), 6);
+            }
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn unsupported_command_is_nakked() {
+    run(
+        Sink,
+        |pe| PolicyEngine::new(pe, SINK_CONFIG).with_vdm(VDM_CONFIG),
+        |mut source| async move {
+            source.negotiate(&[PDO_5V_3A]).await;
+            let (header, _) = source
+                .vdm_request(DISPLAYPORT_SID, VdmCommand::EnterMode)
+                .await;
+            assert_eq!(header.command_type(), VdmCommandType::Nak);
+            assert_eq!(header.command(), VdmCommand::EnterMode);
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn sink_without_vdm_policy_naks_discovery() {
+    run_sink(|mut source| async move {
+        source.negotiate(&[PDO_5V_3A]).await;
+        let (header, vdos) = source
+            .vdm_request(PD_SID, VdmCommand::DiscoverIdentity)
+            .await;
+        assert_eq!(header.command_type(), VdmCommandType::Nak);
+        assert!(vdos.is_empty());
+    })
+    .unwrap();
+}
+
+#[test]
+fn attention_is_not_answered() {
+    run(
+        Sink,
+        |pe| PolicyEngine::new(pe, SINK_CONFIG).with_vdm(VDM_CONFIG),
+        |mut source| async move {
+            source.negotiate(&[PDO_5V_3A]).await;
+            let header = StructuredVdmHeader::request(
+                DISPLAYPORT_SID,
+                VdmCommand::Attention,
+                source.revision,
+            );
+            source
+                .send_data(DataMessageType::VendorDefined, &[header.into()])
+                .await;
+            source.expect_nothing(Duration::from_millis(50)).await;
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn unstructured_vdm_is_not_supported() {
+    run_sink(|mut source| async move {
+        source.revision = SpecificationRevision::Revision3_0;
+        source.negotiate(&[PDO_5V_3A]).await;
+        source
+            .send_data(DataMessageType::VendorDefined, &[0x1234_0000])
+            .await;
+        source
+            .expect_control(ControlMessageType::NotSupported)
+            .await;
+    })
+    .unwrap();
+}
+
+#[test]
+fn unstructured_vdm_is_passed_to_vendor_handler() {
+    let received = RefCell::new(Vec::new());
+    let received = &received;
+    run(
+        Sink,
+        |pe| {
+            PolicyEngine::new(pe, SINK_CONFIG).with_unstructured_vdm(0x1234, EchoProtocol(received))
+        },
+        |mut source| async move {
+            source.revision = SpecificationRevision::Revision3_0;
+            source.negotiate(&[PDO_5V_3A]).await;
+            source
+                .send_data(DataMessageType::VendorDefined, &[0x1234_0001, 0xAA, 0xBB])
+                .await;
+            let reply = source.expect_data(DataMessageType::VendorDefined).await;
+            assert_eq!(reply, [0x1234_0002, 0xAA, 0xBB]);
+            assert_eq!(*received.borrow(), [vec![0x1234_0001, 0xAA, 0xBB]]);
+
+            // The handler may also stay silent.
+            source
+                .send_data(DataMessageType::VendorDefined, &[0x1234_0005])
+                .await;
+            source.expect_nothing(Duration::from_millis(50)).await;
+            assert_eq!(received.borrow().len(), 2);
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn unstructured_vdm_of_other_vendor_is_not_supported() {
+    let received = RefCell::new(Vec::new());
+    let received = &received;
+    run(
+        Sink,
+        |pe| {
+            PolicyEngine::new(pe, SINK_CONFIG).with_unstructured_vdm(0x1234, EchoProtocol(received))
+        },
+        |mut source| async move {
+            source.revision = SpecificationRevision::Revision3_0;
+            source.negotiate(&[PDO_5V_3A]).await;
+            source
+                .send_data(DataMessageType::VendorDefined, &[0x5678_0001, 0xAA])
+                .await;
+            source
+                .expect_control(ControlMessageType::NotSupported)
+                .await;
+            assert!(received.borrow().is_empty());
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn sink_sends_requested_vdm() {
+    let requests = VdmRequests::new();
+    let requests = &requests;
+    run(
+        Sink,
+        |pe| {
+            PolicyEngine::new(pe, SINK_CONFIG).with_vdm(VdmConfig {
+                requests: Some(requests),
+                ..VDM_CONFIG
+            })
+        },
+        |mut source| async move {
+            source.negotiate(&[PDO_5V_3A]).await;
+            let header =
+                StructuredVdmHeader::request(PD_SID, VdmCommand::DiscoverIdentity, source.revision);
+            let request = VdmRequest::new(Sop::Sop, header, &[]);
+
+            let (response, ()) = join(requests.request(request), async {
+                let (header, vdos) = source.expect_vdm().await;
+                assert_eq!(header.command(), VdmCommand::DiscoverIdentity);
+                assert!(vdos.is_empty());
+                source
+                    .send_vdm_response(header, VdmCommandType::Ack, &[ID_HEADER, 0, 0x1])
+                    .await;
+            })
+            .await;
+            let response = response.unwrap();
+            assert_eq!(response.header.command_type(), VdmCommandType::Ack);
+            assert_eq!(response.vdos(), [ID_HEADER, 0, 0x1]);
+
+            for (command_type, error) in [
+                (VdmCommandType::Nak, VdmError::Nak),
+                (VdmCommandType::Busy, VdmError::Busy),
+            ] {
+                let (response, ()) = join(requests.request(request), async {
+                    let (header, _) = source.expect_vdm().await;
+                    source.send_vdm_response(header, command_type, &[]).await;
+                })
+                .await;
+                assert_eq!(response, Err(error));
+            }
+
+            let (response, ()) = join(requests.request(request), async {
+                source.expect_vdm().await;
+            })
+            .await;
+            assert_eq!(response, Err(VdmError::Timeout));
+
+            // The port stays in its contract.
+            source.send_control(ControlMessageType::GetSinkCap).await;
+            source.expect_data(DataMessageType::SinkCapabilities).await;
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn pending_vdm_request_fails_on_soft_reset() {
+    let requests = VdmRequests::new();
+    let requests = &requests;
+    let cc = SimCc::new(RpLevel::Current3_0A);
+    let cc = &cc;
+    run(
+        Sink,
+        |pe| {
+            PolicyEngine::new(pe.with_cc_phy(cc), SINK_CONFIG).with_vdm(VdmConfig {
+                requests: Some(requests),
+                ..VDM_CONFIG
+            })
+        },
+        |mut source| async move {
+            source.revision = SpecificationRevision::Revision3_0;
+            source.negotiate(&[PDO_5V_3A]).await;
+            let header =
+                StructuredVdmHeader::request(PD_SID, VdmCommand::DiscoverIdentity, source.revision);
+            let request = VdmRequest::new(Sop::Sop, header, &[]);
+
+            // The request waits for SinkTxOk when the port is reset.
+            cc.set(RpLevel::Current1_5A);
+            let (response, ()) = join(requests.request(request), async {
+                source.expect_nothing(Duration::from_millis(50)).await;
+                source.send_control(ControlMessageType::SoftReset).await;
+                source.expect_control(ControlMessageType::Accept).await;
+            })
+            .await;
+            assert_eq!(response, Err(VdmError::Timeout));
+
+            // Later requests are still sent.
+            cc.set(RpLevel::Current3_0A);
+            source.negotiate(&[PDO_5V_3A]).await;
+            let (response, ()) = join(requests.request(request), async {
+                let (header, _) = source.expect_vdm().await;
+                source
+                    .send_vdm_response(header, VdmCommandType::Nak, &[])
+                    .await;
+            })
+            .await;
+            assert_eq!(response, Err(VdmError::Nak));
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn source_sends_requested_vdm() {
+    let requests = VdmRequests::new();
+    let requests = &requests;
+    let supply = SimSupply::default();
+    run(
+        Source(&supply),
+        |pe| {
+            PolicyEngine::new(pe, vsafe5v_source_config()).with_vdm(VdmConfig {
+                requests: Some(requests),
+                ..VDM_CONFIG
+            })
+        },
+        |mut sink| async move {
+            let header =
+                StructuredVdmHeader::request(0x1234, VdmCommand::DiscoverModes, sink.revision);
+            let request = VdmRequest::new(Sop::Sop, header, &[]);
+            let (response, ()) = join(requests.request(request), async {
+                // The request waits for the explicit contract.
+                sink.expect_data(DataMessageType::SourceCapabilites).await;
+                assert_eq!(
+                    sink.request(request_5v()).await,
+                    Received::Control(ControlMessageType::Accept)
+                );
+                sink.expect_control(ControlMessageType::PsRdy).await;
+
+                let (header, _) = sink.expect_vdm().await;
+                assert_eq!(header.svid(), 0x1234);
+                sink.send_vdm_response(header, VdmCommandType::Ack, &[0x5])
+                    .await;
+            })
+            .await;
+            assert_eq!(response.unwrap().vdos(), [0x5]);
+        },
+    )
+    .unwrap();
+}